@@ -0,0 +1,5 @@
+#!/bin/sh
+# Answers with two conformers moving atom 0 along x.
+cat > /dev/null
+echo '[{"atoms":{"0":{"element":6,"position":[1.0,0.0,0.0]}},"bonds":{},"groups":[]},'
+echo '{"atoms":{"0":{"element":6,"position":[2.0,0.0,0.0]}},"bonds":{},"groups":[]}]'
@@ -0,0 +1,5 @@
+#!/bin/sh
+# Exits with the status given as first argument, after writing the second one as raw bytes.
+cat > /dev/null
+printf "$2"
+exit "$1"
@@ -0,0 +1,4 @@
+#!/bin/sh
+# Answers with a molecule whose only group is named after the working directory.
+cat > /dev/null
+printf '{"atoms":{},"bonds":{},"groups":[[0,"%s"]]}' "$(pwd)"
@@ -0,0 +1,5 @@
+#!/bin/sh
+# Never stops writing to stdout. Writes its pid to LME_PID_FILE when set.
+cat > /dev/null
+if [ -n "$LME_PID_FILE" ]; then echo $$ > "$LME_PID_FILE"; fi
+yes
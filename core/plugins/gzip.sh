@@ -0,0 +1,4 @@
+#!/bin/sh
+# Answers with a single carbon atom, gzipped.
+cat > /dev/null
+printf '{"atoms":{"0":{"element":6,"position":[0,0,0]}},"bonds":{},"groups":[]}' | gzip -c
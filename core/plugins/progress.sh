@@ -0,0 +1,7 @@
+#!/bin/sh
+# Reports progress frames before answering with a molecule frame.
+cat > /dev/null
+echo '{"progress":0.25}'
+echo '{"progress":0.5}'
+echo '{"progress":1.0}'
+echo '{"molecule":{"atoms":{},"bonds":{},"groups":[[0,"done"]]}}'
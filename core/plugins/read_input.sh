@@ -0,0 +1,3 @@
+#!/bin/sh
+# Answers with the molecule read from the file given as first argument.
+cat "$1"
@@ -1,23 +1,47 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::Arc,
+};
 
-use entity::{Layer, Molecule, Stack};
+use entity::{Layer, Molecule, PluginOptions, Stack, UnitCell};
 use error::LMECoreError;
-use n_to_n::NtoN;
+use n_to_n::{Grouped, NtoN};
+use pair::Pair;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub mod periodic;
+pub mod schema;
+pub mod select;
+pub mod smiles;
+pub mod symmetry;
+pub mod xyz;
 
 pub mod error {
     use serde::Serialize;
 
-    #[derive(Serialize)]
+    #[derive(Debug, Serialize)]
     pub enum LMECoreError {
         // IdMapUniqueError,
-        // NoSuchAtom,
-        // NoSuchId,
-        // RootLayerError,
+        NoSuchAtom,
+        NoSuchId,
+        RootLayerError,
         // NotFillLayer,
         PluginLayerError(isize, String),
+        PluginNotFound(String),
+        AtomSlotOccupied(usize),
+        InvalidTransform(String),
+        InvalidXyz(usize, String),
+        StackConflict,
+        InvalidSelection(String),
+        NoUnitCell,
+        InvalidRequest(String),
+        InvalidAtom(String),
         NoSuchStack,
+        /// The edit would leave a stack with more atoms than the workspace limit.
+        TooManyAtoms(usize),
         // WorkspaceNameConflict,
         // WorkspaceNotFound,
     }
@@ -25,24 +49,34 @@ pub mod error {
 
 pub mod entity {
     use std::{
-        collections::{HashMap, HashSet},
-        io::Write,
-        path::PathBuf,
-        process::{Command, Stdio},
-        sync::Arc,
+        collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
+        f64::consts::PI,
+        fs,
+        hash::{Hash, Hasher},
+        io::{BufRead, BufReader, Read, Write},
+        mem,
+        ops::Range,
+        path::{Component, PathBuf},
+        process::{self, Child, Command, Stdio},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Condvar, Mutex, PoisonError,
+        },
+        thread,
     };
 
+    use flate2::read::GzDecoder;
     use lazy_static::lazy_static;
     use n_to_n::NtoN;
-    use nalgebra::{Point3, Transform3};
+    use nalgebra::{Matrix3, Point3, Rotation3, RowVector4, Transform3, Unit, Vector3};
     use pair::Pair;
     use rayon::iter::{
-        IndexedParallelIterator, IntoParallelIterator, ParallelBridge, ParallelIterator,
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
     };
-    use serde::{Deserialize, Serialize};
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
     use std::env;
 
-    use crate::error::LMECoreError;
+    use crate::{error::LMECoreError, periodic};
 
     fn get_plugin_directory() -> PathBuf {
         let env_var = env::var("LME_PLUGIN_DIRECTORY");
@@ -57,15 +91,118 @@ pub mod entity {
 
     lazy_static! {
         static ref PLUGIN_DIRECTORY: PathBuf = get_plugin_directory();
+        static ref PLUGIN_PERMITS: Permits =
+            Permits::new(thread::available_parallelism().map_or(1, |count| count.get()));
+    }
+
+    /// Counting semaphore bounding the number of running plugin processes, so reading many stacks
+    /// at once waits for permits instead of spawning a process per stack.
+    pub(crate) struct Permits {
+        limit: AtomicUsize,
+        running: Mutex<usize>,
+        freed: Condvar,
+    }
+
+    pub(crate) struct Permit<'a>(&'a Permits);
+
+    impl Permits {
+        pub(crate) fn new(limit: usize) -> Self {
+            Self {
+                limit: AtomicUsize::new(limit.max(1)),
+                running: Mutex::new(0),
+                freed: Condvar::new(),
+            }
+        }
+
+        pub(crate) fn acquire(&self) -> Permit<'_> {
+            let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+            while *running >= self.limit.load(Ordering::Relaxed) {
+                running = self
+                    .freed
+                    .wait(running)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            *running += 1;
+            Permit(self)
+        }
+    }
+
+    impl Drop for Permit<'_> {
+        fn drop(&mut self) {
+            *self
+                .0
+                .running
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) -= 1;
+            self.0.freed.notify_all();
+        }
+    }
+
+    /// Sets how many plugin processes may run at once, defaults to the number of CPUs.
+    pub fn set_plugin_concurrency(limit: usize) {
+        PLUGIN_PERMITS.limit.store(limit.max(1), Ordering::Relaxed);
+        PLUGIN_PERMITS.freed.notify_all();
     }
 
+    pub const PLUGIN_OUTPUT_LIMIT: usize = 256 * 1024 * 1024;
+
+    /// Most atoms `Molecule::repeat` builds, whatever the workspace limit.
+    pub const MAX_REPEAT_ATOMS: usize = 1 << 20;
+
+    /// Deserialized atoms go through `Atom::try_new`, so no invalid atom comes in from clients,
+    /// plugins or exports.
     #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+    #[serde(try_from = "AtomData")]
     pub struct Atom {
         element: usize,
         position: Point3<f64>,
     }
 
+    #[derive(Deserialize)]
+    struct AtomData {
+        element: usize,
+        position: Point3<f64>,
+    }
+
+    impl TryFrom<AtomData> for Atom {
+        type Error = String;
+
+        fn try_from(AtomData { element, position }: AtomData) -> Result<Self, Self::Error> {
+            Atom::check(element, &position)?;
+            Ok(Self::new(element, position))
+        }
+    }
+
     impl Atom {
+        /// Unchecked constructor, see `try_new` for input that isn't known to be valid.
+        pub fn new(element: usize, position: Point3<f64>) -> Self {
+            Self { element, position }
+        }
+
+        /// Requires an element between 1 and 118 and finite coordinates.
+        pub fn try_new(element: usize, position: Point3<f64>) -> Result<Self, LMECoreError> {
+            Self::check(element, &position).map_err(LMECoreError::InvalidAtom)?;
+            Ok(Self::new(element, position))
+        }
+
+        fn check(element: usize, position: &Point3<f64>) -> Result<(), String> {
+            if periodic::symbol(element).is_none() {
+                return Err(format!("No element with atomic number {element}"));
+            }
+            if !position.iter().all(|x| x.is_finite()) {
+                return Err(format!("Non-finite position {position}"));
+            }
+            Ok(())
+        }
+
+        pub fn element(&self) -> usize {
+            self.element
+        }
+
+        pub fn position(&self) -> &Point3<f64> {
+            &self.position
+        }
+
         pub fn set_element(self, element: usize) -> Self {
             Self { element, ..self }
         }
@@ -79,403 +216,4999 @@ pub mod entity {
         }
     }
 
-    #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
-    pub struct Molecule {
-        atoms: HashMap<usize, Option<Atom>>,
-        bonds: HashMap<Pair<usize>, f64>,
-        groups: NtoN<usize, String>,
+    /// Kind of a bond. Plain numbers, the format used before bond kinds existed, still
+    /// deserialize: integral orders 1 to 3 map to the matching kind, anything else to `Custom`.
+    #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+    #[serde(from = "BondValue")]
+    pub enum BondKind {
+        Single,
+        Double,
+        Triple,
+        Aromatic,
+        Dative,
+        Custom(f64),
     }
 
-    impl Molecule {
-        pub fn merge(mut low: Self, high: Self) -> Self {
-            low.atoms.extend(high.atoms);
-            low.bonds.extend(high.bonds);
-            low.groups.extend(high.groups);
-            low
+    impl BondKind {
+        pub fn order(&self) -> f64 {
+            match self {
+                Self::Single | Self::Dative => 1.,
+                Self::Double => 2.,
+                Self::Triple => 3.,
+                Self::Aromatic => 1.5,
+                Self::Custom(order) => *order,
+            }
         }
     }
 
-    pub struct CompactedMolecule {
-        atoms: Vec<Atom>,
-        bonds: HashMap<Pair<usize>, f64>,
-        groups: NtoN<usize, String>,
-    }
-
-    impl CompactedMolecule {
-        pub fn unzip(self, offset: usize) -> Molecule {
-            let atoms = self
-                .atoms
-                .into_par_iter()
-                .enumerate()
-                .map(|(idx, atom)| (idx + offset, Some(atom)))
-                .collect::<HashMap<_, _>>();
-            let bonds = self
-                .bonds
-                .into_par_iter()
-                .map(|(pair, bond_order)| (pair.offset(offset), bond_order))
-                .collect::<HashMap<_, _>>();
-            let groups = self
-                .groups
-                .into_iter()
-                .par_bridge()
-                .map(|(idx, group_name)| (idx + offset, group_name))
-                .collect::<HashSet<_>>();
-            Molecule {
-                atoms,
-                bonds,
-                groups: NtoN::from(groups),
+    impl From<f64> for BondKind {
+        fn from(value: f64) -> Self {
+            match value {
+                _ if value == 1. => Self::Single,
+                _ if value == 2. => Self::Double,
+                _ if value == 3. => Self::Triple,
+                _ => Self::Custom(value),
             }
         }
     }
 
-    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
-    pub enum Layer {
-        Fill(Molecule),
-        Transform(Transform3<f64>),
-        IgnoreBonds,
-        ReplaceElement(usize, usize),
-        RemoveElement(usize),
-        PluginFilter(String, Vec<String>),
+    #[derive(Deserialize)]
+    enum TaggedBondKind {
+        Single,
+        Double,
+        Triple,
+        Aromatic,
+        Dative,
+        Custom(f64),
     }
 
-    impl Layer {
-        pub fn filter(&self, mut low: Molecule) -> Result<Molecule, LMECoreError> {
-            match self {
-                Self::Fill(high) => Ok(Molecule::merge(low, high.clone())),
-                Self::Transform(transform) => {
-                    low.atoms.iter_mut().for_each(|(_, atom)| {
-                        *atom = atom.map(|atom| atom.transform_position(transform))
-                    });
-                    Ok(low)
-                }
-                Self::IgnoreBonds => {
-                    low.bonds = HashMap::new();
-                    Ok(low)
-                }
-                Self::ReplaceElement(origin, target) => {
-                    low.atoms.iter_mut().for_each(|(_, atom)| {
-                        *atom = atom.map(|atom| {
-                            if &atom.element == origin {
-                                atom.set_element(*target)
-                            } else {
-                                atom
-                            }
-                        })
-                    });
-                    Ok(low)
-                }
-                Self::RemoveElement(element) => {
-                    low.atoms.iter_mut().for_each(|(_, atom)| {
-                        *atom = atom.and_then(|atom| {
-                            if &atom.element == element {
-                                None
-                            } else {
-                                Some(atom)
-                            }
-                        })
-                    });
-                    Ok(low)
-                }
-                Self::PluginFilter(plugin, args) => {
-                    let mut command = PLUGIN_DIRECTORY.clone();
-                    command.push(plugin);
-                    let mut child = Command::new(command)
-                        .args(args)
-                        .stdin(Stdio::piped())
-                        .spawn()
-                        .map_err(|err| LMECoreError::PluginLayerError(-1, err.to_string()))?;
-                    let data_to_send = serde_json::to_string(&low)
-                        .map_err(|err| LMECoreError::PluginLayerError(-2, err.to_string()))?;
-                    if let Some(ref mut stdin) = child.stdin {
-                        stdin
-                            .write_all(&data_to_send.as_bytes())
-                            .map_err(|err| LMECoreError::PluginLayerError(-3, err.to_string()))?;
-                        let output = child
-                            .wait_with_output()
-                            .map_err(|err| LMECoreError::PluginLayerError(-4, err.to_string()))?;
-                        let data = String::from_utf8_lossy(&output.stdout);
-                        let high: Molecule = serde_json::from_str(&data)
-                            .map_err(|err| LMECoreError::PluginLayerError(-5, err.to_string()))?;
-                        Ok(Molecule::merge(low, high))
-                    } else {
-                        Err(LMECoreError::PluginLayerError(
-                            -6,
-                            "Unable to get stdin of child process".to_string(),
-                        ))
-                    }
-                }
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BondValue {
+        Order(f64),
+        Kind(TaggedBondKind),
+    }
+
+    impl From<BondValue> for BondKind {
+        fn from(value: BondValue) -> Self {
+            match value {
+                BondValue::Order(order) => Self::from(order),
+                BondValue::Kind(TaggedBondKind::Single) => Self::Single,
+                BondValue::Kind(TaggedBondKind::Double) => Self::Double,
+                BondValue::Kind(TaggedBondKind::Triple) => Self::Triple,
+                BondValue::Kind(TaggedBondKind::Aromatic) => Self::Aromatic,
+                BondValue::Kind(TaggedBondKind::Dative) => Self::Dative,
+                BondValue::Kind(TaggedBondKind::Custom(order)) => Self::Custom(order),
             }
         }
     }
 
-    #[derive(Debug, Default, Clone, PartialEq)]
-    pub struct Stack(Vec<Arc<Layer>>);
+    /// Serializes bonds as a map keyed by `"a-b"` strings, JSON objects only allowing string
+    /// keys. Keys are read back in either order.
+    pub mod bond_map {
+        use std::collections::HashMap;
 
-    impl Stack {
-        pub fn new(layer: Vec<Arc<Layer>>) -> Self {
-            Self(layer)
+        use pair::Pair;
+        use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+        use super::BondKind;
+
+        pub fn serialize<S: Serializer>(
+            bonds: &HashMap<Pair<usize>, BondKind>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bonds
+                .iter()
+                .map(|(pair, kind)| {
+                    let (a, b) = (*pair).into();
+                    (format!("{a}-{b}"), kind)
+                })
+                .collect::<HashMap<_, _>>()
+                .serialize(serializer)
         }
 
-        pub fn get_layers(&self) -> &Vec<Arc<Layer>> {
-            &self.0
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<HashMap<Pair<usize>, BondKind>, D::Error> {
+            HashMap::<String, BondKind>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(key, kind)| {
+                    let (a, b) = key
+                        .split_once('-')
+                        .and_then(|(a, b)| Some((a.trim().parse().ok()?, b.trim().parse().ok()?)))
+                        .ok_or_else(|| D::Error::custom(format!("Invalid bond key {key:?}")))?;
+                    Ok((Pair::new_ordered(a, b), kind))
+                })
+                .collect()
         }
+    }
 
-        pub fn get_base(&self) -> Self {
-            if let Some((_, layers)) = self.0.split_last() {
-                Self(layers.to_vec())
-            } else {
-                Self(vec![])
+    #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+    pub struct Molecule {
+        atoms: HashMap<usize, Option<Atom>>,
+        #[serde(with = "bond_map")]
+        bonds: HashMap<Pair<usize>, BondKind>,
+        groups: NtoN<usize, String>,
+    }
+
+    impl Molecule {
+        pub fn new(
+            atoms: HashMap<usize, Option<Atom>>,
+            bonds: HashMap<Pair<usize>, BondKind>,
+            groups: NtoN<usize, String>,
+        ) -> Self {
+            Self {
+                atoms,
+                bonds,
+                groups,
             }
         }
 
-        pub fn add_layer(&mut self, layer: Arc<Layer>) {
-            self.0.push(layer)
+        pub fn atoms(&self) -> &HashMap<usize, Option<Atom>> {
+            &self.atoms
         }
 
-        pub fn write(&mut self, w: Molecule) {
-            let top = self.0.last().map(|top| top.as_ref());
-            if let Some(Layer::Fill(current)) = top {
-                let updated = Molecule::merge(current.clone(), w);
-                *self.0.last_mut().expect("Should never hint this condition") =
-                    Arc::new(Layer::Fill(updated))
-            } else {
-                self.add_layer(Arc::new(Layer::Fill(w)))
-            }
+        pub fn bonds(&self) -> &HashMap<Pair<usize>, BondKind> {
+            &self.bonds
         }
 
-        pub fn read(&self, mut container: Molecule) -> Result<Molecule, LMECoreError> {
-            for layer in &self.0 {
-                container = layer.filter(container)?
-            }
-            Ok(container)
+        pub fn groups(&self) -> &NtoN<usize, String> {
+            &self.groups
         }
-    }
-}
 
-#[derive(Debug, Default, PartialEq, Clone)]
-pub struct Workspace {
-    base: Molecule,
-    stacks: Vec<Arc<Stack>>,
-    pub atom_names: HashMap<String, usize>,
-    pub groups: NtoN<String, usize>,
-}
+        pub fn get_atom(&self, idx: usize) -> Option<&Atom> {
+            self.atoms.get(&idx).and_then(|atom| atom.as_ref())
+        }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
-pub struct WorkspaceExport {
-    base: Molecule,
-    stacks: Vec<StackTree>,
-    atom_names: HashMap<String, usize>,
-    groups: NtoN<String, usize>,
-}
+        pub fn get_bond(&self, a: usize, b: usize) -> Option<&BondKind> {
+            self.bonds.get(&Pair::new_ordered(a, b))
+        }
 
-impl Workspace {
-    pub fn new(base: Molecule) -> Self {
-        Self {
-            base,
-            stacks: vec![],
-            atom_names: HashMap::new(),
-            groups: NtoN::new(),
+        /// Bonds of this molecule whose pair is not bonded in `other`, whatever the bond kinds.
+        pub fn bond_difference(&self, other: &Molecule) -> HashMap<Pair<usize>, BondKind> {
+            self.bonds
+                .iter()
+                .filter(|(pair, _)| !other.bonds.contains_key(pair))
+                .map(|(pair, kind)| (*pair, *kind))
+                .collect()
         }
-    }
 
-    pub fn read(&self, index: usize) -> Result<Molecule, LMECoreError> {
-        self.stacks
-            .get(index)
-            .map_or(Err(LMECoreError::NoSuchStack), |stack| {
-                stack.read(self.base.clone())
-            })
-    }
+        /// Bonds of this molecule whose pair is also bonded in `other`, with the kinds of `self`.
+        pub fn bond_intersection(&self, other: &Molecule) -> HashMap<Pair<usize>, BondKind> {
+            self.bonds
+                .iter()
+                .filter(|(pair, _)| other.bonds.contains_key(pair))
+                .map(|(pair, kind)| (*pair, *kind))
+                .collect()
+        }
 
-    pub fn stacks(&self) -> usize {
-        self.stacks.len()
-    }
+        pub fn add_to_group(&mut self, idx: usize, group: &str) -> bool {
+            self.groups.insert(idx, group.to_string())
+        }
 
-    pub fn create_stack(&mut self, stack: Arc<Stack>, copies: usize) -> usize {
-        let index = self.stacks.len();
-        for _ in 0..=copies {
-            self.stacks.push(stack.clone());
+        pub fn remove_from_group(&mut self, idx: usize, group: &str) -> bool {
+            self.groups.remove(&idx, &group.to_string())
         }
-        index
-    }
 
-    pub fn create_stack_from_layer(&mut self, layer: Arc<Layer>, copies: usize) -> usize {
-        let stack = Stack::new(vec![layer]);
-        self.create_stack(Arc::new(stack), copies)
-    }
+        /// Marks the atom as removed and drops the bonds and group memberships referring to it.
+        pub fn remove_atom(&mut self, idx: usize) {
+            self.atoms.insert(idx, None);
+            self.bonds.retain(|pair, _| !pair.contains(&idx));
+            self.groups.remove_left(&idx);
+        }
 
-    pub fn clone_stack(&mut self, stack_idx: usize, copies: usize) -> Option<usize> {
-        let stack = self.stacks.get(stack_idx).cloned()?;
+        pub fn group_members(&self, group: &str) -> HashSet<usize> {
+            self.groups.get_right(&group.to_string())
+        }
 
-        Some(self.create_stack(stack, copies))
-    }
+        /// Yields the present atoms bonded to the present atom `idx` with the bond kind. Bonds are
+        /// keyed by pair, so this scans all of them; build an adjacency map instead when visiting
+        /// every atom.
+        pub fn neighbors(&self, idx: usize) -> impl Iterator<Item = (usize, &BondKind)> + '_ {
+            let present = self.get_atom(idx).is_some();
+            self.bonds
+                .iter()
+                .filter(move |_| present)
+                .filter_map(move |(pair, kind)| {
+                    let other = *pair.another(&idx)?;
+                    (other != idx && self.get_atom(other).is_some()).then_some((other, kind))
+                })
+        }
 
-    pub fn clone_base(&mut self, stack_idx: usize, copies: usize) -> Option<usize> {
-        let stack = self.stacks.get(stack_idx)?;
-        let base = stack.get_base();
-        Some(self.create_stack(Arc::new(base), copies))
-    }
+        pub fn degree(&self, idx: usize) -> usize {
+            self.neighbors(idx).count()
+        }
 
-    pub fn write_to_stack(&mut self, start_idx: usize, range: usize, data: Molecule) -> bool {
-        let max_idx = start_idx + range - 1;
-        if max_idx >= self.stacks.len() {
-            false
-        } else {
-            let stacks = (start_idx..start_idx + range)
-                .par_bridge()
-                .map(|i| {
-                    let mut stack = self.stacks[i].as_ref().clone();
-                    stack.write(data.clone());
-                    stack
+        /// Builds a patch rotating `atoms` by `angle` degrees around the axis from atom `a` to
+        /// atom `b`. Overlapping axis atoms leave the positions unchanged.
+        pub fn rotate_about_bond(
+            &self,
+            (a, b): (usize, usize),
+            atoms: &[usize],
+            angle: f64,
+        ) -> Result<Self, LMECoreError> {
+            let origin = *self.get_atom(a).ok_or(LMECoreError::NoSuchAtom)?.position();
+            let target = *self.get_atom(b).ok_or(LMECoreError::NoSuchAtom)?.position();
+            let rotation = Unit::try_new(target - origin, f64::EPSILON)
+                .map(|axis| Rotation3::from_axis_angle(&axis, angle.to_radians()))
+                .unwrap_or_else(Rotation3::identity);
+            let atoms = atoms
+                .iter()
+                .map(|idx| {
+                    let atom = self.get_atom(*idx).ok_or(LMECoreError::NoSuchAtom)?;
+                    let position = origin + rotation * (atom.position - origin);
+                    Ok((*idx, Some(atom.set_position(position))))
                 })
-                .collect::<Vec<_>>();
-            for (i, stack) in stacks.into_iter().enumerate() {
-                self.stacks[i + start_idx] = Arc::new(stack)
-            }
-            true
+                .collect::<Result<HashMap<_, _>, _>>()?;
+            Ok(Self {
+                atoms,
+                ..Default::default()
+            })
         }
-    }
 
-    pub fn add_layer_to_stack(
-        &mut self,
-        start_idx: usize,
-        range: usize,
-        layer: Arc<Layer>,
-    ) -> bool {
-        let max_idx = start_idx + range - 1;
-        if max_idx >= self.stacks.len() {
-            false
-        } else {
-            let stacks = (start_idx..start_idx + range)
-                .par_bridge()
-                .map(|i| {
-                    let mut stack = self.stacks[i].as_ref().clone();
-                    stack.add_layer(layer.clone());
-                    stack
+        /// Offset bringing atom `anchor` to `target`.
+        pub fn offset_to(
+            &self,
+            anchor: usize,
+            target: &Point3<f64>,
+        ) -> Result<Vector3<f64>, LMECoreError> {
+            let atom = self.get_atom(anchor).ok_or(LMECoreError::NoSuchAtom)?;
+            Ok(target - atom.position)
+        }
+
+        /// Builds a patch moving `atoms` by `offset`.
+        pub fn translate_atoms(
+            &self,
+            atoms: &[usize],
+            offset: &Vector3<f64>,
+        ) -> Result<Self, LMECoreError> {
+            let atoms = atoms
+                .iter()
+                .map(|idx| {
+                    let atom = self.get_atom(*idx).ok_or(LMECoreError::NoSuchAtom)?;
+                    Ok((*idx, Some(atom.set_position(atom.position + offset))))
                 })
+                .collect::<Result<HashMap<_, _>, _>>()?;
+            Ok(Self {
+                atoms,
+                ..Default::default()
+            })
+        }
+
+        /// Present atoms with their indexes, in no particular order.
+        pub fn present_atoms(&self) -> impl ParallelIterator<Item = (usize, &Atom)> {
+            self.atoms
+                .par_iter()
+                .filter_map(|(idx, atom)| atom.as_ref().map(|atom| (*idx, atom)))
+        }
+
+        /// Returns up to `k` present atoms closest to `point` as `(index, distance)`, nearest first.
+        pub fn nearest_atoms(&self, point: &Point3<f64>, k: usize) -> Vec<(usize, f64)> {
+            let mut distances = self
+                .present_atoms()
+                .map(|(idx, atom)| (idx, (atom.position - point).norm()))
                 .collect::<Vec<_>>();
-            for (i, stack) in stacks.into_iter().enumerate() {
-                self.stacks[i + start_idx] = Arc::new(stack);
+            let by_distance = |a: &(usize, f64), b: &(usize, f64)| a.1.total_cmp(&b.1);
+            if k < distances.len() {
+                distances.select_nth_unstable_by(k, by_distance);
+                distances.truncate(k);
             }
-            true
+            distances.sort_unstable_by(by_distance);
+            distances
         }
-    }
-}
 
-impl From<&Workspace> for WorkspaceExport {
-    fn from(value: &Workspace) -> Self {
-        Self {
-            base: value.base.clone(),
-            stacks: StackTree::dehydration(&value.stacks),
-            atom_names: value.atom_names.clone(),
-            groups: value.groups.clone(),
+        /// Measures the distance between 2 atoms, the angle at the middle one of 3 atoms, or the
+        /// dihedral angle of 4 atoms, angles being in degrees and dihedrals signed in (-180, 180].
+        pub fn measure(&self, atoms: &[usize]) -> Result<f64, LMECoreError> {
+            self.measure_with(atoms, |from, to| Ok(to - from))
         }
-    }
-}
 
-impl Into<Workspace> for &WorkspaceExport {
-    fn into(self) -> Workspace {
-        let stacks = StackTree::hydration(&self.stacks);
-        Workspace {
-            base: self.base.clone(),
-            stacks,
-            atom_names: self.atom_names.clone(),
-            groups: self.groups.clone(),
+        /// Same as `measure`, taking the nearest periodic image of every atom relative to the
+        /// previous one in the selection.
+        pub fn measure_periodic(
+            &self,
+            atoms: &[usize],
+            cell: &UnitCell,
+        ) -> Result<f64, LMECoreError> {
+            self.measure_with(atoms, |from, to| cell.minimum_image(&(to - from)))
         }
-    }
-}
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
-pub struct StackTree {
-    layer: Layer,
-    indexes: Vec<usize>,
-    children: Vec<StackTree>,
-}
+        fn measure_with(
+            &self,
+            atoms: &[usize],
+            displacement: impl Fn(&Point3<f64>, &Point3<f64>) -> Result<Vector3<f64>, LMECoreError>,
+        ) -> Result<f64, LMECoreError> {
+            let points = atoms
+                .iter()
+                .map(|idx| {
+                    Ok(self
+                        .get_atom(*idx)
+                        .ok_or(LMECoreError::NoSuchAtom)?
+                        .position)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            match points[..] {
+                [a, b] => Ok(displacement(&a, &b)?.norm()),
+                [a, b, c] => Ok(displacement(&b, &a)?
+                    .angle(&displacement(&b, &c)?)
+                    .to_degrees()),
+                [a, b, c, d] => {
+                    let (ba, bc, cd) = (
+                        displacement(&b, &a)?,
+                        displacement(&b, &c)?,
+                        displacement(&c, &d)?,
+                    );
+                    let axis = bc.normalize();
+                    let v = ba - axis * ba.dot(&axis);
+                    let w = cd - axis * cd.dot(&axis);
+                    let x = v.dot(&w);
+                    let y = axis.cross(&v).dot(&w);
+                    Ok(y.atan2(x).to_degrees())
+                }
+                _ => Err(LMECoreError::InvalidSelection(format!(
+                    "Expected 2, 3 or 4 atoms to measure, got {}",
+                    atoms.len()
+                ))),
+            }
+        }
 
-impl StackTree {
-    pub fn dehydration<'a, I>(stacks: I) -> Vec<StackTree>
-    where
-        I: IntoIterator<Item = &'a Arc<Stack>>,
-    {
-        let mut trees = vec![];
-        for (idx, stack) in stacks.into_iter().enumerate() {
-            let matched = trees
-                .iter_mut()
-                .map(|tree: &mut StackTree| tree.merge(idx, stack.get_layers()))
-                .any(|result| result);
-            if !matched {
-                trees.push(StackTree::from((stack.get_layers().as_slice(), idx)))
+        /// Builds a patch bonding every atom of `a` to every atom of `b` with `kind`, restricted to
+        /// pairs closer than `cutoff` when one is given. Both selections must be present atoms.
+        pub fn bond_selections(
+            &self,
+            a: &[usize],
+            b: &[usize],
+            kind: BondKind,
+            cutoff: Option<f64>,
+        ) -> Result<Self, LMECoreError> {
+            let atoms_of = |selection: &[usize]| {
+                selection
+                    .iter()
+                    .map(|idx| Ok((*idx, *self.get_atom(*idx).ok_or(LMECoreError::NoSuchAtom)?)))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let (a, b) = (atoms_of(a)?, atoms_of(b)?);
+            let bonds = a
+                .par_iter()
+                .flat_map_iter(|(i, atom_a)| {
+                    b.iter().filter_map(move |(j, atom_b)| {
+                        let distance = (atom_a.position - atom_b.position).norm();
+                        (i != j && cutoff.is_none_or(|cutoff| distance <= cutoff))
+                            .then_some((Pair::new_ordered(*i, *j), kind))
+                    })
+                })
+                .collect();
+            Ok(Self {
+                bonds,
+                ..Default::default()
+            })
+        }
+
+        /// Keeps only the given present atoms, the bonds among them and their group memberships.
+        pub fn subset(&self, indexes: &[usize]) -> Self {
+            let atoms = indexes
+                .par_iter()
+                .filter_map(|idx| {
+                    self.atoms
+                        .get(idx)
+                        .copied()
+                        .flatten()
+                        .map(|atom| (*idx, Some(atom)))
+                })
+                .collect::<HashMap<_, _>>();
+            let bonds = self
+                .bonds
+                .par_iter()
+                .filter(|(pair, _)| {
+                    let (a, b) = (**pair).into();
+                    atoms.contains_key(&a) && atoms.contains_key(&b)
+                })
+                .map(|(pair, order)| (*pair, *order))
+                .collect();
+            let groups = self
+                .groups
+                .data()
+                .par_iter()
+                .filter(|(idx, _)| atoms.contains_key(idx))
+                .cloned()
+                .collect::<HashSet<_>>();
+            Self {
+                atoms,
+                bonds,
+                groups: NtoN::from(groups),
             }
         }
-        trees
+
+        pub fn next_index(&self) -> usize {
+            self.atoms.keys().max().map_or(0, |max| max + 1)
+        }
+
+        /// Builds a patch holding `count` copies of the `unit` atoms and the bonds among them,
+        /// the k-th copy moved by `step` applied k times. New indexes start past the current
+        /// maximum index; they are returned per copy, in the order of `unit`. Nothing is built
+        /// when the copies would hold more than `limit` atoms, capped at `MAX_REPEAT_ATOMS`.
+        pub fn repeat(
+            &self,
+            unit: &[usize],
+            step: &Transform3<f64>,
+            count: usize,
+            limit: Option<usize>,
+        ) -> Result<(Self, Vec<Vec<usize>>), LMECoreError> {
+            let limit = limit.map_or(MAX_REPEAT_ATOMS, |limit| limit.min(MAX_REPEAT_ATOMS));
+            if count.saturating_mul(unit.len()) > limit {
+                return Err(LMECoreError::TooManyAtoms(limit));
+            }
+            check_affine(step)?;
+            let atoms = unit
+                .iter()
+                .map(|idx| self.get_atom(*idx).copied().ok_or(LMECoreError::NoSuchAtom))
+                .collect::<Result<Vec<_>, _>>()?;
+            let bonds = self
+                .bonds
+                .iter()
+                .filter_map(|(pair, order)| {
+                    let (a, b) = (*pair).into();
+                    let a = unit.iter().position(|idx| *idx == a)?;
+                    let b = unit.iter().position(|idx| *idx == b)?;
+                    Some((a, b, *order))
+                })
+                .collect::<Vec<_>>();
+            let mut patch = Self::default();
+            let mut copies = vec![];
+            let mut transform = Transform3::identity();
+            let mut offset = self.next_index();
+            for _ in 0..count {
+                transform = step * transform;
+                patch.atoms.extend(
+                    atoms
+                        .iter()
+                        .enumerate()
+                        .map(|(i, atom)| (offset + i, Some(atom.transform_position(&transform)))),
+                );
+                patch.bonds.extend(
+                    bonds
+                        .iter()
+                        .map(|(a, b, order)| (Pair::new_ordered(offset + a, offset + b), *order)),
+                );
+                copies.push((offset..offset + atoms.len()).collect());
+                offset += atoms.len();
+            }
+            Ok((patch, copies))
+        }
+
+        /// Builds a patch placing `structure` from `offset`, or past the current maximum index
+        /// when no offset is given. A target slot may only hold a present atom if it belongs to
+        /// exactly the same groups as the incoming one, so re-importing an edited selection keeps
+        /// atom identities while unrelated atoms are never overwritten.
+        pub fn import_structure(
+            &self,
+            structure: CompactedMolecule,
+            offset: Option<usize>,
+        ) -> Result<(Self, Range<usize>), LMECoreError> {
+            let offset = offset.unwrap_or_else(|| self.next_index());
+            for idx in 0..structure.len() {
+                let slot = offset + idx;
+                if self.get_atom(slot).is_some() {
+                    let groups = self.groups.get_left(&slot);
+                    if groups.is_empty() || groups != structure.groups.get_left(&idx) {
+                        return Err(LMECoreError::AtomSlotOccupied(slot));
+                    }
+                }
+            }
+            let range = offset..offset + structure.len();
+            Ok((structure.unzip(offset), range))
+        }
+
+        /// Groups the present atoms into connected fragments, each sorted by index.
+        pub fn fragments(&self) -> Vec<Vec<usize>> {
+            let mut parents = self
+                .atoms
+                .iter()
+                .filter_map(|(idx, atom)| atom.map(|_| (*idx, *idx)))
+                .collect::<HashMap<_, _>>();
+            fn root(parents: &mut HashMap<usize, usize>, idx: usize) -> usize {
+                let mut root = idx;
+                while parents[&root] != root {
+                    root = parents[&root];
+                }
+                let mut current = idx;
+                while current != root {
+                    current = parents.insert(current, root).unwrap_or(root);
+                }
+                root
+            }
+            for pair in self.bonds.keys() {
+                let (a, b) = (*pair).into();
+                if parents.contains_key(&a) && parents.contains_key(&b) {
+                    let (a, b) = (root(&mut parents, a), root(&mut parents, b));
+                    parents.insert(a.max(b), a.min(b));
+                }
+            }
+            let mut fragments: HashMap<usize, Vec<usize>> = HashMap::new();
+            for idx in parents.keys().copied().collect::<Vec<_>>() {
+                fragments
+                    .entry(root(&mut parents, idx))
+                    .or_default()
+                    .push(idx);
+            }
+            let mut fragments = fragments.into_values().collect::<Vec<_>>();
+            fragments.iter_mut().for_each(|fragment| fragment.sort());
+            fragments.sort();
+            fragments
+        }
+
+        /// Present atoms connected to the present atom `idx` through bonds, itself included,
+        /// sorted. Walks only that fragment instead of computing all of them like `fragments`.
+        pub fn fragment_of(&self, idx: usize) -> Result<Vec<usize>, LMECoreError> {
+            self.get_atom(idx).ok_or(LMECoreError::NoSuchAtom)?;
+            let mut adjacency: HashMap<usize, Vec<usize>> = HashMap::new();
+            for pair in self.bonds.keys() {
+                let (a, b) = (*pair).into();
+                if a != b && self.get_atom(a).is_some() && self.get_atom(b).is_some() {
+                    adjacency.entry(a).or_default().push(b);
+                    adjacency.entry(b).or_default().push(a);
+                }
+            }
+            let mut visited = HashSet::from([idx]);
+            let mut queue = VecDeque::from([idx]);
+            while let Some(current) = queue.pop_front() {
+                for next in adjacency.get(&current).into_iter().flatten() {
+                    if visited.insert(*next) {
+                        queue.push_back(*next);
+                    }
+                }
+            }
+            let mut fragment = visited.into_iter().collect::<Vec<_>>();
+            fragment.sort_unstable();
+            Ok(fragment)
+        }
+
+        /// Formula of the present atoms in Hill order: carbon, then hydrogen, then the other
+        /// elements alphabetically, or all of them alphabetically without carbon.
+        pub fn formula(&self) -> String {
+            let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+            for atom in self.atoms.values().flatten() {
+                *counts
+                    .entry(periodic::symbol(atom.element).unwrap_or("X"))
+                    .or_insert(0) += 1;
+            }
+            let mut symbols = counts.keys().copied().collect::<Vec<_>>();
+            if counts.contains_key("C") {
+                symbols.sort_by_key(|symbol| match *symbol {
+                    "C" => 0,
+                    "H" => 1,
+                    _ => 2,
+                });
+            }
+            symbols
+                .into_iter()
+                .map(|symbol| match counts[symbol] {
+                    1 => symbol.to_string(),
+                    count => format!("{symbol}{count}"),
+                })
+                .collect()
+        }
+
+        pub fn composition(&self) -> CompositionReport {
+            let mut elements = BTreeMap::new();
+            for atom in self.atoms.values().flatten() {
+                *elements.entry(atom.element).or_insert(0) += 1;
+            }
+            CompositionReport {
+                atoms: elements.values().sum(),
+                elements,
+                fragments: self.fragments().len(),
+            }
+        }
+
+        pub fn merge(mut low: Self, high: Self) -> Self {
+            low.atoms.extend(high.atoms);
+            low.bonds.extend(high.bonds);
+            low.groups.extend(high.groups);
+            low
+        }
     }
 
-    pub fn hydration<'a, I>(trees: I) -> Vec<Arc<Stack>>
-    where
-        I: IntoIterator<Item = &'a StackTree>,
-    {
-        let mut stacks: HashMap<usize, Arc<Stack>> = HashMap::new();
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    pub struct CompositionReport {
+        pub elements: BTreeMap<usize, usize>,
+        pub atoms: usize,
+        pub fragments: usize,
+    }
 
-        for tree in trees.into_iter() {
-            stacks.extend(tree.to_stacks(&vec![]));
+    /// Hashes the present atoms with their indexes, elements and positions rounded to `decimals`
+    /// decimals, and the bonds with their kinds. Positions on both sides of a rounding boundary
+    /// hash differently, so equal fingerprints flag near-duplicates and different ones do not
+    /// prove two structures distinct. Groups are not part of the fingerprint.
+    pub fn molecule_fingerprint(molecule: &Molecule, decimals: i32) -> u64 {
+        let scale = 10f64.powi(decimals);
+        let mut atoms = molecule
+            .atoms
+            .iter()
+            .filter_map(|(idx, atom)| {
+                atom.map(|atom| {
+                    let position = atom.position.map(|x| (x * scale).round() as i64);
+                    (*idx, atom.element, [position.x, position.y, position.z])
+                })
+            })
+            .collect::<Vec<_>>();
+        atoms.sort_unstable();
+        let mut bonds = molecule
+            .bonds
+            .iter()
+            .map(|(pair, kind)| {
+                let (a, b): (usize, usize) = (*pair).into();
+                ((a, b), mem::discriminant(kind), kind.order().to_bits())
+            })
+            .collect::<Vec<_>>();
+        bonds.sort_unstable_by_key(|(pair, _, order)| (*pair, *order));
+        let mut hasher = DefaultHasher::new();
+        atoms.hash(&mut hasher);
+        bonds.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Hashes everything a molecule serializes to, removed atom slots and groups included, so
+    /// that unlike `molecule_fingerprint` any change gives a different digest but for collisions.
+    pub fn molecule_digest(molecule: &Molecule) -> u64 {
+        let mut atoms = molecule
+            .atoms
+            .iter()
+            .map(|(idx, atom)| {
+                let atom = atom.map(|atom| (atom.element, atom.position.map(f64::to_bits)));
+                (*idx, atom.map(|(element, p)| (element, [p.x, p.y, p.z])))
+            })
+            .collect::<Vec<_>>();
+        atoms.sort_unstable();
+        let mut bonds = molecule
+            .bonds
+            .iter()
+            .map(|(pair, kind)| (*pair, mem::discriminant(kind), kind.order().to_bits()))
+            .collect::<Vec<_>>();
+        bonds.sort_unstable_by_key(|(pair, _, order)| (*pair, *order));
+        let mut groups = molecule.groups.data().iter().collect::<Vec<_>>();
+        groups.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        atoms.hash(&mut hasher);
+        bonds.hash(&mut hasher);
+        groups.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Compares two molecules index by index, allowing atom positions to differ by `pos_tol` and
+    /// bond orders by `bond_tol`. Elements and groups must match exactly.
+    pub fn molecules_approx_eq(a: &Molecule, b: &Molecule, pos_tol: f64, bond_tol: f64) -> bool {
+        let atoms_eq = a.atoms.len() == b.atoms.len()
+            && a.atoms
+                .iter()
+                .all(|(idx, atom)| match (atom, b.atoms.get(idx)) {
+                    (None, Some(None)) => true,
+                    (Some(atom), Some(Some(other))) => {
+                        atom.element == other.element
+                            && (atom.position - other.position).norm() <= pos_tol
+                    }
+                    _ => false,
+                });
+        let bonds_eq = a.bonds.len() == b.bonds.len()
+            && a.bonds.iter().all(|(pair, order)| {
+                b.bonds
+                    .get(pair)
+                    .is_some_and(|other| (order.order() - other.order()).abs() <= bond_tol)
+            });
+        atoms_eq && bonds_eq && a.groups == b.groups
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+    pub struct CompactedMolecule {
+        atoms: Vec<Atom>,
+        #[serde(with = "bond_map")]
+        bonds: HashMap<Pair<usize>, BondKind>,
+        groups: NtoN<usize, String>,
+    }
+
+    impl CompactedMolecule {
+        pub fn new(
+            atoms: Vec<Atom>,
+            bonds: HashMap<Pair<usize>, BondKind>,
+            groups: NtoN<usize, String>,
+        ) -> Self {
+            Self {
+                atoms,
+                bonds,
+                groups,
+            }
         }
 
-        let mut stacks = stacks.into_iter().collect::<Vec<_>>();
-        stacks.sort_by(|(a, _), (b, _)| a.cmp(b));
-        stacks.into_iter().map(|(_, stack)| stack).collect()
+        pub fn len(&self) -> usize {
+            self.atoms.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.atoms.is_empty()
+        }
+
+        pub fn unzip(self, offset: usize) -> Molecule {
+            let atoms = self
+                .atoms
+                .into_par_iter()
+                .enumerate()
+                .map(|(idx, atom)| (idx + offset, Some(atom)))
+                .collect::<HashMap<_, _>>();
+            let bonds = self
+                .bonds
+                .into_par_iter()
+                .map(|(pair, bond_order)| (pair.offset(offset), bond_order))
+                .collect::<HashMap<_, _>>();
+            let mut groups = self.groups;
+            groups.map_left(|idx| idx + offset);
+            Molecule {
+                atoms,
+                bonds,
+                groups,
+            }
+        }
     }
 
-    fn to_stacks(&self, base: &Vec<Arc<Layer>>) -> HashMap<usize, Arc<Stack>> {
-        let mut map = HashMap::new();
-        let mut base = base.clone();
-        base.push(Arc::new(self.layer.clone()));
-        for index in &self.indexes {
-            map.insert(*index, Arc::new(Stack::new(base.clone())));
+    /// Lattice vectors of a periodic cell, in Cartesian coordinates.
+    #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+    pub struct UnitCell {
+        pub a: Vector3<f64>,
+        pub b: Vector3<f64>,
+        pub c: Vector3<f64>,
+    }
+
+    impl UnitCell {
+        /// Matrix whose columns are the lattice vectors, mapping fractional to Cartesian.
+        pub fn matrix(&self) -> Matrix3<f64> {
+            Matrix3::from_columns(&[self.a, self.b, self.c])
         }
-        for child in &self.children {
-            map.extend(child.to_stacks(&base));
+
+        pub fn to_cartesian(&self, fractional: &Point3<f64>) -> Point3<f64> {
+            Point3::from(self.matrix() * fractional.coords)
+        }
+
+        pub fn to_fractional(&self, cartesian: &Point3<f64>) -> Result<Point3<f64>, LMECoreError> {
+            let inverse = self.matrix().try_inverse().ok_or_else(|| {
+                LMECoreError::InvalidTransform("The unit cell has no volume".to_string())
+            })?;
+            Ok(Point3::from(inverse * cartesian.coords))
+        }
+
+        pub fn volume(&self) -> f64 {
+            self.matrix().determinant().abs()
+        }
+
+        /// Lengths a, b, c and angles α, β, γ in degrees, α being the angle between b and c.
+        pub fn parameters(&self) -> (f64, f64, f64, f64, f64, f64) {
+            let angle = |u: &Vector3<f64>, v: &Vector3<f64>| u.angle(v).to_degrees();
+            (
+                self.a.norm(),
+                self.b.norm(),
+                self.c.norm(),
+                angle(&self.b, &self.c),
+                angle(&self.a, &self.c),
+                angle(&self.a, &self.b),
+            )
+        }
+
+        /// Shortest vector equivalent to `displacement` under the cell periodicity.
+        pub fn minimum_image(
+            &self,
+            displacement: &Vector3<f64>,
+        ) -> Result<Vector3<f64>, LMECoreError> {
+            let fractional = self.to_fractional(&Point3::from(*displacement))?.coords;
+            Ok(self.matrix() * fractional.map(|x| x - x.round()))
         }
-        map
     }
 
-    fn merge(&mut self, idx: usize, layers: &[Arc<Layer>]) -> bool {
-        let (current, elements) = layers
-            .split_first()
-            .expect("Should never hint this condition");
-        if current.as_ref() == &self.layer {
-            if elements.len() == 0 {
-                self.indexes.push(idx);
-            } else {
-                let matched = self
-                    .children
-                    .iter_mut()
-                    .map(|item| item.merge(idx, elements))
-                    .any(|result| result);
-                if !matched {
-                    self.children.push(StackTree::from((elements, idx)))
+    #[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+    pub enum PluginInput {
+        #[default]
+        Stdin,
+        /// Writes the molecule to a temporary file and substitutes its path for `{input}` in the
+        /// plugin arguments. Plugins reading stdout before draining stdin can't deadlock this way.
+        TempFile,
+    }
+
+    #[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+    #[serde(default)]
+    pub struct PluginOptions {
+        /// Extra environment variables for the plugin process. They are serialized into the
+        /// workspace export like the rest of the layer, so never put secrets here.
+        pub env: HashMap<String, String>,
+        /// Working directory of the plugin process, relative to the plugin directory. Defaults
+        /// to the plugin directory itself so plugins can load their bundled data files.
+        pub cwd: Option<PathBuf>,
+        /// Maximum number of bytes read from the plugin's stdout before it is killed, defaults
+        /// to `PLUGIN_OUTPUT_LIMIT`.
+        pub max_output_bytes: Option<usize>,
+        pub input: PluginInput,
+        /// The plugin writes its answer gzipped, for large molecules. Progress frames can't be
+        /// read from compressed output, and the output limit applies before and after
+        /// decompression.
+        pub output_gzip: bool,
+    }
+
+    impl PluginOptions {
+        /// Resolves `cwd` in the plugin directory. Absolute paths and `..` would leave it, so
+        /// they are refused.
+        pub fn working_directory(&self) -> Result<PathBuf, LMECoreError> {
+            let Some(cwd) = &self.cwd else {
+                return Ok(PLUGIN_DIRECTORY.clone());
+            };
+            if !cwd
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(LMECoreError::InvalidRequest(format!(
+                    "Plugin working directory {} is not inside the plugin directory",
+                    cwd.display()
+                )));
+            }
+            Ok(PLUGIN_DIRECTORY.join(cwd))
+        }
+    }
+
+    const AFFINE_TOLERANCE: f64 = 1e-9;
+    const ROTATION_TOLERANCE: f64 = 1e-6;
+
+    /// Checks that a matrix is a proper rotation, orthonormal with a determinant of 1.
+    pub fn check_rotation(matrix: &Matrix3<f64>) -> Result<(), LMECoreError> {
+        if (matrix.transpose() * matrix - Matrix3::identity()).amax() > ROTATION_TOLERANCE
+            || (matrix.determinant() - 1.).abs() > ROTATION_TOLERANCE
+        {
+            return Err(LMECoreError::InvalidTransform(
+                "The matrix is not a rotation".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Unit vectors to the corners of the usual polyhedron with `count` corners, the first one
+    /// along z and the second one, when not opposite to it, in the xz plane.
+    fn ideal_directions(count: usize) -> Option<Vec<Vector3<f64>>> {
+        let (third, sqrt3) = (1. / 3., 3f64.sqrt());
+        let directions = match count {
+            1 => vec![Vector3::z()],
+            2 => vec![Vector3::z(), -Vector3::z()],
+            3 => vec![
+                Vector3::z(),
+                Vector3::new(sqrt3 / 2., 0., -0.5),
+                Vector3::new(-sqrt3 / 2., 0., -0.5),
+            ],
+            4 => vec![
+                Vector3::z(),
+                Vector3::new((8. / 9f64).sqrt(), 0., -third),
+                Vector3::new(-(2. / 9f64).sqrt(), (2. / 3f64).sqrt(), -third),
+                Vector3::new(-(2. / 9f64).sqrt(), -(2. / 3f64).sqrt(), -third),
+            ],
+            5 => vec![
+                Vector3::z(),
+                Vector3::x(),
+                -Vector3::z(),
+                Vector3::new(-0.5, sqrt3 / 2., 0.),
+                Vector3::new(-0.5, -sqrt3 / 2., 0.),
+            ],
+            6 => vec![
+                Vector3::z(),
+                Vector3::x(),
+                -Vector3::z(),
+                -Vector3::x(),
+                Vector3::y(),
+                -Vector3::y(),
+            ],
+            _ => return None,
+        };
+        Some(directions)
+    }
+
+    /// Directions of `missing` new bonds next to the existing bond directions `existing`. The
+    /// ideal polyhedron is turned onto the first two existing bonds and its corners closest to
+    /// them are taken out. Empty beyond six bonds in total.
+    fn hydrogen_directions(existing: &[Vector3<f64>], missing: usize) -> Vec<Vector3<f64>> {
+        if missing == 1 {
+            if let Some(direction) = (-existing.iter().sum::<Vector3<f64>>()).try_normalize(1e-3) {
+                return vec![direction];
+            }
+        }
+        let Some(mut corners) = ideal_directions(existing.len() + missing) else {
+            return vec![];
+        };
+        if let Some(first) = existing.first() {
+            let rotation = Rotation3::rotation_between(&corners[0], first)
+                .unwrap_or_else(|| Rotation3::from_axis_angle(&Vector3::x_axis(), PI));
+            corners
+                .iter_mut()
+                .for_each(|corner| *corner = rotation * *corner);
+            if let Some(second) = existing.get(1) {
+                let axis = Unit::new_normalize(*first);
+                let project = |v: &Vector3<f64>| v - axis.into_inner() * v.dot(&axis);
+                let (from, to) = (project(&corners[1]), project(second));
+                if from.norm() > 1e-3 && to.norm() > 1e-3 {
+                    let angle = from.cross(&to).dot(&axis).atan2(from.dot(&to));
+                    let rotation = Rotation3::from_axis_angle(&axis, angle);
+                    corners
+                        .iter_mut()
+                        .for_each(|corner| *corner = rotation * *corner);
                 }
             }
-            true
-        } else {
-            false
         }
+        for direction in existing {
+            let closest = corners
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.dot(direction).total_cmp(&b.dot(direction)))
+                .map(|(idx, _)| idx);
+            if let Some(closest) = closest {
+                corners.swap_remove(closest);
+            }
+        }
+        corners
     }
-}
 
-impl From<(&[Arc<Layer>], usize)> for StackTree {
-    fn from((stack, idx): (&[Arc<Layer>], usize)) -> Self {
-        let (bottom, highers) = stack.split_first().expect("Don't create with empty stack");
-        if highers.len() == 0 {
-            Self {
-                layer: bottom.as_ref().clone(),
-                indexes: vec![idx],
-                children: vec![],
+    /// Checks that a transform deserialized from a raw 4x4 matrix is affine and invertible, as
+    /// nalgebra accepts any matrix for `Transform3`.
+    pub fn check_affine(transform: &Transform3<f64>) -> Result<(), LMECoreError> {
+        let matrix = transform.matrix();
+        let last_row = matrix.fixed_view::<1, 4>(3, 0) - RowVector4::new(0., 0., 0., 1.);
+        if last_row.amax() > AFFINE_TOLERANCE {
+            return Err(LMECoreError::InvalidTransform(
+                "The last row of the matrix must be [0, 0, 0, 1]".to_string(),
+            ));
+        }
+        if matrix.fixed_view::<3, 3>(0, 0).determinant().abs() <= AFFINE_TOLERANCE {
+            return Err(LMECoreError::InvalidTransform(
+                "The linear part of the matrix is not invertible".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Order of `Layer::SortAtoms`.
+    #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SortKey {
+        Index,
+        Element,
+        ZCoordinate,
+    }
+
+    impl SortKey {
+        fn compare(&self, a: &Atom, b: &Atom) -> std::cmp::Ordering {
+            match self {
+                Self::Index => std::cmp::Ordering::Equal,
+                Self::Element => a.element.cmp(&b.element),
+                Self::ZCoordinate => a.position.z.total_cmp(&b.position.z),
             }
-        } else {
-            Self {
-                layer: bottom.as_ref().clone(),
-                indexes: vec![],
-                children: vec![StackTree::from((highers, idx))],
+        }
+    }
+
+    /// Per-atom scalar written by `Layer::MapScalar`.
+    #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ScalarTarget {
+        Element,
+    }
+
+    /// Per-atom scalar read by an `ArithExpr`.
+    #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ScalarVar {
+        Element,
+        X,
+        Y,
+        Z,
+    }
+
+    /// Arithmetic over the scalars of an atom, e.g. `{"Add": [{"Var": "element"}, {"Const": 1}]}`.
+    /// Without division, evaluating always gives a number.
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+    pub enum ArithExpr {
+        Const(f64),
+        Var(ScalarVar),
+        Add(Box<ArithExpr>, Box<ArithExpr>),
+        Mul(Box<ArithExpr>, Box<ArithExpr>),
+    }
+
+    impl ArithExpr {
+        pub fn eval(&self, atom: &Atom) -> f64 {
+            match self {
+                Self::Const(value) => *value,
+                Self::Var(ScalarVar::Element) => atom.element as f64,
+                Self::Var(ScalarVar::X) => atom.position.x,
+                Self::Var(ScalarVar::Y) => atom.position.y,
+                Self::Var(ScalarVar::Z) => atom.position.z,
+                Self::Add(a, b) => a.eval(atom) + b.eval(atom),
+                Self::Mul(a, b) => a.eval(atom) * b.eval(atom),
+            }
+        }
+    }
+
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+    pub enum Layer {
+        Fill(Molecule),
+        Transform(Transform3<f64>),
+        /// Rotates the atoms by `matrix` around `center`.
+        Rotation {
+            matrix: Matrix3<f64>,
+            center: Point3<f64>,
+        },
+        IgnoreBonds,
+        ReplaceElement(usize, usize),
+        RemoveElement(usize),
+        HideElement(usize),
+        /// Hides hydrogens bonded to a single atom whose element is in the table and whose bond
+        /// orders add up to the valence given there, so hydrogens of atoms with an unusual
+        /// valence stay visible.
+        HideHydrogens(HashMap<usize, usize>),
+        /// Removes the atoms without any bond to another present atom.
+        OnlyBondedAtoms,
+        /// Reads atom positions as fractional coordinates of the cell and converts them to
+        /// Cartesian ones. `None` stands for the workspace cell, see `Workspace::bind_cell`.
+        FractionalToCartesian(Option<UnitCell>),
+        CartesianToFractional(Option<UnitCell>),
+        /// Moves every atom into the primary cell, leaving bonds untouched.
+        WrapIntoCell(Option<UnitCell>),
+        /// Turns single bonds into the order whose typical length is closest to the distance of
+        /// the atoms. Element pairs missing from `periodic::bond_lengths` are left as they are.
+        GuessBondOrders,
+        /// Keeps, for every present atom whose element is in `max`, only the bonds to its `max`
+        /// closest present neighbors, ties going to the lower neighbor index. Bonds are dropped
+        /// as soon as one of their atoms is over its cap.
+        CapCoordination {
+            max: HashMap<usize, usize>,
+        },
+        /// Adds hydrogens to the present heavy atoms whose bond orders fall short of their
+        /// valence, taken from `target_valence` or else `periodic::valence`. The hydrogens sit
+        /// at the free corners of the ideal polyhedron around the atom, or opposite to the
+        /// existing bonds when a single one is missing, and get indexes past the maximum index.
+        FillValences {
+            target_valence: HashMap<usize, usize>,
+        },
+        /// Renumbers the present atoms from 0 in the order of `by`, ties keeping the index
+        /// order, and remaps bonds and groups. Workspace atom names and anything else keyed by
+        /// the old indexes don't follow, and layers above address the new indexes.
+        SortAtoms {
+            by: SortKey,
+        },
+        /// Sets `target` of every present atom to `expr`, rounded for elements. Reading fails if
+        /// an atom ends up with no valid element.
+        MapScalar {
+            target: ScalarTarget,
+            expr: ArithExpr,
+        },
+        PluginFilter(String, Vec<String>, #[serde(default)] PluginOptions),
+        Comment(String),
+        Clear,
+    }
+
+    impl Layer {
+        /// Rejects layers built from malformed client input before they enter a stack.
+        pub fn validate(&self) -> Result<(), LMECoreError> {
+            match self {
+                Self::Transform(transform) => check_affine(transform),
+                Self::Rotation { matrix, .. } => check_rotation(matrix),
+                Self::PluginFilter(_, _, options) => options.working_directory().map(|_| ()),
+                _ => Ok(()),
             }
         }
+
+        /// Applies the layer, logging the progress frames of streaming plugins.
+        pub fn filter(&self, low: Molecule) -> Result<Molecule, LMECoreError> {
+            let Self::PluginFilter(plugin, ..) = self else {
+                return self.filter_with_progress(low, &mut |_| ());
+            };
+            self.filter_with_progress(low, &mut |progress| {
+                tracing::info!(plugin, progress, "Plugin progress")
+            })
+        }
+
+        /// Same as `filter`, reporting the progress frames of streaming plugins.
+        pub fn filter_with_progress(
+            &self,
+            mut low: Molecule,
+            on_progress: &mut dyn FnMut(f64),
+        ) -> Result<Molecule, LMECoreError> {
+            match self {
+                Self::Fill(high) => Ok(Molecule::merge(low, high.clone())),
+                Self::Transform(transform) => {
+                    low.atoms.iter_mut().for_each(|(_, atom)| {
+                        *atom = atom.map(|atom| atom.transform_position(transform))
+                    });
+                    Ok(low)
+                }
+                Self::Rotation { matrix, center } => {
+                    for atom in low.atoms.values_mut().flatten() {
+                        *atom = atom.set_position(center + matrix * (atom.position - center));
+                    }
+                    Ok(low)
+                }
+                Self::Comment(_) => Ok(low),
+                Self::Clear => Ok(Molecule::default()),
+                Self::IgnoreBonds => {
+                    low.bonds = HashMap::new();
+                    Ok(low)
+                }
+                Self::ReplaceElement(origin, target) => {
+                    low.atoms.iter_mut().for_each(|(_, atom)| {
+                        *atom = atom.map(|atom| {
+                            if &atom.element == origin {
+                                atom.set_element(*target)
+                            } else {
+                                atom
+                            }
+                        })
+                    });
+                    Ok(low)
+                }
+                Self::RemoveElement(element) => {
+                    low.atoms.iter_mut().for_each(|(_, atom)| {
+                        *atom = atom.and_then(|atom| {
+                            if &atom.element == element {
+                                None
+                            } else {
+                                Some(atom)
+                            }
+                        })
+                    });
+                    Ok(low)
+                }
+                Self::HideElement(element) => {
+                    let hidden = low
+                        .atoms
+                        .iter_mut()
+                        .filter(|(_, atom)| atom.is_some_and(|atom| &atom.element == element))
+                        .map(|(idx, atom)| {
+                            *atom = None;
+                            *idx
+                        })
+                        .collect::<HashSet<_>>();
+                    low.bonds.retain(|pair, _| {
+                        let (a, b) = (*pair).into();
+                        !hidden.contains(&a) && !hidden.contains(&b)
+                    });
+                    Ok(low)
+                }
+                Self::HideHydrogens(valences) => {
+                    let mut adjacency: HashMap<usize, Vec<(usize, &BondKind)>> = HashMap::new();
+                    for (pair, kind) in &low.bonds {
+                        let (a, b) = (*pair).into();
+                        if a != b && low.get_atom(a).is_some() && low.get_atom(b).is_some() {
+                            adjacency.entry(a).or_default().push((b, kind));
+                            adjacency.entry(b).or_default().push((a, kind));
+                        }
+                    }
+                    let neighbors = |idx: usize| adjacency.get(&idx).map_or(&[][..], Vec::as_slice);
+                    let saturated = |idx: usize| {
+                        low.get_atom(idx).is_some_and(|atom| {
+                            valences.get(&atom.element).is_some_and(|valence| {
+                                let orders = neighbors(idx).iter().map(|(_, kind)| kind.order());
+                                (orders.sum::<f64>() - *valence as f64).abs() < 1e-3
+                            })
+                        })
+                    };
+                    let hidden = low
+                        .atoms
+                        .iter()
+                        .filter(|(_, atom)| atom.is_some_and(|atom| atom.element == 1))
+                        .filter(|(idx, _)| match neighbors(**idx) {
+                            [(heavy, _)] => saturated(*heavy),
+                            _ => false,
+                        })
+                        .map(|(idx, _)| *idx)
+                        .collect::<HashSet<_>>();
+                    low.atoms
+                        .iter_mut()
+                        .filter(|(idx, _)| hidden.contains(idx))
+                        .for_each(|(_, atom)| *atom = None);
+                    low.bonds.retain(|pair, _| {
+                        let (a, b) = (*pair).into();
+                        !hidden.contains(&a) && !hidden.contains(&b)
+                    });
+                    Ok(low)
+                }
+                Self::OnlyBondedAtoms => {
+                    let bonded = low
+                        .bonds
+                        .keys()
+                        .map(|pair| (*pair).into())
+                        .filter(|(a, b)| {
+                            a != b && low.get_atom(*a).is_some() && low.get_atom(*b).is_some()
+                        })
+                        .flat_map(|(a, b): (usize, usize)| [a, b])
+                        .collect::<HashSet<_>>();
+                    low.atoms.iter_mut().for_each(|(idx, atom)| {
+                        if !bonded.contains(idx) {
+                            *atom = None
+                        }
+                    });
+                    Ok(low)
+                }
+                Self::FractionalToCartesian(cell) => {
+                    let cell = cell.ok_or(LMECoreError::NoUnitCell)?;
+                    low.atoms.iter_mut().for_each(|(_, atom)| {
+                        *atom =
+                            atom.map(|atom| atom.set_position(cell.to_cartesian(&atom.position)))
+                    });
+                    Ok(low)
+                }
+                Self::CartesianToFractional(cell) => {
+                    let cell = cell.ok_or(LMECoreError::NoUnitCell)?;
+                    for atom in low.atoms.values_mut().flatten() {
+                        *atom = atom.set_position(cell.to_fractional(&atom.position)?);
+                    }
+                    Ok(low)
+                }
+                Self::WrapIntoCell(cell) => {
+                    let cell = cell.ok_or(LMECoreError::NoUnitCell)?;
+                    for atom in low.atoms.values_mut().flatten() {
+                        let fractional = cell.to_fractional(&atom.position)?;
+                        let wrapped = fractional.map(|x| x - x.floor());
+                        *atom = atom.set_position(cell.to_cartesian(&wrapped));
+                    }
+                    Ok(low)
+                }
+                Self::GuessBondOrders => {
+                    let kinds = [BondKind::Single, BondKind::Double, BondKind::Triple];
+                    let guessed = low
+                        .bonds
+                        .iter()
+                        .filter(|(_, kind)| **kind == BondKind::Single)
+                        .filter_map(|(pair, _)| {
+                            let (a, b) = (*pair).into();
+                            let (a, b) = (low.get_atom(a)?, low.get_atom(b)?);
+                            let lengths = periodic::bond_lengths(a.element, b.element)?;
+                            let distance = (a.position - b.position).norm();
+                            let (kind, _) = kinds
+                                .iter()
+                                .zip(lengths)
+                                .filter_map(|(kind, length)| {
+                                    length.map(|length| (kind, (length - distance).abs()))
+                                })
+                                .min_by(|a, b| a.1.total_cmp(&b.1))?;
+                            Some((*pair, *kind))
+                        })
+                        .collect::<Vec<_>>();
+                    low.bonds.extend(guessed);
+                    Ok(low)
+                }
+                Self::CapCoordination { max } => {
+                    let mut neighbors = HashMap::<usize, (usize, Vec<(f64, usize)>)>::new();
+                    for pair in low.bonds.keys() {
+                        let (a, b) = (*pair).into();
+                        let (Some(atom_a), Some(atom_b)) = (low.get_atom(a), low.get_atom(b))
+                        else {
+                            continue;
+                        };
+                        if a == b {
+                            continue;
+                        }
+                        let distance = (atom_a.position - atom_b.position).norm();
+                        for (idx, atom, other) in [(a, atom_a, b), (b, atom_b, a)] {
+                            if let Some(cap) = max.get(&atom.element) {
+                                let (_, bonded) = neighbors.entry(idx).or_insert((*cap, vec![]));
+                                bonded.push((distance, other));
+                            }
+                        }
+                    }
+                    let mut dropped = HashSet::new();
+                    for (idx, (cap, mut bonded)) in neighbors {
+                        bonded.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                        dropped.extend(
+                            bonded
+                                .into_iter()
+                                .skip(cap)
+                                .map(|(_, other)| Pair::new_ordered(idx, other)),
+                        );
+                    }
+                    low.bonds.retain(|pair, _| !dropped.contains(pair));
+                    Ok(low)
+                }
+                Self::FillValences { target_valence } => {
+                    let mut bonded = HashMap::<usize, (f64, Vec<Vector3<f64>>)>::new();
+                    for (pair, kind) in low.bonds.iter() {
+                        let (a, b) = (*pair).into();
+                        let (Some(atom_a), Some(atom_b)) = (low.get_atom(a), low.get_atom(b))
+                        else {
+                            continue;
+                        };
+                        if a == b {
+                            continue;
+                        }
+                        let direction = (atom_b.position - atom_a.position).try_normalize(1e-9);
+                        for (idx, direction) in [(a, direction), (b, direction.map(|d| -d))] {
+                            let (order, directions) = bonded.entry(idx).or_default();
+                            *order += kind.order();
+                            directions.extend(direction);
+                        }
+                    }
+                    let mut heavy = low
+                        .atoms
+                        .iter()
+                        .filter_map(|(idx, atom)| atom.map(|atom| (*idx, atom)))
+                        .filter(|(_, atom)| atom.element != 1)
+                        .collect::<Vec<_>>();
+                    heavy.sort_by_key(|(idx, _)| *idx);
+                    let mut next = low.next_index();
+                    for (idx, atom) in heavy {
+                        let Some(valence) = target_valence
+                            .get(&atom.element)
+                            .copied()
+                            .or_else(|| periodic::valence(atom.element))
+                        else {
+                            continue;
+                        };
+                        let (order, directions) = bonded.remove(&idx).unwrap_or_default();
+                        let missing = (valence as f64 - order).round();
+                        if missing < 1. {
+                            continue;
+                        }
+                        let length = periodic::covalent_radius(atom.element)
+                            .zip(periodic::covalent_radius(1))
+                            .map_or(1.1, |(heavy, hydrogen)| heavy + hydrogen);
+                        for direction in hydrogen_directions(&directions, missing as usize) {
+                            let position = atom.position + direction * length;
+                            low.atoms.insert(next, Some(Atom::new(1, position)));
+                            low.bonds
+                                .insert(Pair::new_ordered(idx, next), BondKind::Single);
+                            next += 1;
+                        }
+                    }
+                    Ok(low)
+                }
+                Self::SortAtoms { by } => {
+                    let mut present = low
+                        .atoms
+                        .iter()
+                        .filter_map(|(idx, atom)| atom.map(|atom| (*idx, atom)))
+                        .collect::<Vec<_>>();
+                    present
+                        .sort_by(|(a_idx, a), (b_idx, b)| by.compare(a, b).then(a_idx.cmp(b_idx)));
+                    let remap = present
+                        .iter()
+                        .enumerate()
+                        .map(|(new, (old, _))| (*old, new))
+                        .collect::<HashMap<_, _>>();
+                    let bonds = low
+                        .bonds
+                        .iter()
+                        .filter_map(|(pair, kind)| {
+                            let (a, b) = (*pair).into();
+                            Some((Pair::new_ordered(*remap.get(&a)?, *remap.get(&b)?), *kind))
+                        })
+                        .collect();
+                    let groups = low
+                        .groups
+                        .data()
+                        .iter()
+                        .filter_map(|(idx, group)| Some((*remap.get(idx)?, group.clone())))
+                        .collect::<HashSet<_>>();
+                    let atoms = present
+                        .into_iter()
+                        .enumerate()
+                        .map(|(new, (_, atom))| (new, Some(atom)))
+                        .collect();
+                    Ok(Molecule::new(atoms, bonds, NtoN::from(groups)))
+                }
+                Self::MapScalar { target, expr } => {
+                    for (idx, atom) in low.atoms.iter_mut() {
+                        let Some(present) = atom else {
+                            continue;
+                        };
+                        let value = expr.eval(present);
+                        match target {
+                            ScalarTarget::Element => {
+                                let element = value.round() as usize;
+                                if periodic::symbol(element).is_none() {
+                                    return Err(LMECoreError::InvalidAtom(format!(
+                                        "Atom {idx} mapped to element {value}"
+                                    )));
+                                }
+                                *present = present.set_element(element);
+                            }
+                        }
+                    }
+                    Ok(low)
+                }
+                Self::PluginFilter(plugin, args, options) => {
+                    let high = run_plugin(plugin, args, options, &low, on_progress)?;
+                    Ok(Molecule::merge(low, high))
+                }
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum PluginFrame<T> {
+        Progress(f64),
+        Molecule(T),
+    }
+
+    /// Runs a plugin answering with a JSON array of molecules, e.g. a conformer generator. Each
+    /// molecule is a patch over `low`, like the output of `Layer::PluginFilter`.
+    pub fn run_plugin_multi(
+        plugin: &str,
+        args: &[String],
+        options: &PluginOptions,
+        low: &Molecule,
+    ) -> Result<Vec<Molecule>, LMECoreError> {
+        run_plugin(plugin, args, options, low, &mut |progress| {
+            tracing::info!(plugin, progress, "Plugin progress")
+        })
+    }
+
+    /// Removes the temporary plugin input once the plugin is done, whatever the outcome.
+    struct TempInput(PathBuf);
+
+    impl TempInput {
+        /// Creates the file only if nothing exists at its path, symlinks included, readable by
+        /// the server user alone, since the name is predictable in a shared directory.
+        fn create(data: &str) -> std::io::Result<Self> {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "lme-plugin-{}-{}.json",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = env::temp_dir().join(name);
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&path)?;
+            let input = Self(path);
+            file.write_all(data.as_bytes())?;
+            Ok(input)
+        }
+    }
+
+    impl Drop for TempInput {
+        fn drop(&mut self) {
+            fs::remove_file(&self.0).ok();
+        }
+    }
+
+    /// Kills and reaps the plugin process when dropped, so an early return leaves neither a
+    /// running plugin nor a zombie. Once the process has been waited for, this does nothing.
+    struct PluginProcess(Child);
+
+    impl Drop for PluginProcess {
+        fn drop(&mut self) {
+            self.0.kill().ok();
+            self.0.wait().ok();
+        }
+    }
+
+    /// Runs a plugin with the molecule on its stdin. The plugin either answers with a single
+    /// output, or streams JSON lines of `{"progress": f64}` frames, which are reported to
+    /// `on_progress` as they arrive, followed by a final `{"molecule": ...}` frame.
+    /// `{atom_count}` and `{formula}` in the arguments are replaced by the values of the input
+    /// molecule, `{input}` by the input file path in `TempFile` mode; any other text is passed
+    /// through literally.
+    #[tracing::instrument(skip(args, options, low, on_progress), fields(atoms = low.atoms.len()))]
+    fn run_plugin<T: DeserializeOwned>(
+        plugin: &str,
+        args: &[String],
+        options: &PluginOptions,
+        low: &Molecule,
+        on_progress: &mut dyn FnMut(f64),
+    ) -> Result<T, LMECoreError> {
+        let mut command = PLUGIN_DIRECTORY.clone();
+        command.push(plugin);
+        if !command.is_file() {
+            return Err(LMECoreError::PluginNotFound(format!(
+                "No plugin executable at {}, check the plugin name or LME_PLUGIN_DIRECTORY",
+                command.display()
+            )));
+        }
+        let cwd = options.working_directory()?;
+        let data_to_send = serde_json::to_string(low)
+            .map_err(|err| LMECoreError::PluginLayerError(-2, err.to_string()))?;
+        let temp_input = match options.input {
+            PluginInput::Stdin => None,
+            PluginInput::TempFile => Some(
+                TempInput::create(&data_to_send)
+                    .map_err(|err| LMECoreError::PluginLayerError(-3, err.to_string()))?,
+            ),
+        };
+        let mut placeholders = vec![
+            (
+                "{atom_count}",
+                low.atoms.values().flatten().count().to_string(),
+            ),
+            ("{formula}", low.formula()),
+        ];
+        if let Some(TempInput(path)) = &temp_input {
+            placeholders.push(("{input}", path.to_string_lossy().to_string()));
+        }
+        let args = args
+            .iter()
+            .map(|arg| {
+                placeholders
+                    .iter()
+                    .fold(arg.clone(), |arg, (key, value)| arg.replace(key, value))
+            })
+            .collect::<Vec<_>>();
+        let _permit = PLUGIN_PERMITS.acquire();
+        tracing::info!(command = %command.display(), ?args, "Spawning plugin");
+        let mut child = PluginProcess(
+            Command::new(&command)
+                .args(args)
+                .envs(&options.env)
+                .current_dir(cwd)
+                .stdin(if temp_input.is_some() {
+                    Stdio::null()
+                } else {
+                    Stdio::piped()
+                })
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|err| {
+                    LMECoreError::PluginLayerError(-1, format!("{}: {err}", command.display()))
+                })?,
+        );
+        if temp_input.is_none() {
+            let Some(mut stdin) = child.0.stdin.take() else {
+                return Err(LMECoreError::PluginLayerError(
+                    -6,
+                    "Unable to get stdin of child process".to_string(),
+                ));
+            };
+            stdin
+                .write_all(data_to_send.as_bytes())
+                .map_err(|err| LMECoreError::PluginLayerError(-3, err.to_string()))?;
+        }
+        let limit = options.max_output_bytes.unwrap_or(PLUGIN_OUTPUT_LIMIT);
+        let mut output = vec![];
+        let mut molecule_frame = None;
+        if let Some(stdout) = child.0.stdout.take() {
+            let mut reader = BufReader::new(stdout.take(limit as u64 + 1));
+            if options.output_gzip {
+                reader
+                    .read_to_end(&mut output)
+                    .map_err(|err| LMECoreError::PluginLayerError(-4, err.to_string()))?;
+            } else {
+                loop {
+                    let line_start = output.len();
+                    let read = reader
+                        .read_until(b'\n', &mut output)
+                        .map_err(|err| LMECoreError::PluginLayerError(-4, err.to_string()))?;
+                    if read == 0 {
+                        break;
+                    }
+                    match serde_json::from_slice(&output[line_start..]) {
+                        Ok(PluginFrame::Progress(progress)) => on_progress(progress),
+                        Ok(PluginFrame::Molecule(molecule)) => molecule_frame = Some(molecule),
+                        Err(_) => (),
+                    }
+                }
+            }
+        }
+        // Returning drops the guard, killing a plugin still writing past the limit.
+        if output.len() > limit {
+            return Err(LMECoreError::PluginLayerError(
+                -7,
+                format!("Plugin output exceeds {limit} bytes"),
+            ));
+        }
+        let status = child
+            .0
+            .wait()
+            .map_err(|err| LMECoreError::PluginLayerError(-4, err.to_string()))?;
+        if !status.success() {
+            tracing::warn!(%status, "Plugin failed");
+            return Err(LMECoreError::PluginLayerError(
+                -8,
+                format!("Plugin exited with {status}"),
+            ));
+        }
+        if let Some(molecule) = molecule_frame {
+            return Ok(molecule);
+        }
+        if options.output_gzip {
+            let mut decompressed = vec![];
+            GzDecoder::new(output.as_slice())
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|err| {
+                    LMECoreError::PluginLayerError(-10, format!("Invalid gzip output: {err}"))
+                })?;
+            if decompressed.len() > limit {
+                return Err(LMECoreError::PluginLayerError(
+                    -7,
+                    format!("Decompressed plugin output exceeds {limit} bytes"),
+                ));
+            }
+            output = decompressed;
+        }
+        let data = String::from_utf8(output).map_err(|err| {
+            LMECoreError::PluginLayerError(
+                -9,
+                format!("Plugin produced non-UTF8 output: {}", err.utf8_error()),
+            )
+        })?;
+        serde_json::from_str(&data)
+            .map_err(|err| LMECoreError::PluginLayerError(-5, err.to_string()))
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub struct Stack(Vec<Arc<Layer>>);
+
+    impl Stack {
+        pub fn new(layer: Vec<Arc<Layer>>) -> Self {
+            Self(layer)
+        }
+
+        pub fn get_layers(&self) -> &Vec<Arc<Layer>> {
+            &self.0
+        }
+
+        /// Layers `from..to`, counting from the bottom of the stack.
+        pub fn layers_between(&self, from: usize, to: usize) -> Result<Vec<Layer>, LMECoreError> {
+            self.0
+                .get(from..to)
+                .map(|layers| layers.iter().map(|layer| layer.as_ref().clone()).collect())
+                .ok_or_else(|| {
+                    LMECoreError::InvalidRequest(format!(
+                        "Invalid layer range {from}..{to} for a stack of {} layers",
+                        self.0.len()
+                    ))
+                })
+        }
+
+        pub fn get_base(&self) -> Self {
+            if let Some((_, layers)) = self.0.split_last() {
+                Self(layers.to_vec())
+            } else {
+                Self(vec![])
+            }
+        }
+
+        /// Maps the atom slots of the stack read on top of `base` to the index of the layer, from
+        /// the bottom, that last changed them, removals included. Slots left as in `base` are not
+        /// listed. Every layer output is compared with its input, so this costs a copy of the
+        /// molecule per layer and is only computed when asked for.
+        pub fn provenance(&self, base: &Molecule) -> Result<HashMap<usize, usize>, LMECoreError> {
+            let mut provenance = HashMap::new();
+            let mut current = base.clone();
+            for (depth, layer) in self.0.iter().enumerate() {
+                let next = layer.filter(current.clone())?;
+                for (idx, atom) in &next.atoms {
+                    if current.atoms.get(idx) != Some(atom) {
+                        provenance.insert(*idx, depth);
+                    }
+                }
+                current = next;
+            }
+            provenance.retain(|idx, _| current.atoms.contains_key(idx));
+            Ok(provenance)
+        }
+
+        /// The stack without its `depth` top layers, `None` past the bottom of the stack.
+        pub fn ancestor(&self, depth: usize) -> Option<Self> {
+            let len = self.0.len().checked_sub(depth)?;
+            Some(Self(self.0[..len].to_vec()))
+        }
+
+        pub fn add_layer(&mut self, layer: Arc<Layer>) {
+            self.0.push(layer)
+        }
+
+        /// Merges `w` into the top `Fill` layer in place, only copying it when another stack
+        /// shares it, or pushes a new `Fill` layer.
+        pub fn write(&mut self, w: Molecule) {
+            match self.0.last_mut() {
+                Some(top) if matches!(top.as_ref(), Layer::Fill(_)) => {
+                    if let Layer::Fill(current) = Arc::make_mut(top) {
+                        *current = Molecule::merge(mem::take(current), w);
+                    }
+                }
+                _ => self.add_layer(Arc::new(Layer::Fill(w))),
+            }
+        }
+
+        pub fn read(&self, mut container: Molecule) -> Result<Molecule, LMECoreError> {
+            for layer in &self.0 {
+                container = layer.filter(container)?
+            }
+            Ok(container)
+        }
+
+        /// Reads the stack on top of a borrowed base, which is only cloned when no `Clear` layer
+        /// discards it anyway. Layers below the last `Clear` are skipped.
+        pub fn read_from(&self, base: &Molecule) -> Result<Molecule, LMECoreError> {
+            let (container, start) = self.read_start(base);
+            self.0[start..]
+                .iter()
+                .try_fold(container, |container, layer| layer.filter(container))
+        }
+
+        /// Molecule a read starts from and index of the first layer applied to it: everything
+        /// below the last `Clear` is skipped.
+        pub(crate) fn read_start(&self, base: &Molecule) -> (Molecule, usize) {
+            match self
+                .0
+                .iter()
+                .rposition(|layer| matches!(layer.as_ref(), Layer::Clear))
+            {
+                Some(idx) => (Molecule::default(), idx + 1),
+                None => (base.clone(), 0),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Workspace {
+    base: Molecule,
+    stacks: Vec<Arc<Stack>>,
+    /// Stable id of every stack slot, parallel to `stacks`; ids survive removals shifting indexes.
+    stack_ids: Vec<Uuid>,
+    pub atom_names: HashMap<String, usize>,
+    pub groups: NtoN<String, usize>,
+    cell: Option<UnitCell>,
+    /// Named exports taken by `checkpoint`, oldest first.
+    checkpoints: Vec<(String, WorkspaceExport)>,
+    max_atoms: Option<usize>,
+}
+
+/// Taking a checkpoint past this many drops the oldest one.
+pub const MAX_CHECKPOINTS: usize = 16;
+
+const CELL_VOLUME_TOLERANCE: f64 = 1e-9;
+
+/// `top_writable` tells whether writes merge into the top layer instead of pushing a new `Fill`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct StackSummary {
+    pub depth: usize,
+    pub top_writable: bool,
+    pub atom_count: Option<usize>,
+}
+
+/// A stack taken out of the workspace so that reading it and computing a patch can happen
+/// without holding the workspace lock. See `Workspace::commit`.
+#[derive(Debug, Clone)]
+pub struct StackSnapshot {
+    index: usize,
+    stack: Arc<Stack>,
+    base: Molecule,
+}
+
+impl StackSnapshot {
+    pub fn read(&self) -> Result<Molecule, LMECoreError> {
+        self.stack.read_from(&self.base)
+    }
+}
+
+/// First layer of a stack failing to read, `layer` counting from the bottom of the stack.
+#[derive(Debug, Serialize)]
+pub struct LayerFailure {
+    pub stack: usize,
+    pub layer: usize,
+    pub error: LMECoreError,
+}
+
+/// Totals are summed per stack slot, so a stack shared by several slots through `clone_stack`
+/// counts once per slot; `unique_stacks` tells how many distinct stacks back the slots. Stacks
+/// failing to read are counted in `unreadable` and left out of the totals.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct WorkspaceStats {
+    pub stacks: usize,
+    pub unique_stacks: usize,
+    pub atoms: usize,
+    pub bonds: usize,
+    pub unreadable: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct WorkspaceExport {
+    base: Molecule,
+    stacks: Vec<StackTree>,
+    atom_names: HashMap<String, usize>,
+    groups: Grouped<String, usize>,
+    #[serde(default)]
+    cell: Option<UnitCell>,
+    #[serde(default)]
+    stack_ids: Vec<Uuid>,
+}
+
+impl Workspace {
+    pub fn new(base: Molecule) -> Self {
+        Self {
+            base,
+            stacks: vec![],
+            stack_ids: vec![],
+            atom_names: HashMap::new(),
+            groups: NtoN::new(),
+            cell: None,
+            checkpoints: vec![],
+            max_atoms: None,
+        }
+    }
+
+    /// Stores the current state under `name`, replacing a checkpoint of the same name. Checkpoints
+    /// are not part of exports.
+    pub fn checkpoint(&mut self, name: String) {
+        let export = WorkspaceExport::from(&*self);
+        self.checkpoints.retain(|(item, _)| *item != name);
+        if self.checkpoints.len() >= MAX_CHECKPOINTS {
+            self.checkpoints.remove(0);
+        }
+        self.checkpoints.push((name, export));
+    }
+
+    pub fn checkpoints(&self) -> Vec<&str> {
+        self.checkpoints
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Brings the workspace back to the checkpoint, keeping every checkpoint.
+    pub fn restore_checkpoint(&mut self, name: &str) -> Result<(), LMECoreError> {
+        let restored = self
+            .checkpoints
+            .iter()
+            .find(|(item, _)| item == name)
+            .map(|(_, export)| Workspace::from(export))
+            .ok_or(LMECoreError::NoSuchId)?;
+        *self = Workspace {
+            checkpoints: std::mem::take(&mut self.checkpoints),
+            max_atoms: self.max_atoms,
+            ..restored
+        };
+        Ok(())
+    }
+
+    pub fn max_atoms(&self) -> Option<usize> {
+        self.max_atoms
+    }
+
+    /// Limits the number of present atoms writes and commits may leave in a stack. Stacks already
+    /// past the limit stay readable; only edits growing them are refused.
+    pub fn set_max_atoms(&mut self, limit: Option<usize>) {
+        self.max_atoms = limit
+    }
+
+    /// Counts the present atoms of `current`, the stack as read by the caller, once `patch` is
+    /// written on it.
+    fn check_atom_limit(&self, current: &Molecule, patch: &Molecule) -> Result<(), LMECoreError> {
+        let Some(limit) = self.max_atoms else {
+            return Ok(());
+        };
+        let present = current.present_atoms().count();
+        let count = patch.atoms().iter().fold(present, |count, (idx, atom)| {
+            match (current.get_atom(*idx).is_some(), atom.is_some()) {
+                (false, true) => count + 1,
+                (true, false) => count - 1,
+                _ => count,
+            }
+        });
+        if count > limit.max(present) {
+            Err(LMECoreError::TooManyAtoms(limit))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn cell(&self) -> Option<&UnitCell> {
+        self.cell.as_ref()
+    }
+
+    /// Refuses cells whose lattice vectors are linearly dependent.
+    pub fn set_cell(&mut self, cell: Option<UnitCell>) -> Result<(), LMECoreError> {
+        if cell.is_some_and(|cell| cell.volume() < CELL_VOLUME_TOLERANCE) {
+            return Err(LMECoreError::InvalidTransform(
+                "The unit cell has no volume".to_string(),
+            ));
+        }
+        self.cell = cell;
+        Ok(())
+    }
+
+    /// Resolves the cell of the layers standing for the workspace cell, so the layer keeps
+    /// converting with the current cell even if the workspace cell changes later.
+    pub fn bind_cell(&self, layer: Layer) -> Result<Layer, LMECoreError> {
+        let cell = || self.cell.ok_or(LMECoreError::NoUnitCell).map(Some);
+        match layer {
+            Layer::FractionalToCartesian(None) => Ok(Layer::FractionalToCartesian(cell()?)),
+            Layer::CartesianToFractional(None) => Ok(Layer::CartesianToFractional(cell()?)),
+            Layer::WrapIntoCell(None) => Ok(Layer::WrapIntoCell(cell()?)),
+            layer => Ok(layer),
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(self), fields(atoms))]
+    pub fn read(&self, index: usize) -> Result<Molecule, LMECoreError> {
+        let molecule = self
+            .stacks
+            .get(index)
+            .map_or(Err(LMECoreError::NoSuchStack), |stack| {
+                stack.read_from(&self.base)
+            })?;
+        tracing::Span::current().record("atoms", molecule.atoms().len());
+        Ok(molecule)
+    }
+
+    /// Reads the given stacks in order, failing with `NoSuchStack` before reading anything if
+    /// any index is out of range.
+    pub fn read_many(&self, indexes: &[usize]) -> Result<Vec<Molecule>, LMECoreError> {
+        let stacks = indexes
+            .iter()
+            .map(|index| self.stacks.get(*index).ok_or(LMECoreError::NoSuchStack))
+            .collect::<Result<Vec<_>, _>>()?;
+        stacks
+            .par_iter()
+            .map(|stack| stack.read_from(&self.base))
+            .collect()
+    }
+
+    /// Reads the stack without its top layer, i.e. the molecule the top layer is applied to.
+    pub fn read_base(&self, index: usize) -> Result<Molecule, LMECoreError> {
+        self.stacks
+            .get(index)
+            .map_or(Err(LMECoreError::NoSuchStack), |stack| {
+                stack.get_base().read_from(&self.base)
+            })
+    }
+
+    pub fn stacks(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Summarizes every stack; `atom_count` is `None` when the stack fails to read.
+    pub fn stack_summaries(&self) -> Vec<StackSummary> {
+        self.stacks
+            .par_iter()
+            .map(|stack| StackSummary {
+                depth: stack.get_layers().len(),
+                top_writable: matches!(
+                    stack.get_layers().last().map(|layer| layer.as_ref()),
+                    Some(Layer::Fill(_))
+                ),
+                atom_count: stack
+                    .read_from(&self.base)
+                    .ok()
+                    .map(|molecule| molecule.present_atoms().count()),
+            })
+            .collect()
+    }
+
+    /// Runs a plugin answering with several molecules on a stack and creates one new stack per
+    /// molecule, each being the source stack topped with a `Fill` of that molecule.
+    pub fn create_stacks_from_plugin(
+        &mut self,
+        idx: usize,
+        plugin: &str,
+        args: &[String],
+        options: &PluginOptions,
+    ) -> Result<Range<usize>, LMECoreError> {
+        let source = self
+            .stacks
+            .get(idx)
+            .cloned()
+            .ok_or(LMECoreError::NoSuchStack)?;
+        let low = source.read_from(&self.base)?;
+        let molecules = entity::run_plugin_multi(plugin, args, options, &low)?;
+        Ok(self.append_stacks(molecules.into_iter().map(|molecule| {
+            let mut stack = source.as_ref().clone();
+            stack.add_layer(Arc::new(Layer::Fill(molecule)));
+            Arc::new(stack)
+        })))
+    }
+
+    pub fn snapshot(&self, idx: usize) -> Result<StackSnapshot, LMECoreError> {
+        let stack = self.stacks.get(idx).ok_or(LMECoreError::NoSuchStack)?;
+        Ok(StackSnapshot {
+            index: idx,
+            stack: stack.clone(),
+            base: self.base.clone(),
+        })
+    }
+
+    /// Writes a patch computed from `current`, the molecule read from `snapshot`, unless the
+    /// stack changed since the snapshot was taken, in which case nothing is written and
+    /// `StackConflict` is returned so the caller can recompute the patch on the current state.
+    /// Stacks are compared by identity, so a write to another slot sharing the same stack is not
+    /// a conflict, and neither is a removal shifting a clone of the stack into the same slot.
+    pub fn commit(
+        &mut self,
+        snapshot: &StackSnapshot,
+        current: &Molecule,
+        patch: Molecule,
+    ) -> Result<(), LMECoreError> {
+        let stack = self
+            .stacks
+            .get(snapshot.index)
+            .ok_or(LMECoreError::StackConflict)?;
+        if !Arc::ptr_eq(stack, &snapshot.stack) {
+            return Err(LMECoreError::StackConflict);
+        }
+        self.check_atom_limit(current, &patch)?;
+        let mut updated = stack.as_ref().clone();
+        updated.write(patch);
+        self.stacks[snapshot.index] = Arc::new(updated);
+        Ok(())
+    }
+
+    /// Appends one stack per frame, each holding the frame alone as `[Clear, Fill]` so the
+    /// workspace base does not show through.
+    pub fn create_stacks_from_frames(&mut self, frames: Vec<Molecule>) -> Range<usize> {
+        self.append_stacks(frames.into_iter().map(|frame| {
+            Arc::new(Stack::new(vec![
+                Arc::new(Layer::Clear),
+                Arc::new(Layer::Fill(frame)),
+            ]))
+        }))
+    }
+
+    /// Drops the top layer of a stack in place, reverting the edits it held. An empty stack has
+    /// nothing to revert past the workspace base.
+    pub fn discard_top_layer(&mut self, idx: usize) -> Result<(), LMECoreError> {
+        let stack = self.stacks.get_mut(idx).ok_or(LMECoreError::NoSuchStack)?;
+        if stack.get_layers().is_empty() {
+            return Err(LMECoreError::RootLayerError);
+        }
+        *stack = Arc::new(stack.get_base());
+        Ok(())
+    }
+
+    /// Swaps the molecule a stack starts from for `base`, keeping its layers: the stack becomes
+    /// `[Clear, Fill(base)]` followed by its layers, minus a leading `[Clear, Fill]` pair which
+    /// is the base being replaced. Layers keep addressing atoms by index, so `base` must number
+    /// its atoms as the old one did. The layers are replayed on `base`, and the stack is left
+    /// untouched if one fails, or with `NoSuchAtom` if a `Fill` layer deletes, bonds or groups
+    /// an atom missing from its input, as renumbering and removing layers leave it.
+    pub fn replace_base(&mut self, idx: usize, base: Molecule) -> Result<(), LMECoreError> {
+        let stack = self.stacks.get(idx).ok_or(LMECoreError::NoSuchStack)?;
+        let layers = match stack.get_layers().as_slice() {
+            [clear, fill, layers @ ..]
+                if matches!(clear.as_ref(), Layer::Clear)
+                    && matches!(fill.as_ref(), Layer::Fill(_)) =>
+            {
+                layers
+            }
+            layers => layers,
+        };
+        let mut current = base.clone();
+        for layer in layers {
+            if let Layer::Fill(patch) = layer.as_ref() {
+                let provided = |idx: &usize| {
+                    patch
+                        .atoms()
+                        .get(idx)
+                        .map_or(current.get_atom(*idx).is_some(), Option::is_some)
+                };
+                let mut removed = patch.atoms().iter().filter(|(_, atom)| atom.is_none());
+                let bonded = patch.bonds().keys().flat_map(|pair| {
+                    let (a, b) = (*pair).into();
+                    [a, b]
+                });
+                let grouped = patch.groups().data().iter().map(|(idx, _)| *idx);
+                if !removed.all(|(idx, _)| current.get_atom(*idx).is_some())
+                    || !bonded.chain(grouped).all(|idx| provided(&idx))
+                {
+                    return Err(LMECoreError::NoSuchAtom);
+                }
+            }
+            current = layer.filter(current)?;
+        }
+        let mut replaced = vec![Arc::new(Layer::Clear), Arc::new(Layer::Fill(base))];
+        replaced.extend(layers.iter().cloned());
+        self.stacks[idx] = Arc::new(Stack::new(replaced));
+        Ok(())
+    }
+
+    /// Reads the stacks in `start..start + range` as if `layer` had been pushed onto each of
+    /// them, without modifying the workspace.
+    #[tracing::instrument(skip(self, layer))]
+    pub fn preview_layer(
+        &self,
+        start: usize,
+        range: usize,
+        layer: &Layer,
+    ) -> Result<Vec<Molecule>, LMECoreError> {
+        start
+            .checked_add(range)
+            .and_then(|end| self.stacks.get(start..end))
+            .ok_or(LMECoreError::NoSuchStack)?
+            .par_iter()
+            .map(|stack| layer.filter(stack.read_from(&self.base)?))
+            .collect()
+    }
+
+    pub fn stats(&self) -> WorkspaceStats {
+        let (atoms, bonds, unreadable) = self
+            .stacks
+            .par_iter()
+            .map(|stack| match stack.read_from(&self.base) {
+                Ok(molecule) => (molecule.present_atoms().count(), molecule.bonds().len(), 0),
+                Err(_) => (0, 0, 1),
+            })
+            .reduce(|| (0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+        WorkspaceStats {
+            stacks: self.stacks.len(),
+            unique_stacks: self
+                .stacks
+                .iter()
+                .map(Arc::as_ptr)
+                .collect::<HashSet<_>>()
+                .len(),
+            atoms,
+            bonds,
+            unreadable,
+        }
+    }
+
+    /// Lists the layers of a stack from the bottom up with their depth, 0 being the first layer
+    /// above the workspace base.
+    pub fn stack_layers(&self, idx: usize) -> Result<Vec<(usize, Layer)>, LMECoreError> {
+        let stack = self.stacks.get(idx).ok_or(LMECoreError::NoSuchStack)?;
+        Ok(stack
+            .get_layers()
+            .iter()
+            .map(|layer| layer.as_ref().clone())
+            .enumerate()
+            .collect())
+    }
+
+    /// Like `stack_layers`, limited to the layers `from..to`.
+    pub fn layers_between(
+        &self,
+        idx: usize,
+        from: usize,
+        to: usize,
+    ) -> Result<Vec<(usize, Layer)>, LMECoreError> {
+        let stack = self.stacks.get(idx).ok_or(LMECoreError::NoSuchStack)?;
+        Ok((from..).zip(stack.layers_between(from, to)?).collect())
+    }
+
+    /// Reads every stack like `read` does and reports the layer each failing stack stops at, so
+    /// layers hidden below a `Clear` are not checked.
+    /// Stacks are computed on read rather than cached, so this is the whole recovery check: once
+    /// the cause is fixed, e.g. a plugin reinstalled, the stacks read correctly again.
+    pub fn check_stacks(&self) -> Vec<LayerFailure> {
+        let mut failures = self
+            .stacks
+            .par_iter()
+            .enumerate()
+            .filter_map(|(stack, layers)| {
+                let (mut molecule, start) = layers.read_start(&self.base);
+                for (layer, current) in layers.get_layers().iter().enumerate().skip(start) {
+                    match current.filter(molecule) {
+                        Ok(filtered) => molecule = filtered,
+                        Err(error) => {
+                            return Some(LayerFailure {
+                                stack,
+                                layer,
+                                error,
+                            })
+                        }
+                    }
+                }
+                None
+            })
+            .collect::<Vec<_>>();
+        failures.sort_by_key(|failure| failure.stack);
+        failures
+    }
+
+    /// Renumbers the present atoms of a stack densely in index order and replaces the stack with
+    /// `[Clear, Fill]` holding the result, so the layer history is flattened. Atom names and
+    /// groups of the workspace pointing at atoms written by a fill of another stack, or at atoms
+    /// of the base when another stack is not cleared, are left to the other stacks; these are
+    /// not read, which would run their plugins. The others follow the returned `old -> new` map,
+    /// or are dropped when their atom is absent from the stack too, since their index may now
+    /// be reused.
+    pub fn compact_stack(&mut self, idx: usize) -> Result<HashMap<usize, usize>, LMECoreError> {
+        let molecule = self.read(idx)?;
+        let referenced = self
+            .atom_names
+            .values()
+            .copied()
+            .chain(self.groups.get_rights())
+            .collect::<HashSet<_>>();
+        let others = self
+            .stacks
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other != idx)
+            .map(|(_, stack)| stack.get_layers())
+            .collect::<Vec<_>>();
+        let base_shared = others.iter().any(|layers| {
+            !layers
+                .iter()
+                .any(|layer| matches!(layer.as_ref(), Layer::Clear))
+        });
+        let written = others
+            .into_iter()
+            .flatten()
+            .filter_map(|layer| match layer.as_ref() {
+                Layer::Fill(fill) => Some(fill.atoms()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|(atom, present)| present.map(|_| *atom))
+            .collect::<HashSet<_>>();
+        let shared = referenced
+            .into_iter()
+            .filter(|atom| {
+                (base_shared && self.base.get_atom(*atom).is_some()) || written.contains(atom)
+            })
+            .collect::<HashSet<_>>();
+        let mut present = molecule
+            .present_atoms()
+            .map(|(idx, atom)| (idx, *atom))
+            .collect::<Vec<_>>();
+        present.sort_by_key(|(idx, _)| *idx);
+        let remap = present
+            .iter()
+            .enumerate()
+            .map(|(new, (old, _))| (*old, new))
+            .collect::<HashMap<_, _>>();
+        let atoms = present
+            .into_iter()
+            .map(|(old, atom)| (remap[&old], Some(atom)))
+            .collect();
+        let bonds = molecule
+            .bonds()
+            .iter()
+            .filter_map(|(pair, order)| {
+                let (a, b) = (*pair).into();
+                Some((Pair::new_ordered(*remap.get(&a)?, *remap.get(&b)?), *order))
+            })
+            .collect();
+        let groups = molecule
+            .groups()
+            .data()
+            .iter()
+            .filter_map(|(idx, group)| Some((*remap.get(idx)?, group.clone())))
+            .collect::<HashSet<_>>();
+        let compacted = Molecule::new(atoms, bonds, NtoN::from(groups));
+        self.stacks[idx] = Arc::new(Stack::new(vec![
+            Arc::new(Layer::Clear),
+            Arc::new(Layer::Fill(compacted)),
+        ]));
+        let follow = |atom: usize| {
+            if shared.contains(&atom) {
+                Some(atom)
+            } else {
+                remap.get(&atom).copied()
+            }
+        };
+        self.atom_names = self
+            .atom_names
+            .drain()
+            .filter_map(|(name, idx)| Some((name, follow(idx)?)))
+            .collect();
+        self.groups = NtoN::from(
+            self.groups
+                .clone()
+                .into_iter()
+                .filter_map(|(group, idx)| Some((group, follow(idx)?)))
+                .collect::<HashSet<_>>(),
+        );
+        Ok(remap)
+    }
+
+    fn append_stacks<I>(&mut self, stacks: I) -> Range<usize>
+    where
+        I: IntoIterator<Item = Arc<Stack>>,
+    {
+        let start = self.stacks.len();
+        for stack in stacks {
+            self.stacks.push(stack);
+            self.stack_ids.push(Uuid::new_v4());
+        }
+        start..self.stacks.len()
+    }
+
+    /// Appends `copies` copies of the stack, none for 0, and returns the range of their
+    /// indexes. `create_stack_from_layer`, `clone_stack` and `clone_base` count the same way.
+    pub fn create_stack(&mut self, stack: Arc<Stack>, copies: usize) -> Range<usize> {
+        self.append_stacks((0..copies).map(|_| stack.clone()))
+    }
+
+    pub fn stack_id(&self, idx: usize) -> Result<Uuid, LMECoreError> {
+        self.stack_ids
+            .get(idx)
+            .copied()
+            .ok_or(LMECoreError::NoSuchStack)
+    }
+
+    /// Current index of the stack with the given id.
+    pub fn find_stack(&self, id: &Uuid) -> Result<usize, LMECoreError> {
+        self.stack_ids
+            .iter()
+            .position(|item| item == id)
+            .ok_or(LMECoreError::NoSuchStack)
+    }
+
+    pub fn create_stack_from_layer(&mut self, layer: Arc<Layer>, copies: usize) -> Range<usize> {
+        let stack = Stack::new(vec![layer]);
+        self.create_stack(Arc::new(stack), copies)
+    }
+
+    pub fn clone_stack(&mut self, stack_idx: usize, copies: usize) -> Option<Range<usize>> {
+        let stack = self.stacks.get(stack_idx).cloned()?;
+
+        Some(self.create_stack(stack, copies))
+    }
+
+    pub fn clone_base(&mut self, stack_idx: usize, copies: usize) -> Option<Range<usize>> {
+        let stack = self.stacks.get(stack_idx)?;
+        let base = stack.get_base();
+        Some(self.create_stack(Arc::new(base), copies))
+    }
+
+    pub fn provenance(&self, idx: usize) -> Result<HashMap<usize, usize>, LMECoreError> {
+        self.stacks
+            .get(idx)
+            .ok_or(LMECoreError::NoSuchStack)?
+            .provenance(&self.base)
+    }
+
+    /// Like `clone_base`, peeling `depth` layers off the stack instead of one.
+    pub fn clone_ancestor(
+        &mut self,
+        stack_idx: usize,
+        depth: usize,
+        copies: usize,
+    ) -> Result<Range<usize>, LMECoreError> {
+        let stack = self
+            .stacks
+            .get(stack_idx)
+            .ok_or(LMECoreError::NoSuchStack)?;
+        let ancestor = stack.ancestor(depth).ok_or_else(|| {
+            LMECoreError::InvalidRequest(format!(
+                "Depth {depth} past the bottom of a stack of {} layers",
+                stack.get_layers().len()
+            ))
+        })?;
+        Ok(self.create_stack(Arc::new(ancestor), copies))
+    }
+
+    pub fn layer_tree(&self) -> Vec<(Vec<usize>, Vec<Layer>)> {
+        StackTree::flatten(&StackTree::dehydration(&self.stacks))
+    }
+
+    pub fn find_atom_name(&self, name: &str) -> Result<usize, LMECoreError> {
+        self.atom_names
+            .get(name)
+            .copied()
+            .ok_or(LMECoreError::NoSuchId)
+    }
+
+    /// Name and groups of each of the atoms, found in one pass over the names and the groups.
+    pub fn annotations_for(
+        &self,
+        atoms: &[usize],
+    ) -> HashMap<usize, (Option<String>, HashSet<String>)> {
+        let mut annotations = atoms
+            .iter()
+            .map(|idx| (*idx, (None, HashSet::new())))
+            .collect::<HashMap<_, _>>();
+        for (name, idx) in &self.atom_names {
+            if let Some((slot, _)) = annotations.get_mut(idx) {
+                *slot = Some(name.clone());
+            }
+        }
+        for (group, idx) in self.groups.data() {
+            if let Some((_, groups)) = annotations.get_mut(idx) {
+                groups.insert(group.clone());
+            }
+        }
+        annotations
+    }
+
+    /// Names atoms in index order, replacing any previous name of the atom. Names already taken
+    /// by another atom are skipped and returned as `index -> name` so the caller can report them.
+    pub fn set_atom_names(&mut self, names: HashMap<usize, String>) -> HashMap<usize, String> {
+        let mut names = names.into_iter().collect::<Vec<_>>();
+        names.sort_by_key(|(idx, _)| *idx);
+        let renamed = names.iter().map(|(idx, _)| *idx).collect::<HashSet<_>>();
+        let mut previous = HashMap::<usize, Vec<String>>::new();
+        for (name, owner) in &self.atom_names {
+            if renamed.contains(owner) {
+                previous.entry(*owner).or_default().push(name.clone());
+            }
+        }
+        let mut conflicts = HashMap::new();
+        for (idx, name) in names {
+            match self.atom_names.get(&name) {
+                Some(owner) if *owner != idx => {
+                    conflicts.insert(idx, name);
+                }
+                _ => {
+                    for old in previous.remove(&idx).into_iter().flatten() {
+                        self.atom_names.remove(&old);
+                    }
+                    self.atom_names.insert(name, idx);
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Removes `range` stacks starting from `start_idx`. Stacks above the removed range shift
+    /// down, so the returned map gives the new index of every stack whose index changed.
+    /// Stacks created afterwards by `create_stack`, `clone_stack` or `clone_base` are always
+    /// appended, so their returned range is never affected by earlier removals.
+    pub fn remove_stacks(
+        &mut self,
+        start_idx: usize,
+        range: usize,
+    ) -> Option<HashMap<usize, usize>> {
+        let end = start_idx
+            .checked_add(range)
+            .filter(|end| *end <= self.stacks.len())?;
+        self.stacks.drain(start_idx..end);
+        self.stack_ids.drain(start_idx..end);
+        Some(
+            (end..self.stacks.len() + range)
+                .map(|old| (old, old - range))
+                .collect(),
+        )
+    }
+
+    #[tracing::instrument(skip(self, data), fields(atoms = data.atoms().len()))]
+    pub fn write_to_stack(
+        &mut self,
+        start_idx: usize,
+        range: usize,
+        data: Molecule,
+    ) -> Result<bool, LMECoreError> {
+        if range == 0 || start_idx.saturating_add(range) > self.stacks.len() {
+            Ok(false)
+        } else {
+            // Only patches bringing atoms in can go over the limit. Stacks shared by several
+            // slots are read once, and stacks failing to read are refused.
+            if self.max_atoms.is_some() && data.atoms().values().any(Option::is_some) {
+                let mut seen = HashSet::new();
+                let stacks = self.stacks[start_idx..start_idx + range]
+                    .iter()
+                    .filter(|stack| seen.insert(Arc::as_ptr(stack)))
+                    .collect::<Vec<_>>();
+                stacks.par_iter().try_for_each(|stack| {
+                    self.check_atom_limit(&stack.read_from(&self.base)?, &data)
+                })?;
+            }
+            self.stacks[start_idx..start_idx + range]
+                .par_iter_mut()
+                .for_each(|stack| Arc::make_mut(stack).write(data.clone()));
+            Ok(true)
+        }
+    }
+
+    #[tracing::instrument(skip(self, layer))]
+    pub fn add_layer_to_stack(
+        &mut self,
+        start_idx: usize,
+        range: usize,
+        layer: Arc<Layer>,
+    ) -> bool {
+        if range == 0 || start_idx.saturating_add(range) > self.stacks.len() {
+            false
+        } else {
+            self.stacks[start_idx..start_idx + range]
+                .par_iter_mut()
+                .for_each(|stack| Arc::make_mut(stack).add_layer(layer.clone()));
+            true
+        }
+    }
+}
+
+impl From<&Workspace> for WorkspaceExport {
+    #[tracing::instrument(skip_all, fields(stacks = value.stacks.len()))]
+    fn from(value: &Workspace) -> Self {
+        Self {
+            base: value.base.clone(),
+            stacks: StackTree::dehydration(&value.stacks),
+            atom_names: value.atom_names.clone(),
+            groups: Grouped(value.groups.clone()),
+            cell: value.cell,
+            stack_ids: value.stack_ids.clone(),
+        }
+    }
+}
+
+impl From<&WorkspaceExport> for Workspace {
+    fn from(value: &WorkspaceExport) -> Self {
+        let stacks = StackTree::hydration(&value.stacks);
+        Self {
+            base: value.base.clone(),
+            // Exports without ids, or with ids not matching the stacks, get fresh ones.
+            stack_ids: if value.stack_ids.len() == stacks.len() {
+                value.stack_ids.clone()
+            } else {
+                stacks.iter().map(|_| Uuid::new_v4()).collect()
+            },
+            stacks,
+            atom_names: value.atom_names.clone(),
+            groups: value.groups.0.clone(),
+            cell: value.cell,
+            checkpoints: vec![],
+            max_atoms: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct StackTree {
+    /// `None` only for the root holding the empty stacks, which have no layer to hang from.
+    layer: Option<Layer>,
+    indexes: Vec<usize>,
+    children: Vec<StackTree>,
+}
+
+impl StackTree {
+    pub fn dehydration<'a, I>(stacks: I) -> Vec<StackTree>
+    where
+        I: IntoIterator<Item = &'a Arc<Stack>>,
+    {
+        let mut trees = vec![];
+        for (idx, stack) in stacks.into_iter().enumerate() {
+            let matched = trees
+                .iter_mut()
+                .any(|tree: &mut StackTree| tree.merge(idx, stack.get_layers()));
+            if !matched {
+                trees.push(StackTree::from((stack.get_layers().as_slice(), idx)))
+            }
+        }
+        trees
+    }
+
+    pub fn hydration<'a, I>(trees: I) -> Vec<Arc<Stack>>
+    where
+        I: IntoIterator<Item = &'a StackTree>,
+    {
+        let mut stacks: HashMap<usize, Arc<Stack>> = HashMap::new();
+
+        for tree in trees.into_iter() {
+            stacks.extend(tree.to_stacks(&[]));
+        }
+
+        let mut stacks = stacks.into_iter().collect::<Vec<_>>();
+        stacks.sort_by_key(|(idx, _)| *idx);
+        stacks.into_iter().map(|(_, stack)| stack).collect()
+    }
+
+    /// Lists every node holding stacks as the indexes of these stacks and the layers from the
+    /// root down to the node, in tree order.
+    pub fn flatten<'a, I>(trees: I) -> Vec<(Vec<usize>, Vec<Layer>)>
+    where
+        I: IntoIterator<Item = &'a StackTree>,
+    {
+        let mut flattened = vec![];
+        for tree in trees.into_iter() {
+            tree.collect_layers(&mut vec![], &mut flattened);
+        }
+        flattened
+    }
+
+    fn collect_layers(&self, base: &mut Vec<Layer>, output: &mut Vec<(Vec<usize>, Vec<Layer>)>) {
+        base.extend(self.layer.clone());
+        if !self.indexes.is_empty() {
+            output.push((self.indexes.clone(), base.clone()));
+        }
+        for child in &self.children {
+            child.collect_layers(base, output);
+        }
+        if self.layer.is_some() {
+            base.pop();
+        }
+    }
+
+    fn to_stacks(&self, base: &[Arc<Layer>]) -> HashMap<usize, Arc<Stack>> {
+        let mut map = HashMap::new();
+        let mut base = base.to_vec();
+        base.extend(self.layer.clone().map(Arc::new));
+        for index in &self.indexes {
+            map.insert(*index, Arc::new(Stack::new(base.clone())));
+        }
+        for child in &self.children {
+            map.extend(child.to_stacks(&base));
+        }
+        map
+    }
+
+    fn merge(&mut self, idx: usize, layers: &[Arc<Layer>]) -> bool {
+        let Some((current, elements)) = layers.split_first() else {
+            let matched = self.layer.is_none();
+            if matched {
+                self.indexes.push(idx);
+            }
+            return matched;
+        };
+        if self.layer.as_ref() == Some(current.as_ref()) {
+            if elements.is_empty() {
+                self.indexes.push(idx);
+            } else {
+                let matched = self
+                    .children
+                    .iter_mut()
+                    .any(|item| item.merge(idx, elements));
+                if !matched {
+                    self.children.push(StackTree::from((elements, idx)))
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl From<(&[Arc<Layer>], usize)> for StackTree {
+    fn from((stack, idx): (&[Arc<Layer>], usize)) -> Self {
+        let Some((bottom, highers)) = stack.split_first() else {
+            return Self {
+                layer: None,
+                indexes: vec![idx],
+                children: vec![],
+            };
+        };
+        if highers.is_empty() {
+            Self {
+                layer: Some(bottom.as_ref().clone()),
+                indexes: vec![idx],
+                children: vec![],
+            }
+        } else {
+            Self {
+                layer: Some(bottom.as_ref().clone()),
+                indexes: vec![],
+                children: vec![StackTree::from((highers, idx))],
+            }
+        }
+    }
+}
+
+mod test {
+    #[test]
+    fn remove_stacks_remap() {
+        use crate::{entity::Stack, Workspace};
+        use std::{collections::HashMap, sync::Arc};
+
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::default()), 5);
+        assert_eq!(workspace.stacks(), 5);
+        assert_eq!(
+            workspace.remove_stacks(1, 2),
+            Some(HashMap::from([(3, 1), (4, 2)]))
+        );
+        assert_eq!(workspace.stacks(), 3);
+        assert_eq!(workspace.remove_stacks(2, 2), None);
+        assert_eq!(workspace.remove_stacks(usize::MAX, 1), None);
+        assert_eq!(workspace.remove_stacks(2, 1), Some(HashMap::new()));
+        assert_eq!(workspace.create_stack(Arc::new(Stack::default()), 1), 2..3);
+        assert_eq!(workspace.clone_stack(0, 2), Some(3..5));
+        assert_eq!(workspace.clone_base(5, 1), None);
+    }
+
+    #[test]
+    fn copies_count_exactly() {
+        use crate::{
+            entity::{Layer, Stack},
+            Workspace,
+        };
+        use std::sync::Arc;
+
+        let mut workspace = Workspace::default();
+        assert_eq!(workspace.create_stack(Arc::new(Stack::default()), 0), 0..0);
+        assert_eq!(workspace.stacks(), 0);
+        assert_eq!(workspace.create_stack(Arc::new(Stack::default()), 1), 0..1);
+        assert_eq!(
+            workspace.create_stack_from_layer(Arc::new(Layer::Clear), 2),
+            1..3
+        );
+        assert_eq!(workspace.clone_stack(1, 3), Some(3..6));
+        assert_eq!(workspace.clone_base(1, 1), Some(6..7));
+        assert_eq!(workspace.clone_stack(0, 0), Some(7..7));
+        assert_eq!(workspace.stacks(), 7);
+    }
+
+    #[test]
+    fn stack_ids_follow_removals() {
+        use crate::{
+            entity::{Layer, Stack},
+            error::LMECoreError,
+            Workspace, WorkspaceExport,
+        };
+        use std::sync::Arc;
+
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::new(vec![Arc::new(Layer::Clear)])), 3);
+        workspace.clone_stack(0, 1);
+        let ids = (0..workspace.stacks())
+            .map(|idx| workspace.stack_id(idx).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids.iter().collect::<std::collections::HashSet<_>>().len(),
+            ids.len()
+        );
+        workspace.remove_stacks(1, 1);
+        assert!(matches!(
+            workspace.find_stack(&ids[1]),
+            Err(LMECoreError::NoSuchStack)
+        ));
+        assert_eq!(workspace.find_stack(&ids[3]).unwrap(), 2);
+        assert_eq!(workspace.stack_id(2).unwrap(), ids[3]);
+        let restored = Workspace::from(&WorkspaceExport::from(&workspace));
+        assert_eq!(restored.find_stack(&ids[3]).unwrap(), 2);
+    }
+
+    #[test]
+    fn find_atom_name() {
+        use crate::{error::LMECoreError, Workspace};
+        use std::collections::HashMap;
+
+        let mut workspace = Workspace::default();
+        workspace.set_atom_names(HashMap::from([(4, "CA".to_string())]));
+        assert_eq!(workspace.find_atom_name("CA").unwrap(), 4);
+        assert!(matches!(
+            workspace.find_atom_name("CB"),
+            Err(LMECoreError::NoSuchId)
+        ));
+    }
+
+    #[test]
+    fn set_atom_names_conflicts() {
+        use crate::Workspace;
+        use std::collections::HashMap;
+
+        let mut workspace = Workspace::default();
+        workspace.atom_names.insert("C1".to_string(), 0);
+        workspace.atom_names.insert("N1".to_string(), 5);
+        let conflicts = workspace.set_atom_names(HashMap::from([
+            (1, "C1".to_string()),
+            (2, "O1".to_string()),
+            (3, "O1".to_string()),
+            (4, "N1".to_string()),
+            (0, "C0".to_string()),
+        ]));
+        assert_eq!(
+            conflicts,
+            HashMap::from([(3, "O1".to_string()), (4, "N1".to_string())])
+        );
+        assert_eq!(
+            workspace.atom_names,
+            HashMap::from([
+                ("C0".to_string(), 0),
+                ("C1".to_string(), 1),
+                ("O1".to_string(), 2),
+                ("N1".to_string(), 5)
+            ])
+        );
+    }
+
+    #[test]
+    fn nearest_atoms_sorted() {
+        use crate::entity::{Atom, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let atoms = (0..10)
+            .map(|idx| (idx, Some(Atom::new(6, Point3::new(idx as f64, 0., 0.)))))
+            .chain([(10, None)])
+            .collect::<HashMap<_, _>>();
+        let molecule = Molecule::new(atoms, HashMap::new(), NtoN::new());
+        let origin = Point3::new(6.2, 0., 0.);
+        let nearest = molecule
+            .nearest_atoms(&origin, 3)
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        assert_eq!(nearest, vec![6, 7, 5]);
+        assert_eq!(molecule.nearest_atoms(&origin, 20).len(), 10);
+        assert!(molecule.nearest_atoms(&origin, 0).is_empty());
+    }
+
+    #[test]
+    fn plugin_env_propagated() {
+        use crate::entity::{Layer, Molecule, PluginOptions};
+        use std::collections::HashMap;
+
+        let layer = Layer::PluginFilter(
+            "echo_env.sh".to_string(),
+            vec![],
+            PluginOptions {
+                env: HashMap::from([("LME_ECHO".to_string(), "from-env".to_string())]),
+                ..Default::default()
+            },
+        );
+        let molecule = layer.filter(Molecule::default()).unwrap();
+        assert!(molecule.groups().get_left(&0).contains("from-env"));
+    }
+
+    #[test]
+    fn plugin_filter_without_options() {
+        use crate::entity::{Layer, PluginOptions};
+
+        let layer: Layer = serde_json::from_str(r#"{"PluginFilter": ["x.sh", ["-v"]]}"#).unwrap();
+        assert_eq!(
+            layer,
+            Layer::PluginFilter(
+                "x.sh".to_string(),
+                vec!["-v".to_string()],
+                PluginOptions::default()
+            )
+        );
+    }
+
+    #[test]
+    fn plugin_cwd() {
+        use crate::{
+            entity::{Layer, Molecule, PluginOptions},
+            error::LMECoreError,
+        };
+        use std::env;
+
+        let layer = |cwd: Option<&str>| {
+            Layer::PluginFilter(
+                "echo_cwd.sh".to_string(),
+                vec![],
+                PluginOptions {
+                    cwd: cwd.map(Into::into),
+                    ..Default::default()
+                },
+            )
+        };
+        let cwd_of = |cwd| {
+            let molecule = layer(cwd).filter(Molecule::default()).unwrap();
+            molecule.groups().get_left(&0).into_iter().next().unwrap()
+        };
+        let plugin_dir = env::current_dir().unwrap().join("plugins");
+        assert_eq!(cwd_of(None), plugin_dir.to_string_lossy());
+        assert_eq!(cwd_of(Some(".")), plugin_dir.to_string_lossy());
+        for outside in ["/", "..", "./../plugins"] {
+            assert!(matches!(
+                layer(Some(outside)).validate(),
+                Err(LMECoreError::InvalidRequest(_))
+            ));
+            assert!(matches!(
+                layer(Some(outside)).filter(Molecule::default()),
+                Err(LMECoreError::InvalidRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn plugin_temp_file_input() {
+        use crate::entity::{Atom, Layer, Molecule, PluginInput, PluginOptions};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, env, fs, process};
+
+        let molecule = Molecule::new(
+            HashMap::from([(3, Some(Atom::new(8, Point3::new(1., 2., 3.))))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let layer = Layer::PluginFilter(
+            "read_input.sh".to_string(),
+            vec!["{input}".to_string()],
+            PluginOptions {
+                input: PluginInput::TempFile,
+                ..Default::default()
+            },
+        );
+        assert_eq!(layer.filter(molecule.clone()).unwrap(), molecule);
+        let prefix = format!("lme-plugin-{}-", process::id());
+        assert!(!fs::read_dir(env::temp_dir()).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&prefix)));
+    }
+
+    #[test]
+    fn plugin_multi_creates_stacks() {
+        use crate::{
+            entity::{Atom, Molecule, PluginOptions, Stack},
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let base = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::origin()))),
+                (1, Some(Atom::new(8, Point3::origin()))),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base);
+        workspace.create_stack(Arc::new(Stack::new(vec![])), 1);
+        let source = workspace.stacks();
+        let range = workspace
+            .create_stacks_from_plugin(0, "conformers.sh", &[], &PluginOptions::default())
+            .unwrap();
+        assert_eq!(range, source..source + 2);
+        for (idx, x) in range.zip([1., 2.]) {
+            let molecule = workspace.read(idx).unwrap();
+            assert_eq!(molecule.get_atom(0).unwrap().position().x, x);
+            assert_eq!(molecule.get_atom(1).unwrap().element(), 8);
+        }
+    }
+
+    #[test]
+    fn plugin_crash_reported() {
+        use crate::{
+            entity::{Layer, Molecule, PluginOptions},
+            error::LMECoreError,
+        };
+
+        let run = |status: &str, output: &str| {
+            Layer::PluginFilter(
+                "crash.sh".to_string(),
+                vec![status.to_string(), output.to_string()],
+                PluginOptions::default(),
+            )
+            .filter(Molecule::default())
+        };
+        assert!(matches!(
+            run("3", "{}"),
+            Err(LMECoreError::PluginLayerError(-8, message)) if message.contains('3')
+        ));
+        assert!(matches!(
+            run("0", "\\377\\376"),
+            Err(LMECoreError::PluginLayerError(-9, _))
+        ));
+        assert!(matches!(
+            run("0", "{"),
+            Err(LMECoreError::PluginLayerError(-5, _))
+        ));
+    }
+
+    #[test]
+    fn plugin_args_templating() {
+        use crate::entity::{Atom, Layer, Molecule, PluginOptions};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let atom = |element| Some(Atom::new(element, Point3::origin()));
+        let ethanol = Molecule::new(
+            [6, 6, 8, 1, 1, 1, 1, 1, 1]
+                .into_iter()
+                .enumerate()
+                .map(|(idx, element)| (idx, atom(element)))
+                .chain([(9, None)])
+                .collect(),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        assert_eq!(ethanol.formula(), "C2H6O");
+        let layer = Layer::PluginFilter(
+            "echo_args.sh".to_string(),
+            vec![
+                "--atoms={atom_count}".to_string(),
+                "{formula}".to_string(),
+                "{charge}".to_string(),
+            ],
+            PluginOptions::default(),
+        );
+        let echoed = layer.filter(ethanol).unwrap();
+        assert_eq!(
+            echoed.groups().get_left(&0).into_iter().next().unwrap(),
+            "--atoms=9 C2H6O {charge}"
+        );
+    }
+
+    #[test]
+    fn plugin_permits_bound_concurrency() {
+        use crate::entity::Permits;
+        use std::{
+            sync::atomic::{AtomicUsize, Ordering},
+            thread,
+            time::Duration,
+        };
+
+        let permits = Permits::new(2);
+        let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let _permit = permits.acquire();
+                    let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(current, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn plugin_output_limit() {
+        use crate::{
+            entity::{Layer, Molecule, PluginOptions},
+            error::LMECoreError,
+        };
+        use std::{collections::HashMap, env, fs, path::Path, process};
+
+        let pid_file = env::temp_dir().join(format!("lme-flood-{}.pid", process::id()));
+        let layer = Layer::PluginFilter(
+            "flood.sh".to_string(),
+            vec![],
+            PluginOptions {
+                max_output_bytes: Some(1024),
+                env: HashMap::from([(
+                    "LME_PID_FILE".to_string(),
+                    pid_file.to_string_lossy().to_string(),
+                )]),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            layer.filter(Molecule::default()),
+            Err(LMECoreError::PluginLayerError(-7, _))
+        ));
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        fs::remove_file(&pid_file).unwrap();
+        assert!(!Path::new("/proc").join(pid.trim()).exists());
+    }
+
+    #[test]
+    fn plugin_gzip_output() {
+        use crate::{
+            entity::{Layer, Molecule, PluginOptions},
+            error::LMECoreError,
+        };
+
+        let layer = |output_gzip| {
+            Layer::PluginFilter(
+                "gzip.sh".to_string(),
+                vec![],
+                PluginOptions {
+                    output_gzip,
+                    ..Default::default()
+                },
+            )
+        };
+        use rayon::prelude::*;
+
+        let molecule = layer(true).filter(Molecule::default()).unwrap();
+        assert_eq!(molecule.present_atoms().count(), 1);
+        assert!(matches!(
+            layer(false).filter(Molecule::default()),
+            Err(LMECoreError::PluginLayerError(-9, _))
+        ));
+    }
+
+    #[test]
+    fn molecule_subset() {
+        use crate::entity::{Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{HashMap, HashSet};
+
+        let atom = Atom::new(6, Point3::origin());
+        let molecule = Molecule::new(
+            HashMap::from([(0, Some(atom)), (1, Some(atom)), (2, Some(atom)), (3, None)]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Single),
+                (Pair::new_ordered(1, 2), BondKind::Double),
+            ]),
+            NtoN::from(HashSet::from([(0, "a".to_string()), (2, "a".to_string())])),
+        );
+        let subset = molecule.subset(&[0, 1, 3, 4]);
+        assert_eq!(
+            subset,
+            Molecule::new(
+                HashMap::from([(0, Some(atom)), (1, Some(atom))]),
+                HashMap::from([(Pair::new_ordered(0, 1), BondKind::Single)]),
+                NtoN::from(HashSet::from([(0, "a".to_string())])),
+            )
+        );
+    }
+
+    #[test]
+    fn approx_eq_molecules() {
+        use crate::entity::{molecules_approx_eq, Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let molecule = |x: f64, order: f64| {
+            Molecule::new(
+                HashMap::from([
+                    (0, Some(Atom::new(6, Point3::new(x, 0., 0.)))),
+                    (1, Some(Atom::new(8, Point3::origin()))),
+                    (2, None),
+                ]),
+                HashMap::from([(Pair::new_ordered(0, 1), BondKind::from(order))]),
+                NtoN::new(),
+            )
+        };
+        let origin = molecule(1., 2.);
+        assert!(molecules_approx_eq(
+            &origin,
+            &molecule(1. + 1e-9, 2.),
+            1e-6,
+            0.
+        ));
+        assert!(molecules_approx_eq(&origin, &molecule(1., 2.05), 0., 0.1));
+        assert!(!molecules_approx_eq(&origin, &molecule(1.1, 2.), 1e-6, 0.));
+        assert!(!molecules_approx_eq(&origin, &molecule(1., 1.), 1e-6, 0.1));
+        assert!(!molecules_approx_eq(&origin, &Molecule::default(), 1., 1.));
+    }
+
+    #[test]
+    fn hide_element_drops_bonds() {
+        use crate::entity::{Atom, BondKind, Layer, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        // Sodium formate: Na-O(-C=O)
+        let atom = |element| Some(Atom::new(element, Point3::origin()));
+        let salt = Molecule::new(
+            HashMap::from([(0, atom(11)), (1, atom(8)), (2, atom(6)), (3, atom(8))]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Single),
+                (Pair::new_ordered(1, 2), BondKind::Single),
+                (Pair::new_ordered(2, 3), BondKind::Double),
+            ]),
+            NtoN::new(),
+        );
+        let hidden = Layer::HideElement(11).filter(salt).unwrap();
+        assert_eq!(hidden.atoms()[&0], None);
+        assert_eq!(
+            hidden.bonds(),
+            &HashMap::from([
+                (Pair::new_ordered(1, 2), BondKind::Single),
+                (Pair::new_ordered(2, 3), BondKind::Double)
+            ])
+        );
+    }
+
+    #[test]
+    fn hide_nonpolar_hydrogens() {
+        use crate::{
+            entity::{Atom, BondKind, Layer, Molecule},
+            periodic,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        // Methanol: H3C-OH, plus a carbon radical-like CH with a missing bond.
+        let atom = |element| Some(Atom::new(element, Point3::origin()));
+        let elements = [6, 8, 1, 1, 1, 1, 6, 1];
+        let bonds = [(0, 1), (0, 2), (0, 3), (0, 4), (1, 5), (6, 7)];
+        let methanol = Molecule::new(
+            elements
+                .iter()
+                .enumerate()
+                .map(|(idx, element)| (idx, atom(*element)))
+                .collect(),
+            bonds
+                .iter()
+                .map(|(a, b)| (Pair::new_ordered(*a, *b), BondKind::Single))
+                .collect(),
+            NtoN::new(),
+        );
+        let carbon = HashMap::from([(6, periodic::valence(6).unwrap())]);
+        let hidden = Layer::HideHydrogens(carbon).filter(methanol).unwrap();
+        let present = |idx| hidden.get_atom(idx).is_some();
+        assert!(!present(2) && !present(3) && !present(4));
+        assert!(present(5) && present(7));
+        assert_eq!(hidden.degree(0), 1);
+    }
+
+    #[test]
+    fn translate_atom_to_point() {
+        use crate::{
+            entity::{Atom, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::{Point3, Vector3};
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::new(1., 1., 1.)))),
+                (1, Some(Atom::new(8, Point3::new(2., 1., 1.)))),
+                (2, None),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let offset = molecule.offset_to(1, &Point3::origin()).unwrap();
+        assert_eq!(offset, Vector3::new(-2., -1., -1.));
+        let patch = molecule.translate_atoms(&[0, 1], &offset).unwrap();
+        assert_eq!(patch.get_atom(1).unwrap().position(), &Point3::origin());
+        assert_eq!(
+            patch.get_atom(0).unwrap().position(),
+            &Point3::new(-1., 0., 0.)
+        );
+        assert!(matches!(
+            molecule.offset_to(2, &Point3::origin()),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+    }
+
+    #[test]
+    fn rotate_about_bond() {
+        use crate::{
+            entity::{Atom, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::new(0., 0., 0.)))),
+                (1, Some(Atom::new(6, Point3::new(0., 0., 1.)))),
+                (2, Some(Atom::new(1, Point3::new(1., 0., 1.)))),
+                (3, None),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let patch = molecule.rotate_about_bond((0, 1), &[2], 90.).unwrap();
+        assert_eq!(patch.atoms().len(), 1);
+        let position = patch.get_atom(2).unwrap().position();
+        assert!((position - Point3::new(0., 1., 1.)).norm() < 1e-9);
+        assert!(matches!(
+            molecule.rotate_about_bond((0, 1), &[3], 90.),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+        assert!(matches!(
+            molecule.rotate_about_bond((0, 4), &[2], 90.),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+    }
+
+    #[test]
+    fn compact_stack_remaps() {
+        use crate::{
+            entity::{Atom, BondKind, Layer, Molecule},
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::{
+            collections::{HashMap, HashSet},
+            sync::Arc,
+        };
+
+        let atom = Some(Atom::new(6, Point3::origin()));
+        let base = Molecule::new(
+            HashMap::from([(0, atom), (2, atom), (5, atom), (7, atom)]),
+            HashMap::from([
+                (Pair::new_ordered(2, 5), BondKind::Double),
+                (Pair::new_ordered(0, 7), BondKind::Single),
+            ]),
+            NtoN::from(HashSet::from([(5, "ring".to_string())])),
+        );
+        let mut workspace = Workspace::new(base);
+        workspace.create_stack_from_layer(Arc::new(Layer::RemoveElement(0)), 1);
+        workspace
+            .write_to_stack(
+                0,
+                1,
+                Molecule::new(HashMap::from([(0, None)]), HashMap::new(), NtoN::new()),
+            )
+            .unwrap();
+        workspace.atom_names.insert("a".to_string(), 5);
+        workspace.atom_names.insert("b".to_string(), 0);
+        workspace.groups.insert("g".to_string(), 7);
+
+        let remap = workspace.compact_stack(0).unwrap();
+        assert_eq!(remap, HashMap::from([(2, 0), (5, 1), (7, 2)]));
+        let compacted = workspace.read(0).unwrap();
+        assert_eq!(
+            compacted,
+            Molecule::new(
+                HashMap::from([(0, atom), (1, atom), (2, atom)]),
+                HashMap::from([(Pair::new_ordered(0, 1), BondKind::Double)]),
+                NtoN::from(HashSet::from([(1, "ring".to_string())])),
+            )
+        );
+        assert_eq!(workspace.atom_names, HashMap::from([("a".to_string(), 1)]));
+        assert_eq!(
+            workspace.groups.get_left(&"g".to_string()),
+            HashSet::from([2])
+        );
+    }
+
+    #[test]
+    fn compact_stack_keeps_other_stacks() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{
+            collections::{HashMap, HashSet},
+            sync::Arc,
+        };
+
+        let atom = Some(Atom::new(6, Point3::origin()));
+        let base = Molecule::new(
+            HashMap::from([(0, atom), (1, atom), (2, atom), (3, atom)]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base);
+        workspace.create_stack(Arc::new(Stack::default()), 2);
+        let patch = Molecule::new(
+            HashMap::from([(1, None), (9, atom)]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        workspace.write_to_stack(0, 1, patch).unwrap();
+        workspace.atom_names.insert("shared".to_string(), 3);
+        workspace.atom_names.insert("second".to_string(), 1);
+        workspace.atom_names.insert("first".to_string(), 9);
+        workspace.groups.insert("g".to_string(), 1);
+        workspace.groups.insert("g".to_string(), 2);
+        let missing = Layer::PluginFilter("missing.sh".to_string(), vec![], Default::default());
+        workspace.create_stack_from_layer(Arc::new(missing), 1);
+
+        let remap = workspace.compact_stack(0).unwrap();
+        assert_eq!(remap, HashMap::from([(0, 0), (2, 1), (3, 2), (9, 3)]));
+        assert_eq!(
+            workspace.atom_names,
+            HashMap::from([
+                ("shared".to_string(), 3),
+                ("second".to_string(), 1),
+                ("first".to_string(), 3),
+            ])
+        );
+        assert_eq!(
+            workspace.groups.get_left(&"g".to_string()),
+            HashSet::from([1, 2])
+        );
+        let second = workspace.read(1).unwrap();
+        assert!((0..4).all(|idx| second.get_atom(idx).is_some()));
+    }
+
+    #[test]
+    fn plugin_progress_frames() {
+        use crate::entity::{Layer, Molecule, PluginOptions};
+
+        let layer =
+            Layer::PluginFilter("progress.sh".to_string(), vec![], PluginOptions::default());
+        let mut progress = vec![];
+        let molecule = layer
+            .filter_with_progress(Molecule::default(), &mut |value| progress.push(value))
+            .unwrap();
+        assert_eq!(progress, vec![0.25, 0.5, 1.]);
+        assert!(molecule.groups().get_left(&0).contains("done"));
+    }
+
+    #[test]
+    fn composition_report() {
+        use crate::{
+            entity::{Atom, BondKind, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{BTreeMap, HashMap};
+
+        let atom = |element| Some(Atom::new(element, Point3::origin()));
+        // Water, a lone argon and a shadowed atom.
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, atom(8)),
+                (1, atom(1)),
+                (2, atom(1)),
+                (3, atom(18)),
+                (4, None),
+            ]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Single),
+                (Pair::new_ordered(0, 2), BondKind::Single),
+                (Pair::new_ordered(3, 4), BondKind::Single),
+            ]),
+            NtoN::new(),
+        );
+        assert_eq!(molecule.fragments(), vec![vec![0, 1, 2], vec![3]]);
+        assert_eq!(molecule.fragment_of(2).unwrap(), vec![0, 1, 2]);
+        assert_eq!(molecule.fragment_of(3).unwrap(), vec![3]);
+        assert!(matches!(
+            molecule.fragment_of(4),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+        let report = molecule.composition();
+        assert_eq!(report.elements, BTreeMap::from([(1, 2), (8, 1), (18, 1)]));
+        assert_eq!(report.atoms, 4);
+        assert_eq!(report.fragments, 2);
+    }
+
+    #[test]
+    fn repeat_unit() {
+        use crate::{
+            entity::{Atom, BondKind, Molecule, MAX_REPEAT_ATOMS},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::{Point3, Transform3, Translation3};
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::new(0., 0., 0.)))),
+                (1, Some(Atom::new(8, Point3::new(1., 0., 0.)))),
+                (5, None),
+            ]),
+            HashMap::from([(Pair::new_ordered(0, 1), BondKind::Double)]),
+            NtoN::new(),
+        );
+        let step =
+            Transform3::from_matrix_unchecked(Translation3::new(0., 0., 1.5).to_homogeneous());
+        let (patch, copies) = molecule.repeat(&[1, 0], &step, 2, None).unwrap();
+        assert_eq!(copies, vec![vec![6, 7], vec![8, 9]]);
+        assert_eq!(
+            patch.get_atom(8).unwrap().position(),
+            &Point3::new(1., 0., 3.)
+        );
+        assert_eq!(patch.get_atom(9).unwrap().element(), 6);
+        assert_eq!(
+            patch.bonds(),
+            &HashMap::from([
+                (Pair::new_ordered(6, 7), BondKind::Double),
+                (Pair::new_ordered(8, 9), BondKind::Double)
+            ])
+        );
+        assert!(molecule.repeat(&[5], &step, 1, None).is_err());
+        assert!(matches!(
+            molecule.repeat(&[1, 0], &step, 3, Some(5)),
+            Err(LMECoreError::TooManyAtoms(5))
+        ));
+        assert!(matches!(
+            molecule.repeat(&[1, 0], &step, usize::MAX, None),
+            Err(LMECoreError::TooManyAtoms(MAX_REPEAT_ATOMS))
+        ));
+    }
+
+    #[test]
+    fn missing_plugin() {
+        use crate::{
+            entity::{Layer, Molecule, PluginOptions},
+            error::LMECoreError,
+        };
+
+        let layer = Layer::PluginFilter("missing.sh".to_string(), vec![], PluginOptions::default());
+        match layer.filter(Molecule::default()) {
+            Err(LMECoreError::PluginNotFound(message)) => {
+                assert!(message.contains("plugins/missing.sh"))
+            }
+            _ => panic!("Missing plugin should be reported"),
+        }
+    }
+
+    #[test]
+    fn stack_summaries() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, PluginOptions, Stack},
+            StackSummary, Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atom = Some(Atom::new(6, Point3::origin()));
+        let base = Molecule::new(
+            HashMap::from([(0, atom), (1, None)]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base);
+        workspace.create_stack(Arc::new(Stack::default()), 1);
+        workspace.create_stack_from_layer(Arc::new(Layer::IgnoreBonds), 1);
+        workspace
+            .write_to_stack(
+                1,
+                1,
+                Molecule::new(HashMap::from([(2, atom)]), HashMap::new(), NtoN::new()),
+            )
+            .unwrap();
+        let plugin =
+            Layer::PluginFilter("missing.sh".to_string(), vec![], PluginOptions::default());
+        workspace.create_stack_from_layer(Arc::new(plugin), 1);
+        assert_eq!(
+            workspace.stack_summaries(),
+            vec![
+                StackSummary {
+                    depth: 0,
+                    top_writable: false,
+                    atom_count: Some(1),
+                },
+                StackSummary {
+                    depth: 2,
+                    top_writable: true,
+                    atom_count: Some(2),
+                },
+                StackSummary {
+                    depth: 1,
+                    top_writable: false,
+                    atom_count: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bond_kind_from_numeric_order() {
+        use crate::entity::BondKind;
+
+        let kinds: Vec<BondKind> =
+            serde_json::from_str(r#"[1.0, 2, 3.0, 1.5, "Aromatic", "Dative", {"Custom": 0.5}]"#)
+                .unwrap();
+        assert_eq!(
+            kinds,
+            vec![
+                BondKind::Single,
+                BondKind::Double,
+                BondKind::Triple,
+                BondKind::Custom(1.5),
+                BondKind::Aromatic,
+                BondKind::Dative,
+                BondKind::Custom(0.5),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&BondKind::Double).unwrap(),
+            r#""Double""#
+        );
+        assert_eq!(BondKind::Aromatic.order(), 1.5);
+    }
+
+    #[test]
+    fn import_structure_at_offset() {
+        use crate::{
+            entity::{Atom, BondKind, CompactedMolecule, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{HashMap, HashSet};
+
+        let atom = Atom::new(6, Point3::origin());
+        let molecule = Molecule::new(
+            HashMap::from([(0, Some(atom)), (1, Some(atom)), (2, None)]),
+            HashMap::new(),
+            NtoN::from(HashSet::from([(1, "ligand".to_string())])),
+        );
+        let structure = |groups: &[(usize, &str)]| {
+            CompactedMolecule::new(
+                vec![atom.set_element(8), atom.set_element(1)],
+                HashMap::from([(Pair::new_ordered(0, 1), BondKind::Single)]),
+                NtoN::from(
+                    groups
+                        .iter()
+                        .map(|(idx, group)| (*idx, group.to_string()))
+                        .collect::<HashSet<_>>(),
+                ),
+            )
+        };
+
+        let (patch, range) = molecule.import_structure(structure(&[]), None).unwrap();
+        assert_eq!(range, 3..5);
+        assert_eq!(patch.get_atom(3).unwrap().element(), 8);
+        assert!(patch.bonds().contains_key(&Pair::new_ordered(3, 4)));
+
+        let (patch, range) = molecule
+            .import_structure(structure(&[(0, "ligand")]), Some(1))
+            .unwrap();
+        assert_eq!(range, 1..3);
+        assert_eq!(patch.get_atom(1).unwrap().element(), 8);
+
+        assert!(matches!(
+            molecule.import_structure(structure(&[]), Some(1)),
+            Err(LMECoreError::AtomSlotOccupied(1))
+        ));
+        assert!(matches!(
+            molecule.import_structure(structure(&[(0, "ligand")]), Some(0)),
+            Err(LMECoreError::AtomSlotOccupied(0))
+        ));
+    }
+
+    #[test]
+    fn preview_layer_leaves_stacks() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let base = Molecule::new(
+            HashMap::from([(0, Some(Atom::new(6, Point3::origin())))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base);
+        workspace.create_stack(Arc::new(Stack::new(vec![])), 2);
+        let before = workspace.clone();
+        let previews = workspace
+            .preview_layer(0, 2, &Layer::ReplaceElement(6, 7))
+            .unwrap();
+        assert_eq!(previews.len(), 2);
+        assert!(previews
+            .iter()
+            .all(|molecule| molecule.get_atom(0).unwrap().element() == 7));
+        assert_eq!(workspace, before);
+        assert!(matches!(
+            workspace.preview_layer(1, 5, &Layer::Clear),
+            Err(LMECoreError::NoSuchStack)
+        ));
+        assert!(matches!(
+            workspace.preview_layer(usize::MAX, 1, &Layer::Clear),
+            Err(LMECoreError::NoSuchStack)
+        ));
+    }
+
+    #[test]
+    fn neighbors_and_degree() {
+        use crate::entity::{Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let atom = Some(Atom::new(6, Point3::origin()));
+        let molecule = Molecule::new(
+            HashMap::from([(0, atom), (1, atom), (2, atom), (3, None)]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Double),
+                (Pair::new_ordered(2, 0), BondKind::Single),
+                (Pair::new_ordered(0, 3), BondKind::Single),
+                (Pair::new_ordered(1, 2), BondKind::Single),
+            ]),
+            NtoN::new(),
+        );
+        let mut neighbors = molecule.neighbors(0).collect::<Vec<_>>();
+        neighbors.sort_by_key(|(idx, _)| *idx);
+        assert_eq!(
+            neighbors,
+            vec![(1, &BondKind::Double), (2, &BondKind::Single)]
+        );
+        assert_eq!(molecule.degree(0), 2);
+        assert_eq!(molecule.degree(3), 0);
+        assert_eq!(molecule.degree(7), 0);
+        assert_eq!(molecule.get_bond(1, 0), Some(&BondKind::Double));
+        assert_eq!(molecule.get_bond(0, 1), Some(&BondKind::Double));
+        assert_eq!(molecule.get_bond(0, 0), None);
+    }
+
+    #[test]
+    fn read_many_keeps_order() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let base = Molecule::new(
+            (1..=4)
+                .map(|element| (element, Some(Atom::new(element, Point3::origin()))))
+                .collect(),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base);
+        for element in 1..=4 {
+            workspace.create_stack_from_layer(Arc::new(Layer::HideElement(element)), 1);
+        }
+        workspace.create_stack(Arc::new(Stack::new(vec![Arc::new(Layer::Clear)])), 1);
+        let indexes = [workspace.stacks() - 1, 0, 2];
+        let molecules = workspace.read_many(&indexes).unwrap();
+        let expected = indexes
+            .iter()
+            .map(|index| workspace.read(*index).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(molecules, expected);
+        assert_ne!(molecules[1], molecules[2]);
+        assert!(matches!(
+            workspace.read_many(&[0, workspace.stacks()]),
+            Err(LMECoreError::NoSuchStack)
+        ));
+    }
+
+    #[test]
+    fn replace_stack_base() {
+        use crate::{
+            entity::{ArithExpr, Atom, BondKind, Layer, Molecule, ScalarTarget, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atoms = |elements: &[usize]| {
+            elements
+                .iter()
+                .enumerate()
+                .map(|(idx, element)| (idx, Some(Atom::new(*element, Point3::origin()))))
+                .collect::<HashMap<_, _>>()
+        };
+        let bond = Molecule::new(
+            HashMap::new(),
+            HashMap::from([(Pair::new_ordered(0, 1), BondKind::Single)]),
+            NtoN::new(),
+        );
+        let mut workspace =
+            Workspace::new(Molecule::new(atoms(&[6, 6]), HashMap::new(), NtoN::new()));
+        workspace.create_stack(
+            Arc::new(Stack::new(vec![
+                Arc::new(Layer::Fill(bond)),
+                Arc::new(Layer::ReplaceElement(6, 7)),
+            ])),
+            1,
+        );
+        let fixed = Molecule::new(atoms(&[6, 8]), HashMap::new(), NtoN::new());
+        workspace.replace_base(0, fixed.clone()).unwrap();
+        let molecule = workspace.read(0).unwrap();
+        assert_eq!(molecule.atoms()[&0].unwrap().element(), 7);
+        assert_eq!(molecule.atoms()[&1].unwrap().element(), 8);
+        assert_eq!(molecule.bonds().len(), 1);
+        // Replacing again swaps the previous replacement instead of stacking on it.
+        workspace.replace_base(0, fixed).unwrap();
+        assert_eq!(workspace.stack_layers(0).unwrap().len(), 4);
+
+        let single = Molecule::new(atoms(&[6]), HashMap::new(), NtoN::new());
+        assert!(matches!(
+            workspace.replace_base(0, single),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+        assert_eq!(workspace.read(0).unwrap(), molecule);
+
+        // The bond is written after oxygen is removed: a base with oxygen at 1 no longer fits.
+        workspace.create_stack(
+            Arc::new(Stack::new(vec![
+                Arc::new(Layer::RemoveElement(8)),
+                Arc::new(Layer::Fill(Molecule::new(
+                    HashMap::new(),
+                    HashMap::from([(Pair::new_ordered(0, 1), BondKind::Single)]),
+                    NtoN::new(),
+                ))),
+            ])),
+            1,
+        );
+        let carbons = Molecule::new(atoms(&[6, 6]), HashMap::new(), NtoN::new());
+        workspace.replace_base(1, carbons).unwrap();
+        let oxygen = Molecule::new(atoms(&[6, 8]), HashMap::new(), NtoN::new());
+        assert!(matches!(
+            workspace.replace_base(1, oxygen),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+        let invalid = Layer::MapScalar {
+            target: ScalarTarget::Element,
+            expr: ArithExpr::Const(0.),
+        };
+        workspace.create_stack_from_layer(Arc::new(invalid), 1);
+        assert!(matches!(
+            workspace.replace_base(2, Molecule::new(atoms(&[6]), HashMap::new(), NtoN::new())),
+            Err(LMECoreError::InvalidAtom(_))
+        ));
+    }
+
+    #[test]
+    fn read_base_skips_top_layer() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let base = Molecule::new(
+            HashMap::from([(0, Some(Atom::new(6, Point3::origin())))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base.clone());
+        let stack = Stack::new(vec![
+            Arc::new(Layer::ReplaceElement(6, 7)),
+            Arc::new(Layer::ReplaceElement(7, 8)),
+        ]);
+        workspace.create_stack(Arc::new(stack), 1);
+        let before = workspace.read_base(0).unwrap();
+        assert_eq!(before.atoms()[&0].unwrap().element(), 7);
+        assert_eq!(workspace.read(0).unwrap().atoms()[&0].unwrap().element(), 8);
+        workspace.discard_top_layer(0).unwrap();
+        assert_eq!(workspace.read_base(0).unwrap(), base);
+        assert!(matches!(
+            workspace.read_base(workspace.stacks()),
+            Err(LMECoreError::NoSuchStack)
+        ));
+    }
+
+    #[test]
+    fn discard_top_layer_reverts() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atom = |element| {
+            Molecule::new(
+                HashMap::from([(0, Some(Atom::new(element, Point3::origin())))]),
+                HashMap::new(),
+                NtoN::new(),
+            )
+        };
+        let mut workspace = Workspace::new(atom(6));
+        let stack = Stack::new(vec![Arc::new(Layer::ReplaceElement(6, 7))]);
+        workspace.create_stack(Arc::new(stack), 1);
+        let structure = workspace.read(0).unwrap();
+        workspace.write_to_stack(0, 1, atom(8)).unwrap();
+        assert_ne!(workspace.read(0).unwrap(), structure);
+        workspace.discard_top_layer(0).unwrap();
+        assert_eq!(workspace.read(0).unwrap(), structure);
+        workspace.discard_top_layer(0).unwrap();
+        assert_eq!(workspace.read(0).unwrap(), atom(6));
+        assert!(matches!(
+            workspace.discard_top_layer(0),
+            Err(LMECoreError::RootLayerError)
+        ));
+        assert!(matches!(
+            workspace.discard_top_layer(workspace.stacks()),
+            Err(LMECoreError::NoSuchStack)
+        ));
+    }
+
+    #[test]
+    fn reject_non_affine_transform() {
+        use crate::{entity::Layer, error::LMECoreError};
+        use nalgebra::{Matrix4, Transform3, Translation3};
+
+        let translation =
+            Transform3::from_matrix_unchecked(Translation3::new(1., 2., 3.).to_homogeneous());
+        assert!(Layer::Transform(translation).validate().is_ok());
+        let projective = Transform3::from_matrix_unchecked(Matrix4::new(
+            1., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1., 0., 0., 0., 1., 1.,
+        ));
+        assert!(matches!(
+            Layer::Transform(projective).validate(),
+            Err(LMECoreError::InvalidTransform(_))
+        ));
+        let singular = Transform3::from_matrix_unchecked(Matrix4::new(
+            1., 0., 0., 0., 0., 0., 0., 0., 0., 0., 1., 0., 0., 0., 0., 1.,
+        ));
+        assert!(matches!(
+            Layer::Transform(singular).validate(),
+            Err(LMECoreError::InvalidTransform(_))
+        ));
+    }
+
+    #[test]
+    fn molecule_groups() {
+        use crate::entity::Molecule;
+        use std::collections::HashSet;
+
+        let mut molecule = Molecule::default();
+        assert!(molecule.add_to_group(0, "ligand"));
+        assert!(molecule.add_to_group(1, "ligand"));
+        assert!(!molecule.add_to_group(1, "ligand"));
+        assert!(molecule.add_to_group(1, "ring"));
+        assert_eq!(molecule.group_members("ligand"), HashSet::from([0, 1]));
+        assert!(molecule.remove_from_group(0, "ligand"));
+        assert!(!molecule.remove_from_group(0, "ligand"));
+        assert_eq!(molecule.group_members("ligand"), HashSet::from([1]));
+        assert!(molecule.group_members("solvent").is_empty());
+    }
+
+    #[test]
+    fn remove_atom_cleans_references() {
+        use crate::entity::{Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{HashMap, HashSet};
+
+        let atom = Some(Atom::new(6, Point3::origin()));
+        let mut molecule = Molecule::new(
+            HashMap::from([(0, atom), (1, atom), (2, atom)]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Single),
+                (Pair::new_ordered(1, 2), BondKind::Single),
+            ]),
+            NtoN::from(HashSet::from([
+                (1, "ring".to_string()),
+                (1, "ligand".to_string()),
+                (2, "ligand".to_string()),
+            ])),
+        );
+        molecule.remove_atom(1);
+        assert_eq!(molecule.atoms()[&1], None);
+        assert!(molecule.bonds().is_empty());
+        assert!(molecule.group_members("ring").is_empty());
+        assert_eq!(molecule.group_members("ligand"), HashSet::from([2]));
+    }
+
+    #[test]
+    fn bond_selections_cutoff() {
+        use crate::{
+            entity::{Atom, BondKind, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{HashMap, HashSet};
+
+        let atom = |x| Some(Atom::new(6, Point3::new(x, 0., 0.)));
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, atom(0.)),
+                (1, atom(1.)),
+                (2, atom(1.5)),
+                (3, atom(4.)),
+                (4, None),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let bonded = |patch: Molecule| patch.bonds().keys().copied().collect::<HashSet<_>>();
+        let patch = molecule
+            .bond_selections(&[0, 1], &[1, 2, 3], BondKind::Single, Some(2.))
+            .unwrap();
+        assert_eq!(
+            bonded(patch),
+            HashSet::from([
+                Pair::new_ordered(0, 1),
+                Pair::new_ordered(0, 2),
+                Pair::new_ordered(1, 2),
+            ])
+        );
+        let patch = molecule
+            .bond_selections(&[0], &[3], BondKind::Double, None)
+            .unwrap();
+        assert_eq!(patch.bonds()[&Pair::new_ordered(0, 3)], BondKind::Double);
+        assert!(matches!(
+            molecule.bond_selections(&[0], &[4], BondKind::Single, None),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+    }
+
+    #[test]
+    fn workspace_stats() {
+        use crate::{
+            entity::{Atom, BondKind, Layer, Molecule, Stack},
+            Workspace, WorkspaceStats,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atom = Some(Atom::new(6, Point3::origin()));
+        let base = Molecule::new(
+            HashMap::from([(0, atom), (1, atom), (2, None)]),
+            HashMap::from([(Pair::new_ordered(0, 1), BondKind::Single)]),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(base);
+        let range = workspace.create_stack(Arc::new(Stack::new(vec![])), 1);
+        workspace.clone_stack(range.start, 1);
+        let plugin = Layer::PluginFilter("missing.sh".to_string(), vec![], Default::default());
+        workspace.create_stack_from_layer(Arc::new(plugin), 1);
+        let slots = workspace.stacks();
+        assert_eq!(
+            workspace.stats(),
+            WorkspaceStats {
+                stacks: slots,
+                unique_stacks: 2,
+                atoms: 2 * (slots - 1),
+                bonds: slots - 1,
+                unreadable: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn only_bonded_atoms() {
+        use crate::entity::{Atom, BondKind, Layer, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let atom = |element| Some(Atom::new(element, Point3::origin()));
+        let molecule = Molecule::new(
+            HashMap::from([(0, atom(6)), (1, atom(8)), (2, atom(18)), (3, None)]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Double),
+                (Pair::new_ordered(2, 3), BondKind::Single),
+            ]),
+            NtoN::new(),
+        );
+        let filtered = Layer::OnlyBondedAtoms.filter(molecule).unwrap();
+        assert!(filtered.get_atom(0).is_some());
+        assert!(filtered.get_atom(1).is_some());
+        assert!(filtered.get_atom(2).is_none());
+    }
+
+    #[test]
+    fn commit_detects_conflicts() {
+        use crate::{
+            entity::{Atom, Molecule, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let patch = |element| {
+            Molecule::new(
+                HashMap::from([(0, Some(Atom::new(element, Point3::origin())))]),
+                HashMap::new(),
+                NtoN::new(),
+            )
+        };
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::new(vec![])), 1);
+        let stale = workspace.snapshot(0).unwrap();
+        let fresh = workspace.snapshot(0).unwrap();
+        workspace
+            .commit(&fresh, &fresh.read().unwrap(), patch(6))
+            .unwrap();
+        assert!(matches!(
+            workspace.commit(&stale, &stale.read().unwrap(), patch(7)),
+            Err(LMECoreError::StackConflict)
+        ));
+        assert_eq!(workspace.read(0).unwrap().get_atom(0).unwrap().element(), 6);
+        let current = workspace.snapshot(0).unwrap();
+        assert_eq!(current.read().unwrap(), workspace.read(0).unwrap());
+        workspace
+            .commit(&current, &current.read().unwrap(), patch(7))
+            .unwrap();
+        assert_eq!(workspace.read(0).unwrap().get_atom(0).unwrap().element(), 7);
+    }
+
+    #[test]
+    fn measure_geometry() {
+        use crate::{
+            entity::{Atom, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let atom = |x, y, z| Some(Atom::new(6, Point3::new(x, y, z)));
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, atom(1., 1., 0.)),
+                (1, atom(0., 0., 0.)),
+                (2, atom(0., 0., 1.)),
+                (3, atom(-1., -1., 1.)),
+                (4, atom(1., 1., 1.)),
+                (5, atom(-1., 1., 1.)),
+                (6, None),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let approx = |atoms: &[usize], expected: f64| {
+            assert!((molecule.measure(atoms).unwrap() - expected).abs() < 1e-9)
+        };
+        approx(&[1, 2], 1.);
+        approx(&[0, 1, 2], 90.);
+        approx(&[0, 1, 2, 3], 180.);
+        approx(&[0, 1, 2, 4], 0.);
+        approx(&[0, 1, 2, 5], 90.);
+        approx(&[5, 2, 1, 0], 90.);
+        assert!(matches!(
+            molecule.measure(&[0, 6]),
+            Err(LMECoreError::NoSuchAtom)
+        ));
+        assert!(matches!(
+            molecule.measure(&[0]),
+            Err(LMECoreError::InvalidSelection(_))
+        ));
+    }
+
+    #[test]
+    fn flattened_layer_tree() {
+        use crate::{
+            entity::{Layer, Stack},
+            Workspace,
+        };
+        use std::sync::Arc;
+
+        let root = Arc::new(Layer::Comment("root".to_string()));
+        let leaf = Arc::new(Layer::Clear);
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::new(vec![root.clone()])), 1);
+        let shared = Arc::new(Stack::new(vec![root.clone(), leaf.clone()]));
+        let range = workspace.create_stack(shared, 2);
+        assert_eq!(
+            workspace.layer_tree(),
+            vec![
+                (vec![0], vec![root.as_ref().clone()]),
+                (
+                    range.collect(),
+                    vec![root.as_ref().clone(), leaf.as_ref().clone()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn fingerprint_near_duplicates() {
+        use crate::entity::{molecule_fingerprint, Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let molecule = |x, kind| {
+            Molecule::new(
+                HashMap::from([
+                    (0, Some(Atom::new(6, Point3::origin()))),
+                    (1, Some(Atom::new(8, Point3::new(x, 0., 0.)))),
+                    (2, None),
+                ]),
+                HashMap::from([(Pair::new_ordered(0, 1), kind)]),
+                NtoN::new(),
+            )
+        };
+        let fingerprint = |x, kind| molecule_fingerprint(&molecule(x, kind), 3);
+        assert_eq!(
+            fingerprint(1.2, BondKind::Double),
+            fingerprint(1.2001, BondKind::Double)
+        );
+        assert_ne!(
+            fingerprint(1.2, BondKind::Double),
+            fingerprint(1.21, BondKind::Double)
+        );
+        assert_ne!(
+            fingerprint(1.2, BondKind::Single),
+            fingerprint(1.2, BondKind::Dative)
+        );
+    }
+
+    #[test]
+    fn fractional_coordinates() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, UnitCell},
+            error::LMECoreError,
+            Workspace, WorkspaceExport,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::{Point3, Vector3};
+        use std::collections::HashMap;
+
+        let cell = UnitCell {
+            a: Vector3::new(2., 0., 0.),
+            b: Vector3::new(1., 2., 0.),
+            c: Vector3::new(0., 0., 4.),
+        };
+        let fractional = Molecule::new(
+            HashMap::from([(0, Some(Atom::new(6, Point3::new(0.5, 0.5, 0.25))))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut workspace = Workspace::new(fractional.clone());
+        assert!(matches!(
+            workspace.bind_cell(Layer::FractionalToCartesian(None)),
+            Err(LMECoreError::NoUnitCell)
+        ));
+        assert!(matches!(
+            Layer::FractionalToCartesian(None).filter(fractional.clone()),
+            Err(LMECoreError::NoUnitCell)
+        ));
+        let flat = UnitCell {
+            c: Vector3::new(4., 0., 0.),
+            ..cell
+        };
+        assert!(matches!(
+            workspace.set_cell(Some(flat)),
+            Err(LMECoreError::InvalidTransform(_))
+        ));
+        workspace.set_cell(Some(cell)).unwrap();
+        let (a, b, c, alpha, beta, gamma) = cell.parameters();
+        let expected = [2., 5f64.sqrt(), 4., 90., 90., 63.435];
+        for (value, expected) in [a, b, c, alpha, beta, gamma].into_iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-3);
+        }
+        let layer = workspace
+            .bind_cell(Layer::FractionalToCartesian(None))
+            .unwrap();
+        assert_eq!(layer, Layer::FractionalToCartesian(Some(cell)));
+        let cartesian = layer.filter(fractional.clone()).unwrap();
+        assert_eq!(
+            *cartesian.get_atom(0).unwrap().position(),
+            Point3::new(1.5, 1., 1.)
+        );
+        let back = Layer::CartesianToFractional(Some(cell))
+            .filter(cartesian)
+            .unwrap();
+        assert_eq!(back, fractional);
+        let exported = WorkspaceExport::from(&workspace);
+        assert_eq!(Workspace::from(&exported).cell(), Some(&cell));
+    }
+
+    #[test]
+    fn periodic_measurements() {
+        use crate::entity::{Atom, Molecule, UnitCell};
+        use n_to_n::NtoN;
+        use nalgebra::{Point3, Vector3};
+        use std::collections::HashMap;
+
+        let cell = UnitCell {
+            a: Vector3::new(10., 0., 0.),
+            b: Vector3::new(0., 10., 0.),
+            c: Vector3::new(0., 0., 10.),
+        };
+        let atom = |x, y| Some(Atom::new(6, Point3::new(x, y, 5.)));
+        let molecule = Molecule::new(
+            HashMap::from([(0, atom(0.5, 5.)), (1, atom(9.5, 5.)), (2, atom(9.5, 6.))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        assert!((molecule.measure(&[0, 1]).unwrap() - 9.).abs() < 1e-9);
+        assert!((molecule.measure_periodic(&[0, 1], &cell).unwrap() - 1.).abs() < 1e-9);
+        assert!((molecule.measure_periodic(&[0, 1, 2], &cell).unwrap() - 90.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn wrap_into_cell() {
+        use crate::{
+            entity::{Atom, BondKind, Layer, Molecule, UnitCell},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::{Point3, Transform3, Translation3, Vector3};
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let cell = UnitCell {
+            a: Vector3::new(10., 0., 0.),
+            b: Vector3::new(0., 10., 0.),
+            c: Vector3::new(0., 0., 10.),
+        };
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::new(9., 5., 5.)))),
+                (1, Some(Atom::new(8, Point3::new(8., 5., 5.)))),
+            ]),
+            HashMap::from([(Pair::new_ordered(0, 1), BondKind::Double)]),
+            NtoN::new(),
+        );
+        let shift =
+            Transform3::from_matrix_unchecked(Translation3::new(1.5, -6., 0.).to_homogeneous());
+        let moved = Layer::Transform(shift).filter(molecule.clone()).unwrap();
+        let wrapped = Layer::WrapIntoCell(Some(cell)).filter(moved).unwrap();
+        let position = |idx| *wrapped.get_atom(idx).unwrap().position();
+        assert!((position(0) - Point3::new(0.5, 9., 5.)).norm() < 1e-9);
+        assert!((position(1) - Point3::new(9.5, 9., 5.)).norm() < 1e-9);
+        assert_eq!(wrapped.bonds(), molecule.bonds());
+        assert!(matches!(
+            Layer::WrapIntoCell(None).filter(molecule),
+            Err(LMECoreError::NoUnitCell)
+        ));
+    }
+
+    #[test]
+    fn check_stacks_reports_layer() {
+        use crate::{
+            entity::{Layer, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use std::sync::Arc;
+
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::new(vec![Arc::new(Layer::Clear)])), 1);
+        let hidden = Stack::new(vec![
+            Arc::new(Layer::WrapIntoCell(None)),
+            Arc::new(Layer::Clear),
+        ]);
+        workspace.create_stack(Arc::new(hidden), 1);
+        let broken = Stack::new(vec![
+            Arc::new(Layer::WrapIntoCell(None)),
+            Arc::new(Layer::Clear),
+            Arc::new(Layer::WrapIntoCell(None)),
+        ]);
+        let range = workspace.create_stack(Arc::new(broken), 1);
+        let failures = workspace.check_stacks();
+        assert_eq!(failures.len(), range.len());
+        assert_eq!(failures[0].stack, range.start);
+        assert_eq!(failures[0].layer, 2);
+        let layers = workspace.stack_layers(range.start).unwrap();
+        assert_eq!(layers[failures[0].layer], (2, Layer::WrapIntoCell(None)));
+        assert!(matches!(failures[0].error, LMECoreError::NoUnitCell));
+    }
+
+    #[test]
+    fn guess_bond_orders_ethene() {
+        use crate::entity::{Atom, BondKind, Layer, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let atom = |element, x, y| Some(Atom::new(element, Point3::new(x, y, 0.)));
+        let ethene = Molecule::new(
+            HashMap::from([
+                (0, atom(6, 0., 0.)),
+                (1, atom(6, 1.33, 0.)),
+                (2, atom(1, -0.55, 0.93)),
+                (3, atom(1, -0.55, -0.93)),
+                (4, atom(1, 1.88, 0.93)),
+                (5, atom(1, 1.88, -0.93)),
+            ]),
+            [(0, 1), (0, 2), (0, 3), (1, 4), (1, 5)]
+                .into_iter()
+                .map(|(a, b)| (Pair::new_ordered(a, b), BondKind::Single))
+                .collect(),
+            NtoN::new(),
+        );
+        let guessed = Layer::GuessBondOrders.filter(ethene).unwrap();
+        assert_eq!(guessed.get_bond(0, 1), Some(&BondKind::Double));
+        assert_eq!(guessed.get_bond(0, 2), Some(&BondKind::Single));
+        assert_eq!(guessed.get_bond(1, 5), Some(&BondKind::Single));
+    }
+
+    #[test]
+    fn atom_validation() {
+        use crate::{entity::Atom, error::LMECoreError};
+        use nalgebra::Point3;
+
+        assert!(Atom::try_new(6, Point3::new(1., 2., 3.)).is_ok());
+        assert!(matches!(
+            Atom::try_new(0, Point3::origin()),
+            Err(LMECoreError::InvalidAtom(_))
+        ));
+        assert!(matches!(
+            Atom::try_new(119, Point3::origin()),
+            Err(LMECoreError::InvalidAtom(_))
+        ));
+        assert!(matches!(
+            Atom::try_new(6, Point3::new(0., f64::NAN, 0.)),
+            Err(LMECoreError::InvalidAtom(_))
+        ));
+        assert!(serde_json::from_str::<Atom>(r#"{"element":0,"position":[0,0,0]}"#).is_err());
+        assert!(serde_json::from_str::<Atom>(r#"{"element":8,"position":[0,0,0]}"#).is_ok());
+    }
+
+    #[test]
+    fn export_empty_stack() {
+        use crate::{
+            entity::{Layer, Stack},
+            Workspace, WorkspaceExport,
+        };
+        use std::sync::Arc;
+
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::default()), 1);
+        workspace.create_stack_from_layer(Arc::new(Layer::Clear), 1);
+        workspace.create_stack(Arc::new(Stack::default()), 1);
+        let export = WorkspaceExport::from(&workspace);
+        assert_eq!(export.stacks.len(), 2);
+        let json = serde_json::to_string(&export).unwrap();
+        let restored = Workspace::from(&serde_json::from_str::<WorkspaceExport>(&json).unwrap());
+        assert_eq!(restored, workspace);
+        assert_eq!(
+            workspace.layer_tree(),
+            vec![(vec![0, 2], vec![]), (vec![1], vec![Layer::Clear])]
+        );
+    }
+
+    #[test]
+    fn comment_layer_round_trip() {
+        use crate::{
+            entity::{Layer, Molecule, Stack},
+            Workspace, WorkspaceExport,
+        };
+        use std::sync::Arc;
+
+        let fill = Arc::new(Layer::Fill(Molecule::default()));
+        let comment = Arc::new(Layer::Comment("flattened for docking".to_string()));
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::new(vec![fill.clone()])), 1);
+        workspace.create_stack(Arc::new(Stack::new(vec![fill, comment])), 1);
+        assert_eq!(workspace.read(0).ok(), workspace.read(1).ok());
+
+        let export = WorkspaceExport::from(&workspace);
+        assert_eq!(export.stacks.len(), 1);
+        let restored = Workspace::from(&export);
+        assert_eq!(restored, workspace);
+        assert_ne!(restored.stacks[0], restored.stacks[1]);
+    }
+
+    #[test]
+    fn read_from_skips_cleared_layers() {
+        use crate::entity::{Atom, Layer, Molecule, Stack};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atom = |element| HashMap::from([(0, Some(Atom::new(element, Point3::origin())))]);
+        let base = Molecule::new(atom(6), HashMap::new(), NtoN::new());
+        let fill = Molecule::new(atom(8), HashMap::new(), NtoN::new());
+        let stack = Stack::new(vec![
+            Arc::new(Layer::ReplaceElement(6, 7)),
+            Arc::new(Layer::Clear),
+            Arc::new(Layer::Fill(fill.clone())),
+        ]);
+        assert_eq!(stack.read_from(&base).unwrap(), fill);
+        assert_eq!(
+            stack.read_from(&base).unwrap(),
+            stack.read(base.clone()).unwrap()
+        );
+        let uncleared = Stack::new(vec![Arc::new(Layer::ReplaceElement(6, 7))]);
+        assert_eq!(
+            uncleared.read_from(&base).unwrap(),
+            uncleared.read(base.clone()).unwrap()
+        );
+    }
+
+    #[test]
+    fn bond_set_operations() {
+        use crate::entity::{BondKind, Molecule};
+        use n_to_n::NtoN;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let bonded = |bonds: &[(usize, usize, BondKind)]| {
+            let bonds = bonds
+                .iter()
+                .map(|(a, b, kind)| (Pair::new_ordered(*a, *b), *kind))
+                .collect();
+            Molecule::new(HashMap::new(), bonds, NtoN::new())
+        };
+        let before = bonded(&[(0, 1, BondKind::Single), (1, 2, BondKind::Single)]);
+        let after = bonded(&[(2, 1, BondKind::Double), (2, 3, BondKind::Single)]);
+
+        assert_eq!(
+            after.bond_difference(&before),
+            HashMap::from([(Pair::new_ordered(2, 3), BondKind::Single)])
+        );
+        assert_eq!(
+            before.bond_difference(&after),
+            HashMap::from([(Pair::new_ordered(0, 1), BondKind::Single)])
+        );
+        assert_eq!(
+            after.bond_intersection(&before),
+            HashMap::from([(Pair::new_ordered(1, 2), BondKind::Double)])
+        );
+        assert!(before.bond_difference(&before).is_empty());
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        use crate::{
+            entity::{Layer, Molecule},
+            error::LMECoreError,
+            Workspace, MAX_CHECKPOINTS,
+        };
+        use std::sync::Arc;
+
+        let mut workspace = Workspace::new(Molecule::default());
+        workspace.create_stack_from_layer(Arc::new(Layer::Clear), 1);
+        workspace.checkpoint("before".to_string());
+        workspace.create_stack_from_layer(Arc::new(Layer::Clear), 2);
+        workspace.atom_names.insert("a".to_string(), 0);
+        assert_eq!(workspace.stacks(), 3);
+
+        workspace.restore_checkpoint("before").unwrap();
+        assert_eq!(workspace.stacks(), 1);
+        assert!(workspace.atom_names.is_empty());
+        assert_eq!(workspace.checkpoints(), vec!["before"]);
+        assert!(matches!(
+            workspace.restore_checkpoint("missing"),
+            Err(LMECoreError::NoSuchId)
+        ));
+
+        for idx in 0..MAX_CHECKPOINTS {
+            workspace.checkpoint(idx.to_string());
+        }
+        assert_eq!(workspace.checkpoints().len(), MAX_CHECKPOINTS);
+        assert_eq!(workspace.checkpoints()[0], "0");
+    }
+
+    #[test]
+    fn sort_atoms_layer() {
+        use crate::entity::{Atom, BondKind, Layer, Molecule, SortKey};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{HashMap, HashSet};
+
+        let atom = |element, z| Some(Atom::new(element, Point3::new(0., 0., z)));
+        // Methanol with the hydroxyl hydrogen first and a removed atom.
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, atom(1, 0.)),
+                (1, atom(8, 1.)),
+                (2, None),
+                (3, atom(6, 2.)),
+                (4, atom(1, 3.)),
+            ]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Single),
+                (Pair::new_ordered(1, 3), BondKind::Single),
+                (Pair::new_ordered(3, 4), BondKind::Single),
+                (Pair::new_ordered(2, 3), BondKind::Single),
+            ]),
+            NtoN::from(HashSet::from([(1, "hydroxyl".to_string())])),
+        );
+        let layer = Layer::SortAtoms {
+            by: SortKey::Element,
+        };
+        let sorted = layer.filter(molecule).unwrap();
+        let elements = (0..4)
+            .map(|idx| sorted.get_atom(idx).unwrap().element())
+            .collect::<Vec<_>>();
+        assert_eq!(elements, vec![1, 1, 6, 8]);
+        assert_eq!(sorted.get_atom(4), None);
+        assert_eq!(sorted.get_atom(1).unwrap().position().z, 3.);
+        assert_eq!(
+            sorted.bonds().keys().copied().collect::<HashSet<_>>(),
+            HashSet::from([
+                Pair::new_ordered(0, 3),
+                Pair::new_ordered(1, 2),
+                Pair::new_ordered(2, 3)
+            ])
+        );
+        assert_eq!(sorted.group_members("hydroxyl"), HashSet::from([3]));
+    }
+
+    #[test]
+    fn concurrent_writes_persist() {
+        use crate::{
+            entity::{Atom, Molecule, Stack},
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{
+            collections::HashMap,
+            sync::{Arc, Mutex},
+            thread,
+        };
+
+        let atom = |idx, element| {
+            let atoms = HashMap::from([(idx, Some(Atom::new(element, Point3::origin())))]);
+            Molecule::new(atoms, HashMap::new(), NtoN::new())
+        };
+        let mut workspace = Workspace::new(Molecule::default());
+        workspace.create_stack(Arc::new(Stack::default()), 8);
+        for idx in 0..8 {
+            workspace.write_to_stack(idx, 1, atom(0, idx + 1)).unwrap();
+        }
+        let workspace = Mutex::new(workspace);
+        thread::scope(|scope| {
+            for idx in [1, 2] {
+                let workspace = &workspace;
+                scope.spawn(move || {
+                    let mut workspace = workspace.lock().unwrap();
+                    workspace.write_to_stack(0, 8, atom(idx, 6)).unwrap();
+                });
+            }
+        });
+        let workspace = workspace.into_inner().unwrap();
+        for idx in 0..8 {
+            let molecule = workspace.read(idx).unwrap();
+            assert_eq!(molecule.get_atom(0).unwrap().element(), idx + 1);
+            assert!(molecule.get_atom(1).is_some() && molecule.get_atom(2).is_some());
+            assert_eq!(workspace.stack_layers(idx).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn atom_limit_guards_writes() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atoms = |indexes: &[usize]| {
+            let atoms = indexes
+                .iter()
+                .map(|idx| (*idx, Some(Atom::new(6, Point3::origin()))))
+                .collect();
+            Molecule::new(atoms, HashMap::new(), NtoN::new())
+        };
+        let mut workspace = Workspace::new(atoms(&[0, 1]));
+        workspace.create_stack(Arc::new(Stack::default()), 2);
+        workspace.set_max_atoms(Some(3));
+
+        assert!(workspace.write_to_stack(0, 1, atoms(&[1, 2])).unwrap());
+        assert!(matches!(
+            workspace.write_to_stack(0, 2, atoms(&[3])),
+            Err(LMECoreError::TooManyAtoms(3))
+        ));
+        assert_eq!(workspace.read(1).unwrap(), atoms(&[0, 1]));
+
+        let snapshot = workspace.snapshot(0).unwrap();
+        let current = snapshot.read().unwrap();
+        assert!(matches!(
+            workspace.commit(&snapshot, &current, atoms(&[4])),
+            Err(LMECoreError::TooManyAtoms(3))
+        ));
+        let removal = Molecule::new(HashMap::from([(0, None)]), HashMap::new(), NtoN::new());
+        let patch = Molecule::merge(removal, atoms(&[4]));
+        workspace.commit(&snapshot, &current, patch).unwrap();
+
+        let missing = Layer::PluginFilter("missing.sh".to_string(), vec![], Default::default());
+        workspace.add_layer_to_stack(1, 1, Arc::new(missing));
+        assert!(matches!(
+            workspace.write_to_stack(1, 1, atoms(&[2])),
+            Err(LMECoreError::PluginNotFound(_))
+        ));
+        let removal = Molecule::new(HashMap::from([(0, None)]), HashMap::new(), NtoN::new());
+        assert!(workspace.write_to_stack(1, 1, removal).unwrap());
+    }
+
+    #[test]
+    fn layers_between_pages_layers() {
+        use crate::{
+            entity::{Layer, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use std::sync::Arc;
+
+        let layers = (0..4).map(Layer::RemoveElement);
+        let stack = Stack::new(layers.clone().map(Arc::new).collect());
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(stack), 1);
+
+        assert_eq!(
+            workspace.layers_between(0, 1, 3).unwrap(),
+            vec![(1, Layer::RemoveElement(1)), (2, Layer::RemoveElement(2))]
+        );
+        assert!(workspace.layers_between(0, 4, 4).unwrap().is_empty());
+        assert!(matches!(
+            workspace.layers_between(0, 2, 5),
+            Err(LMECoreError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            workspace.layers_between(0, 3, 2),
+            Err(LMECoreError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            workspace.layers_between(1, 0, 0),
+            Err(LMECoreError::NoSuchStack)
+        ));
+    }
+
+    #[test]
+    fn empty_ranges_write_nothing() {
+        use crate::{
+            entity::{Layer, Molecule, Stack},
+            Workspace,
+        };
+        use std::sync::Arc;
+
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::default()), 2);
+        let before = workspace.clone();
+        for start in [0, 1, 2, 5, usize::MAX] {
+            assert!(!workspace
+                .write_to_stack(start, 0, Molecule::default())
+                .unwrap());
+            assert!(!workspace.add_layer_to_stack(start, 0, Arc::new(Layer::Clear)));
+        }
+        assert!(!workspace.add_layer_to_stack(usize::MAX, 2, Arc::new(Layer::Clear)));
+        assert_eq!(workspace, before);
+    }
+
+    #[test]
+    fn annotations_for_atoms() {
+        use crate::{entity::Molecule, Workspace};
+        use std::collections::{HashMap, HashSet};
+
+        let mut workspace = Workspace::new(Molecule::default());
+        workspace.set_atom_names(HashMap::from([(0, "a".to_string())]));
+        workspace.groups.insert("ring".to_string(), 0);
+        workspace.groups.insert("ring".to_string(), 1);
+        workspace.groups.insert("chain".to_string(), 0);
+
+        assert_eq!(
+            workspace.annotations_for(&[0, 1, 2]),
+            HashMap::from([
+                (
+                    0,
+                    (
+                        Some("a".to_string()),
+                        HashSet::from(["ring".to_string(), "chain".to_string()])
+                    )
+                ),
+                (1, (None, HashSet::from(["ring".to_string()]))),
+                (2, (None, HashSet::new())),
+            ])
+        );
+    }
+
+    #[test]
+    fn map_scalar_layer() {
+        use crate::{
+            entity::{ArithExpr, Atom, Layer, Molecule, ScalarTarget, ScalarVar},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::new(1., 0., 0.)))),
+                (1, Some(Atom::new(7, Point3::origin()))),
+                (2, None),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let layer: Layer = serde_json::from_str(
+            r#"{"MapScalar": {"target": "element", "expr": {"Add": [{"Var": "element"}, {"Var": "x"}]}}}"#,
+        )
+        .unwrap();
+        let mapped = layer.filter(molecule.clone()).unwrap();
+        assert_eq!(mapped.atoms()[&0].unwrap().element(), 7);
+        assert_eq!(mapped.atoms()[&1].unwrap().element(), 7);
+        assert_eq!(mapped.atoms()[&2], None);
+
+        let negative = Layer::MapScalar {
+            target: ScalarTarget::Element,
+            expr: ArithExpr::Mul(
+                Box::new(ArithExpr::Var(ScalarVar::Element)),
+                Box::new(ArithExpr::Const(-1.)),
+            ),
+        };
+        assert!(matches!(
+            negative.filter(molecule),
+            Err(LMECoreError::InvalidAtom(_))
+        ));
+    }
+
+    #[test]
+    fn molecule_digest_tracks_changes() {
+        use crate::entity::{molecule_digest, Atom, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let atom = Atom::new(6, Point3::origin());
+        let molecule = Molecule::new(
+            HashMap::from([(0, Some(atom)), (1, Some(atom)), (2, None)]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut copy = Molecule::merge(Molecule::default(), molecule.clone());
+        assert_eq!(molecule_digest(&copy), molecule_digest(&molecule));
+        copy.add_to_group(0, "ring");
+        assert_ne!(molecule_digest(&copy), molecule_digest(&molecule));
+        let moved = Molecule::merge(
+            molecule.clone(),
+            Molecule::new(
+                HashMap::from([(1, Some(Atom::new(6, Point3::new(1e-12, 0., 0.))))]),
+                HashMap::new(),
+                NtoN::new(),
+            ),
+        );
+        assert_ne!(molecule_digest(&moved), molecule_digest(&molecule));
+    }
+
+    #[test]
+    fn cap_coordination_layer() {
+        use crate::entity::{Atom, BondKind, Layer, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::HashMap;
+
+        let mut atoms = HashMap::from([(0, Some(Atom::new(6, Point3::origin())))]);
+        let mut bonds = HashMap::new();
+        for (idx, x) in [(1, 1.1), (2, 1.0), (3, 1.2), (4, 1.1), (5, 1.05)] {
+            atoms.insert(idx, Some(Atom::new(1, Point3::new(x, 0., 0.))));
+            bonds.insert(Pair::new_ordered(0, idx), BondKind::Single);
+        }
+        let molecule = Molecule::new(atoms, bonds, NtoN::new());
+        let layer = Layer::CapCoordination {
+            max: HashMap::from([(6, 4)]),
+        };
+        let capped = layer.filter(molecule).unwrap();
+        assert_eq!(capped.degree(0), 4);
+        assert!(capped.get_bond(0, 3).is_none());
+
+        let tied = Layer::CapCoordination {
+            max: HashMap::from([(6, 3)]),
+        };
+        let capped = tied.filter(capped).unwrap();
+        let mut kept = capped.neighbors(0).map(|(idx, _)| idx).collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn fill_valences_layer() {
+        use crate::entity::{Atom, Layer, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([(2, Some(Atom::new(6, Point3::origin()))), (3, None)]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let layer = Layer::FillValences {
+            target_valence: HashMap::new(),
+        };
+        let methane = layer.filter(molecule).unwrap();
+        let hydrogens = (4..8)
+            .map(|idx| methane.get_atom(idx).unwrap().position().coords)
+            .collect::<Vec<_>>();
+        assert_eq!(methane.degree(2), 4);
+        for (i, a) in hydrogens.iter().enumerate() {
+            assert!((a.norm() - 1.07).abs() < 1e-9);
+            for b in &hydrogens[i + 1..] {
+                assert!((a.angle(b).to_degrees() - 109.47).abs() < 0.01);
+            }
+        }
+
+        let saturated = layer.filter(methane.clone()).unwrap();
+        assert_eq!(saturated, methane);
+        let radical = Layer::FillValences {
+            target_valence: HashMap::from([(6, 3)]),
+        };
+        assert_eq!(radical.filter(methane.clone()).unwrap(), methane);
+    }
+
+    #[test]
+    fn clone_ancestor_peels_layers() {
+        use crate::{
+            entity::{Layer, Stack},
+            error::LMECoreError,
+            Workspace,
+        };
+        use std::sync::Arc;
+
+        let stack = Stack::new((1..=4).map(Layer::RemoveElement).map(Arc::new).collect());
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(stack), 1);
+
+        assert_eq!(workspace.clone_ancestor(0, 3, 2).unwrap(), 1..3);
+        assert_eq!(
+            workspace.stack_layers(2).unwrap(),
+            vec![(0, Layer::RemoveElement(1))]
+        );
+        assert_eq!(workspace.clone_ancestor(0, 4, 1).unwrap(), 3..4);
+        assert!(workspace.stack_layers(3).unwrap().is_empty());
+        assert!(matches!(
+            workspace.clone_ancestor(0, 5, 1),
+            Err(LMECoreError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            workspace.clone_ancestor(9, 0, 1),
+            Err(LMECoreError::NoSuchStack)
+        ));
+    }
+
+    #[test]
+    fn provenance_tracks_last_writer() {
+        use crate::{
+            entity::{Atom, Layer, Molecule, Stack},
+            Workspace,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::{collections::HashMap, sync::Arc};
+
+        let atoms = |atoms: &[(usize, usize)]| {
+            let atoms = atoms
+                .iter()
+                .map(|(idx, element)| (*idx, Some(Atom::new(*element, Point3::origin()))))
+                .collect();
+            Molecule::new(atoms, HashMap::new(), NtoN::new())
+        };
+        let stack = Stack::new(vec![
+            Arc::new(Layer::Fill(atoms(&[(2, 6), (3, 8)]))),
+            Arc::new(Layer::ReplaceElement(8, 7)),
+            Arc::new(Layer::Comment("note".to_string())),
+            Arc::new(Layer::RemoveElement(1)),
+        ]);
+        let mut workspace = Workspace::new(atoms(&[(0, 6), (1, 1)]));
+        workspace.create_stack(Arc::new(stack), 1);
+
+        assert_eq!(
+            workspace.provenance(0).unwrap(),
+            HashMap::from([(1, 3), (2, 0), (3, 1)])
+        );
+    }
+
+    #[test]
+    fn rotation_layer() {
+        use crate::{
+            entity::{Atom, Layer, Molecule},
+            error::LMECoreError,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::{Matrix3, Point3, Rotation3, Vector3};
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([(0, Some(Atom::new(6, Point3::new(2., 1., 0.))))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let quarter = Rotation3::from_axis_angle(&Vector3::z_axis(), std::f64::consts::FRAC_PI_2);
+        let layer = Layer::Rotation {
+            matrix: *quarter.matrix(),
+            center: Point3::new(1., 1., 0.),
+        };
+        layer.validate().unwrap();
+        let rotated = layer.filter(molecule).unwrap();
+        let position = rotated.get_atom(0).unwrap().position();
+        assert!((position - Point3::new(1., 2., 0.)).norm() < 1e-12);
+
+        let mirror = Layer::Rotation {
+            matrix: Matrix3::from_diagonal(&Vector3::new(-1., 1., 1.)),
+            center: Point3::origin(),
+        };
+        assert!(matches!(
+            mirror.validate(),
+            Err(LMECoreError::InvalidTransform(_))
+        ));
+    }
+
+    #[test]
+    fn present_atoms_skip_removed() {
+        use crate::entity::{Atom, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use rayon::prelude::*;
+        use std::collections::HashMap;
+
+        let atom = Atom::new(6, Point3::origin());
+        let molecule = Molecule::new(
+            HashMap::from([(0, Some(atom)), (1, None), (4, Some(atom))]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        let mut present = molecule
+            .present_atoms()
+            .map(|(idx, atom)| (idx, *atom))
+            .collect::<Vec<_>>();
+        present.sort_by_key(|(idx, _)| *idx);
+        assert_eq!(present, vec![(0, atom), (4, atom)]);
     }
 }
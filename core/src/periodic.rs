@@ -0,0 +1,199 @@
+use serde::Serialize;
+
+#[rustfmt::skip]
+const SYMBOLS: [&str; 118] = [
+    "H", "He",
+    "Li", "Be", "B", "C", "N", "O", "F", "Ne",
+    "Na", "Mg", "Al", "Si", "P", "S", "Cl", "Ar",
+    "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe", "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As", "Se", "Br", "Kr",
+    "Rb", "Sr", "Y", "Zr", "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd", "In", "Sn", "Sb", "Te", "I", "Xe",
+    "Cs", "Ba", "La", "Ce", "Pr", "Nd", "Pm", "Sm", "Eu", "Gd", "Tb", "Dy", "Ho", "Er", "Tm", "Yb", "Lu", "Hf",
+    "Ta", "W", "Re", "Os", "Ir", "Pt", "Au", "Hg", "Tl", "Pb", "Bi", "Po", "At", "Rn",
+    "Fr", "Ra", "Ac", "Th", "Pa", "U", "Np", "Pu", "Am", "Cm", "Bk", "Cf", "Es", "Fm", "Md", "No", "Lr", "Rf",
+    "Db", "Sg", "Bh", "Hs", "Mt", "Ds", "Rg", "Cn", "Nh", "Fl", "Mc", "Lv", "Ts", "Og",
+];
+
+#[rustfmt::skip]
+const NAMES: [&str; 118] = [
+    "Hydrogen", "Helium", "Lithium", "Beryllium", "Boron", "Carbon", "Nitrogen", "Oxygen", "Fluorine", "Neon",
+    "Sodium", "Magnesium", "Aluminium", "Silicon", "Phosphorus", "Sulfur", "Chlorine", "Argon", "Potassium", "Calcium",
+    "Scandium", "Titanium", "Vanadium", "Chromium", "Manganese", "Iron", "Cobalt", "Nickel", "Copper", "Zinc",
+    "Gallium", "Germanium", "Arsenic", "Selenium", "Bromine", "Krypton", "Rubidium", "Strontium", "Yttrium", "Zirconium",
+    "Niobium", "Molybdenum", "Technetium", "Ruthenium", "Rhodium", "Palladium", "Silver", "Cadmium", "Indium", "Tin",
+    "Antimony", "Tellurium", "Iodine", "Xenon", "Caesium", "Barium", "Lanthanum", "Cerium", "Praseodymium", "Neodymium",
+    "Promethium", "Samarium", "Europium", "Gadolinium", "Terbium", "Dysprosium", "Holmium", "Erbium", "Thulium", "Ytterbium",
+    "Lutetium", "Hafnium", "Tantalum", "Tungsten", "Rhenium", "Osmium", "Iridium", "Platinum", "Gold", "Mercury",
+    "Thallium", "Lead", "Bismuth", "Polonium", "Astatine", "Radon", "Francium", "Radium", "Actinium", "Thorium",
+    "Protactinium", "Uranium", "Neptunium", "Plutonium", "Americium", "Curium", "Berkelium", "Californium", "Einsteinium", "Fermium",
+    "Mendelevium", "Nobelium", "Lawrencium", "Rutherfordium", "Dubnium", "Seaborgium", "Bohrium", "Hassium", "Meitnerium", "Darmstadtium",
+    "Roentgenium", "Copernicium", "Nihonium", "Flerovium", "Moscovium", "Livermorium", "Tennessine", "Oganesson",
+];
+
+/// Standard atomic weights, or the mass number of the most stable isotope.
+#[rustfmt::skip]
+const MASSES: [f64; 118] = [
+    1.008, 4.0026, 6.94, 9.0122, 10.81, 12.011, 14.007, 15.999, 18.998, 20.180,
+    22.990, 24.305, 26.982, 28.085, 30.974, 32.06, 35.45, 39.95, 39.098, 40.078,
+    44.956, 47.867, 50.942, 51.996, 54.938, 55.845, 58.933, 58.693, 63.546, 65.38,
+    69.723, 72.630, 74.922, 78.971, 79.904, 83.798, 85.468, 87.62, 88.906, 91.224,
+    92.906, 95.95, 97.0, 101.07, 102.91, 106.42, 107.87, 112.41, 114.82, 118.71,
+    121.76, 127.60, 126.90, 131.29, 132.91, 137.33, 138.91, 140.12, 140.91, 144.24,
+    145.0, 150.36, 151.96, 157.25, 158.93, 162.50, 164.93, 167.26, 168.93, 173.05,
+    174.97, 178.49, 180.95, 183.84, 186.21, 190.23, 192.22, 195.08, 196.97, 200.59,
+    204.38, 207.2, 208.98, 209.0, 210.0, 222.0, 223.0, 226.0, 227.0, 232.04,
+    231.04, 238.03, 237.0, 244.0, 243.0, 247.0, 247.0, 251.0, 252.0, 257.0,
+    258.0, 259.0, 266.0, 267.0, 268.0, 269.0, 270.0, 269.0, 278.0, 281.0,
+    282.0, 285.0, 286.0, 289.0, 290.0, 293.0, 294.0, 294.0,
+];
+
+/// Single bond covalent radii in Å (Cordero et al. 2008), known up to curium.
+#[rustfmt::skip]
+const COVALENT_RADII: [f64; 96] = [
+    0.31, 0.28, 1.28, 0.96, 0.84, 0.76, 0.71, 0.66, 0.57, 0.58,
+    1.66, 1.41, 1.21, 1.11, 1.07, 1.05, 1.02, 1.06, 2.03, 1.76,
+    1.70, 1.60, 1.53, 1.39, 1.39, 1.32, 1.26, 1.24, 1.32, 1.22,
+    1.22, 1.20, 1.19, 1.20, 1.20, 1.16, 2.20, 1.95, 1.90, 1.75,
+    1.64, 1.54, 1.47, 1.46, 1.42, 1.39, 1.45, 1.44, 1.42, 1.39,
+    1.39, 1.38, 1.39, 1.40, 2.44, 2.15, 2.07, 2.04, 2.03, 2.01,
+    1.99, 1.98, 1.98, 1.96, 1.94, 1.92, 1.92, 1.89, 1.90, 1.87,
+    1.87, 1.75, 1.70, 1.62, 1.51, 1.44, 1.41, 1.36, 1.36, 1.32,
+    1.45, 1.46, 1.48, 1.40, 1.50, 1.50, 2.60, 2.21, 2.15, 2.06,
+    2.00, 1.96, 1.90, 1.87, 1.80, 1.69,
+];
+
+/// Jmol CPK colors, known up to meitnerium.
+#[rustfmt::skip]
+const COLORS: [u32; 109] = [
+    0xFFFFFF, 0xD9FFFF, 0xCC80FF, 0xC2FF00, 0xFFB5B5, 0x909090, 0x3050F8, 0xFF0D0D, 0x90E050, 0xB3E3F5,
+    0xAB5CF2, 0x8AFF00, 0xBFA6A6, 0xF0C8A0, 0xFF8000, 0xFFFF30, 0x1FF01F, 0x80D1E3, 0x8F40D4, 0x3DFF00,
+    0xE6E6E6, 0xBFC2C7, 0xA6A6AB, 0x8A99C7, 0x9C7AC7, 0xE06633, 0xF090A0, 0x50D050, 0xC88033, 0x7D80B0,
+    0xC28F8F, 0x668F8F, 0xBD80E3, 0xFFA100, 0xA62929, 0x5CB8D1, 0x702EB0, 0x00FF00, 0x94FFFF, 0x94E0E0,
+    0x73C2C9, 0x54B5B5, 0x3B9E9E, 0x248F8F, 0x0A7D8C, 0x006985, 0xC0C0C0, 0xFFD98F, 0xA67573, 0x668080,
+    0x9E63B5, 0xD47A00, 0x940094, 0x429EB0, 0x57178F, 0x00C900, 0x70D4FF, 0xFFFFC7, 0xD9FFC7, 0xC7FFC7,
+    0xA3FFC7, 0x8FFFC7, 0x61FFC7, 0x45FFC7, 0x30FFC7, 0x1FFFC7, 0x00FF9C, 0x00E675, 0x00D452, 0x00BF38,
+    0x00AB24, 0x4DC2FF, 0x4DA6FF, 0x2194D6, 0x267DAB, 0x266696, 0x175487, 0xD0D0E0, 0xFFD123, 0xB8B8D0,
+    0xA6544D, 0x575961, 0x9E4FB5, 0xAB5C00, 0x754F45, 0x428296, 0x420066, 0x007D00, 0x70ABFA, 0x00BAFF,
+    0x00A1FF, 0x008FFF, 0x0080FF, 0x006BFF, 0x545CF2, 0x785CE3, 0x8A4FE3, 0xA136D4, 0xB31FD4, 0xB31FBA,
+    0xB30DA6, 0xBD0D87, 0xC70066, 0xCC0059, 0xD1004F, 0xD90045, 0xE00038, 0xE6002E, 0xEB0026,
+];
+
+pub fn symbol(element: usize) -> Option<&'static str> {
+    element
+        .checked_sub(1)
+        .and_then(|idx| SYMBOLS.get(idx))
+        .copied()
+}
+
+pub fn element(symbol: &str) -> Option<usize> {
+    SYMBOLS
+        .iter()
+        .position(|item| item.eq_ignore_ascii_case(symbol))
+        .map(|idx| idx + 1)
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ElementInfo {
+    pub atomic_number: usize,
+    pub symbol: &'static str,
+    pub name: &'static str,
+    pub mass: f64,
+    pub covalent_radius: Option<f64>,
+    pub color: Option<[u8; 3]>,
+}
+
+pub fn info(element: usize) -> Option<ElementInfo> {
+    let idx = element.checked_sub(1)?;
+    Some(ElementInfo {
+        atomic_number: element,
+        symbol: SYMBOLS.get(idx)?,
+        name: NAMES[idx],
+        mass: MASSES[idx],
+        covalent_radius: COVALENT_RADII.get(idx).copied(),
+        color: COLORS.get(idx).map(|color| {
+            let [_, r, g, b] = color.to_be_bytes();
+            [r, g, b]
+        }),
+    })
+}
+
+pub fn mass(element: usize) -> Option<f64> {
+    element
+        .checked_sub(1)
+        .and_then(|idx| MASSES.get(idx))
+        .copied()
+}
+
+pub fn covalent_radius(element: usize) -> Option<f64> {
+    element
+        .checked_sub(1)
+        .and_then(|idx| COVALENT_RADII.get(idx))
+        .copied()
+}
+
+pub fn table() -> Vec<ElementInfo> {
+    (1..=SYMBOLS.len()).filter_map(info).collect()
+}
+
+/// Typical single, double and triple bond lengths in Å between common organic elements.
+#[rustfmt::skip]
+const BOND_LENGTHS: [(usize, usize, [Option<f64>; 3]); 8] = [
+    (6, 6, [Some(1.54), Some(1.34), Some(1.20)]),
+    (6, 7, [Some(1.47), Some(1.28), Some(1.16)]),
+    (6, 8, [Some(1.43), Some(1.21), Some(1.13)]),
+    (6, 16, [Some(1.82), Some(1.60), None]),
+    (7, 7, [Some(1.45), Some(1.25), Some(1.10)]),
+    (7, 8, [Some(1.40), Some(1.21), None]),
+    (8, 8, [Some(1.48), Some(1.21), None]),
+    (15, 8, [Some(1.63), Some(1.48), None]),
+];
+
+pub fn bond_lengths(a: usize, b: usize) -> Option<[Option<f64>; 3]> {
+    BOND_LENGTHS
+        .iter()
+        .find(|(x, y, _)| (*x, *y) == (a, b) || (*x, *y) == (b, a))
+        .map(|(_, _, lengths)| *lengths)
+}
+
+/// Usual valence of the common main group elements.
+#[rustfmt::skip]
+const VALENCES: [(usize, usize); 12] = [
+    (1, 1), (5, 3), (6, 4), (7, 3), (8, 2), (9, 1),
+    (14, 4), (15, 3), (16, 2), (17, 1), (35, 1), (53, 1),
+];
+
+pub fn valence(element: usize) -> Option<usize> {
+    VALENCES
+        .iter()
+        .find(|(item, _)| *item == element)
+        .map(|(_, valence)| *valence)
+}
+
+mod test {
+    #[test]
+    fn symbol_lookup() {
+        use crate::periodic::{element, symbol};
+
+        assert_eq!(symbol(0), None);
+        assert_eq!(symbol(6), Some("C"));
+        assert_eq!(symbol(118), Some("Og"));
+        assert_eq!(symbol(119), None);
+        assert_eq!(element("Cl"), Some(17));
+        assert_eq!(element("Xx"), None);
+    }
+
+    #[test]
+    fn element_info() {
+        use crate::periodic::{info, table};
+
+        let carbon = info(6).unwrap();
+        assert_eq!(carbon.name, "Carbon");
+        assert_eq!(carbon.mass, 12.011);
+        assert_eq!(carbon.covalent_radius, Some(0.76));
+        assert_eq!(carbon.color, Some([0x90, 0x90, 0x90]));
+        let oganesson = info(118).unwrap();
+        assert_eq!(oganesson.covalent_radius, None);
+        assert_eq!(oganesson.color, None);
+        assert_eq!(info(0), None);
+        assert_eq!(table().len(), 118);
+    }
+}
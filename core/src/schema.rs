@@ -0,0 +1,110 @@
+use serde_json::{json, Value};
+
+/// JSON Schema of the wire format of `Molecule`, kept by hand next to the serde attributes it
+/// describes.
+pub fn molecule() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Molecule",
+        "type": "object",
+        "required": ["atoms", "bonds", "groups"],
+        "properties": {
+            "atoms": {
+                "description": "Atoms by index; null marks an atom deleted by this layer",
+                "type": "object",
+                "propertyNames": { "pattern": "^[0-9]+$" },
+                "additionalProperties": {
+                    "oneOf": [{ "$ref": "#/$defs/atom" }, { "type": "null" }]
+                }
+            },
+            "bonds": {
+                "description": "Bonds keyed by the indexes of their atoms as \"a-b\", in either order",
+                "type": "object",
+                "propertyNames": { "pattern": "^[0-9]+-[0-9]+$" },
+                "additionalProperties": { "$ref": "#/$defs/bond" }
+            },
+            "groups": {
+                "description": "Group memberships as [atom index, group name] pairs",
+                "type": "array",
+                "items": {
+                    "type": "array",
+                    "prefixItems": [
+                        { "type": "integer", "minimum": 0 },
+                        { "type": "string" }
+                    ],
+                    "items": false,
+                    "minItems": 2
+                }
+            }
+        },
+        "$defs": {
+            "atom": {
+                "type": "object",
+                "required": ["element", "position"],
+                "properties": {
+                    "element": { "type": "integer", "minimum": 1, "maximum": 118 },
+                    "position": {
+                        "type": "array",
+                        "items": { "type": "number" },
+                        "minItems": 3,
+                        "maxItems": 3
+                    }
+                }
+            },
+            "bond": {
+                "description": "Bond kind; a plain number is read as a bond order",
+                "oneOf": [
+                    { "enum": ["Single", "Double", "Triple", "Aromatic", "Dative"] },
+                    {
+                        "type": "object",
+                        "required": ["Custom"],
+                        "properties": { "Custom": { "type": "number" } },
+                        "additionalProperties": false
+                    },
+                    { "type": "number" }
+                ]
+            }
+        }
+    })
+}
+
+mod test {
+    #[test]
+    fn schema_matches_serialized_molecule() {
+        use crate::entity::{Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+        use std::collections::{HashMap, HashSet};
+
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, Some(Atom::new(6, Point3::origin()))),
+                (1, Some(Atom::new(8, Point3::new(1.2, 0., 0.)))),
+                (2, None),
+            ]),
+            HashMap::from([
+                (Pair::new_ordered(0, 1), BondKind::Double),
+                (Pair::new_ordered(1, 2), BondKind::Custom(0.5)),
+            ]),
+            NtoN::from(HashSet::from([(1, "carbonyl".to_string())])),
+        );
+        let value = serde_json::to_value(&molecule).unwrap();
+        assert_eq!(value["bonds"]["1-0"], "Double");
+        assert_eq!(value["bonds"]["2-1"]["Custom"], 0.5);
+        assert_eq!(serde_json::from_value::<Molecule>(value).unwrap(), molecule);
+        let swapped = r#"{"atoms":{},"bonds":{"0-1":2},"groups":[]}"#;
+        let parsed = serde_json::from_str::<Molecule>(swapped).unwrap();
+        assert_eq!(parsed.bonds()[&Pair::new_ordered(1, 0)], BondKind::Double);
+        assert!(
+            serde_json::from_str::<Molecule>(r#"{"atoms":{},"bonds":{"0":1},"groups":[]}"#)
+                .is_err()
+        );
+
+        let schema = super::molecule();
+        let pattern = schema["properties"]["bonds"]["propertyNames"]["pattern"]
+            .as_str()
+            .unwrap();
+        assert_eq!(pattern, "^[0-9]+-[0-9]+$");
+    }
+}
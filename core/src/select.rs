@@ -0,0 +1,82 @@
+use nalgebra::Point3;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::entity::{Atom, Molecule};
+
+/// Atom selection criteria, e.g. `{"and": [{"element": 6}, {"within": [[0, 0, 0], 2.5]}]}`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Predicate {
+    Element(usize),
+    /// Atoms at most the given distance away from a point.
+    Within(Point3<f64>, f64),
+    Group(String),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    fn matches(&self, molecule: &Molecule, idx: usize, atom: &Atom) -> bool {
+        match self {
+            Self::Element(element) => atom.element() == *element,
+            Self::Within(point, radius) => (atom.position() - point).norm() <= *radius,
+            Self::Group(group) => molecule.groups().get_left(&idx).contains(group),
+            Self::And(predicates) => predicates
+                .iter()
+                .all(|predicate| predicate.matches(molecule, idx, atom)),
+            Self::Or(predicates) => predicates
+                .iter()
+                .any(|predicate| predicate.matches(molecule, idx, atom)),
+            Self::Not(predicate) => !predicate.matches(molecule, idx, atom),
+        }
+    }
+}
+
+/// Indexes of the present atoms matching the predicate, sorted.
+pub fn select(molecule: &Molecule, predicate: &Predicate) -> Vec<usize> {
+    let mut selected = molecule
+        .present_atoms()
+        .filter(|(idx, atom)| predicate.matches(molecule, *idx, atom))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    selected.sort_unstable();
+    selected
+}
+
+mod test {
+    #[test]
+    fn select_by_predicate() {
+        use crate::{
+            entity::{Atom, Molecule},
+            select::{select, Predicate},
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::{HashMap, HashSet};
+
+        let atom = |element, x| Some(Atom::new(element, Point3::new(x, 0., 0.)));
+        let molecule = Molecule::new(
+            HashMap::from([
+                (0, atom(6, 0.)),
+                (1, atom(6, 1.5)),
+                (2, atom(8, 2.7)),
+                (3, atom(6, 5.)),
+                (4, None),
+            ]),
+            HashMap::new(),
+            NtoN::from(HashSet::from([(3, "ligand".to_string())])),
+        );
+        let predicate = serde_json::from_str::<Predicate>(
+            r#"{"or": [
+                {"and": [{"element": 6}, {"within": [[0, 0, 0], 2]}]},
+                {"group": "ligand"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(select(&molecule, &predicate), vec![0, 1, 3]);
+        let not_carbon = Predicate::Not(Box::new(Predicate::Element(6)));
+        assert_eq!(select(&molecule, &not_carbon), vec![2]);
+    }
+}
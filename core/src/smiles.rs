@@ -0,0 +1,528 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{entity::Molecule, periodic};
+
+const ORGANIC_SUBSET: [usize; 10] = [5, 6, 7, 8, 9, 15, 16, 17, 35, 53];
+const HYDROGEN: usize = 1;
+
+struct Graph {
+    elements: BTreeMap<usize, usize>,
+    hydrogens: HashMap<usize, usize>,
+    neighbors: HashMap<usize, Vec<(usize, f64)>>,
+    ranks: HashMap<usize, usize>,
+}
+
+impl Graph {
+    fn new(molecule: &Molecule) -> Self {
+        let present = molecule
+            .atoms()
+            .iter()
+            .filter_map(|(idx, atom)| atom.map(|atom| (*idx, atom.element())))
+            .collect::<HashMap<_, _>>();
+        let mut neighbors: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+        for (pair, kind) in molecule.bonds() {
+            let (a, b) = (*pair).into();
+            let order = &kind.order();
+            if a != b && *order > 0. && present.contains_key(&a) && present.contains_key(&b) {
+                neighbors.entry(a).or_default().push((b, *order));
+                neighbors.entry(b).or_default().push((a, *order));
+            }
+        }
+        // Hydrogens bonded to a single heavy atom are folded into their neighbor.
+        let mut hydrogens = HashMap::new();
+        let mut elements = BTreeMap::new();
+        for (idx, element) in &present {
+            let folded = match neighbors.get(idx).map(Vec::as_slice) {
+                Some([(heavy, _)]) if *element == HYDROGEN => {
+                    (present[heavy] != HYDROGEN).then_some(*heavy)
+                }
+                _ => None,
+            };
+            if let Some(heavy) = folded {
+                *hydrogens.entry(heavy).or_insert(0) += 1;
+            } else {
+                elements.insert(*idx, *element);
+            }
+        }
+        for list in neighbors.values_mut() {
+            list.retain(|(idx, _)| elements.contains_key(idx));
+        }
+        let mut graph = Self {
+            elements,
+            hydrogens,
+            neighbors,
+            ranks: HashMap::new(),
+        };
+        graph.ranks = graph.canonical_ranks();
+        for list in graph.neighbors.values_mut() {
+            list.sort_by_key(|(idx, _)| graph.ranks[idx]);
+        }
+        graph
+    }
+
+    /// Ranks of the heavy atoms from their element, implicit hydrogens and degree, refined by
+    /// their bonds so that equal molecules get equal ranks whatever their numbering.
+    fn canonical_ranks(&self) -> HashMap<usize, usize> {
+        let slots = self.elements.keys().copied().collect::<Vec<_>>();
+        let slot_of = slots
+            .iter()
+            .enumerate()
+            .map(|(slot, idx)| (*idx, slot))
+            .collect::<HashMap<_, _>>();
+        let neighbors = slots
+            .iter()
+            .map(|idx| {
+                self.neighbors(*idx)
+                    .iter()
+                    .map(|(other, order)| (slot_of[other], (order * 1000.).round() as u64))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let keys = slots
+            .iter()
+            .zip(&neighbors)
+            .map(|(idx, bonded)| {
+                let hydrogens = self.hydrogens.get(idx).copied().unwrap_or(0);
+                (self.elements[idx], hydrogens, bonded.len())
+            })
+            .collect::<Vec<_>>();
+        let ranks = Partition::new(&keys).ranks(&neighbors);
+        slots.into_iter().zip(ranks).collect()
+    }
+
+    fn neighbors(&self, idx: usize) -> &[(usize, f64)] {
+        self.neighbors.get(&idx).map_or(&[], Vec::as_slice)
+    }
+
+    fn atom_symbol(&self, idx: usize) -> String {
+        let element = self.elements[&idx];
+        let symbol = periodic::symbol(element).unwrap_or("*");
+        let hydrogens = self.hydrogens.get(&idx).copied().unwrap_or(0);
+        if ORGANIC_SUBSET.contains(&element) {
+            symbol.to_string()
+        } else {
+            match hydrogens {
+                0 => format!("[{symbol}]"),
+                1 => format!("[{symbol}H]"),
+                count => format!("[{symbol}H{count}]"),
+            }
+        }
+    }
+}
+
+/// Partition of the heavy atoms into cells of atoms the refinement can't tell apart, stored as
+/// contiguous runs of `atoms` ordered by what told them apart. Once every cell holds a single
+/// atom, positions are ranks that only depend on the graph and not on the numbering.
+struct Partition {
+    /// Atom slots, by position.
+    atoms: Vec<usize>,
+    position: Vec<usize>,
+    /// Cell of every slot.
+    cell: Vec<usize>,
+    /// Range of positions of every cell.
+    cells: Vec<(usize, usize)>,
+    pending: Vec<bool>,
+    splitters: Vec<usize>,
+}
+
+impl Partition {
+    fn new<K: Ord>(keys: &[K]) -> Self {
+        let count = keys.len();
+        let mut atoms = (0..count).collect::<Vec<_>>();
+        atoms.sort_by(|a, b| keys[*a].cmp(&keys[*b]));
+        let mut position = vec![0; count];
+        atoms
+            .iter()
+            .enumerate()
+            .for_each(|(idx, slot)| position[*slot] = idx);
+        let mut partition = Self {
+            atoms,
+            position,
+            cell: vec![0; count],
+            cells: vec![],
+            pending: vec![],
+            splitters: vec![],
+        };
+        let mut start = 0;
+        for end in 1..=count {
+            if end == count || keys[partition.atoms[end]] != keys[partition.atoms[start]] {
+                partition.add_cell(start, end);
+                start = end;
+            }
+        }
+        partition
+    }
+
+    /// Makes the positions `start..end` a new cell, waiting to split the others.
+    fn add_cell(&mut self, start: usize, end: usize) {
+        let id = self.cells.len();
+        self.cells.push((start, end));
+        self.pending.push(true);
+        self.splitters.push(id);
+        for position in start..end {
+            self.cell[self.atoms[position]] = id;
+        }
+    }
+
+    /// Splits cells until the atoms of every cell have the same bonds, counted by order, to
+    /// every cell. Only the parts smaller than the largest one of a split are queued again, so
+    /// an atom changes cell a logarithmic number of times.
+    fn refine(&mut self, neighbors: &[Vec<(usize, u64)>]) {
+        while let Some(splitter) = self.splitters.pop() {
+            self.pending[splitter] = false;
+            let mut keys: HashMap<usize, Vec<u64>> = HashMap::new();
+            let (start, end) = self.cells[splitter];
+            for position in start..end {
+                for (other, order) in &neighbors[self.atoms[position]] {
+                    keys.entry(*other).or_default().push(*order);
+                }
+            }
+            let mut touched: BTreeMap<usize, Vec<(Vec<u64>, usize)>> = BTreeMap::new();
+            for (slot, mut key) in keys {
+                key.sort_unstable();
+                touched
+                    .entry(self.cell[slot])
+                    .or_default()
+                    .push((key, slot));
+            }
+            for (cell, mut members) in touched {
+                let (start, end) = self.cells[cell];
+                members.sort_unstable();
+                if members.len() == end - start && members[0].0 == members[members.len() - 1].0 {
+                    continue;
+                }
+                // Touched atoms move to the end of the cell, untouched ones stay first.
+                let mut tail = end;
+                for (_, slot) in members.iter().rev() {
+                    tail -= 1;
+                    let (from, displaced) = (self.position[*slot], self.atoms[tail]);
+                    self.atoms.swap(from, tail);
+                    self.position[displaced] = from;
+                    self.position[*slot] = tail;
+                }
+                let mut parts = vec![];
+                if tail > start {
+                    parts.push((start, tail));
+                }
+                let mut part_start = tail;
+                for i in 1..=members.len() {
+                    if i == members.len() || members[i].0 != members[i - 1].0 {
+                        parts.push((part_start, tail + i));
+                        part_start = tail + i;
+                    }
+                }
+                // The largest part keeps the cell, so its atoms need no update.
+                let largest = (0..parts.len())
+                    .max_by_key(|i| (parts[*i].1 - parts[*i].0, std::cmp::Reverse(*i)))
+                    .unwrap_or(0);
+                self.cells[cell] = parts[largest];
+                for (i, (part_start, part_end)) in parts.into_iter().enumerate() {
+                    if i != largest {
+                        self.add_cell(part_start, part_end);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Refines, then singles out an atom of the first cell left with several atoms and refines
+    /// again until every atom has its own rank. The atom singled out is the one with the lowest
+    /// slot, which doesn't matter when the atoms of the cell are symmetric, as the refinement
+    /// leaves them in usual molecules.
+    fn ranks(mut self, neighbors: &[Vec<(usize, u64)>]) -> Vec<usize> {
+        let mut start = 0;
+        loop {
+            self.refine(neighbors);
+            while start < self.atoms.len() {
+                let (_, end) = self.cells[self.cell[self.atoms[start]]];
+                if end > start + 1 {
+                    break;
+                }
+                start += 1;
+            }
+            if start == self.atoms.len() {
+                return self.position;
+            }
+            let cell = self.cell[self.atoms[start]];
+            let (_, end) = self.cells[cell];
+            let chosen = (start..end)
+                .min_by_key(|position| self.atoms[*position])
+                .unwrap_or(start);
+            let displaced = self.atoms[start];
+            self.atoms.swap(start, chosen);
+            self.position[displaced] = chosen;
+            self.position[self.atoms[start]] = start;
+            self.cells[cell] = (start + 1, end);
+            self.add_cell(start, start + 1);
+        }
+    }
+}
+
+fn bond_symbol(order: f64) -> &'static str {
+    if (order - order.round()).abs() > 1e-3 {
+        return ":";
+    }
+    match order.round() as usize {
+        2 => "=",
+        3 => "#",
+        4 => "$",
+        _ => "",
+    }
+}
+
+#[derive(Default)]
+struct Traversal {
+    visited: BTreeSet<usize>,
+    children: HashMap<usize, Vec<(usize, f64)>>,
+    openings: HashMap<usize, Vec<(usize, f64)>>,
+    closings: HashMap<usize, Vec<usize>>,
+}
+
+impl Traversal {
+    /// Depth-first walk from `root` with an explicit stack, so long chains can't overflow the
+    /// thread stack. Each frame holds an atom, its parent and the next neighbor to look at.
+    fn visit(&mut self, graph: &Graph, root: usize) {
+        self.visited.insert(root);
+        let mut stack = vec![(root, None, 0)];
+        while let Some((idx, parent, next_neighbor)) = stack.last_mut() {
+            let (idx, parent) = (*idx, *parent);
+            let Some((next, order)) = graph.neighbors(idx).get(*next_neighbor) else {
+                stack.pop();
+                continue;
+            };
+            *next_neighbor += 1;
+            if Some(*next) == parent {
+                continue;
+            }
+            if self.visited.insert(*next) {
+                self.children.entry(idx).or_default().push((*next, *order));
+                stack.push((*next, Some(idx), 0));
+            } else if !self
+                .closings
+                .get(next)
+                .is_some_and(|list| list.contains(&idx))
+            {
+                // `next` is an ancestor still being expanded: it opens the ring, `idx` closes it.
+                self.openings.entry(*next).or_default().push((idx, *order));
+                self.closings.entry(idx).or_default().push(*next);
+            }
+        }
+    }
+}
+
+enum Step {
+    Atom(usize),
+    Text(&'static str),
+}
+
+struct Writer<'a> {
+    graph: &'a Graph,
+    traversal: &'a Traversal,
+    digits: BTreeSet<usize>,
+    open_rings: HashMap<(usize, usize), usize>,
+    output: String,
+}
+
+impl<'a> Writer<'a> {
+    fn ring_label(digit: usize) -> String {
+        if digit < 10 {
+            digit.to_string()
+        } else {
+            format!("%{digit}")
+        }
+    }
+
+    fn write_atom(&mut self, idx: usize) {
+        self.output.push_str(&self.graph.atom_symbol(idx));
+        for closing in self.traversal.closings.get(&idx).into_iter().flatten() {
+            let digit = self
+                .open_rings
+                .remove(&(*closing, idx))
+                .expect("Ring should be opened before closing");
+            self.digits.remove(&digit);
+            self.output.push_str(&Self::ring_label(digit));
+        }
+        for (other, order) in self.traversal.openings.get(&idx).into_iter().flatten() {
+            let digit = (1..).find(|digit| !self.digits.contains(digit)).unwrap();
+            self.digits.insert(digit);
+            self.open_rings.insert((idx, *other), digit);
+            self.output.push_str(bond_symbol(*order));
+            self.output.push_str(&Self::ring_label(digit));
+        }
+    }
+
+    /// Writes the tree below `root` in the traversal order, every child but the last one as a
+    /// branch. The steps left are kept on an explicit stack, in reverse.
+    fn write(&mut self, root: usize) {
+        let mut steps = vec![Step::Atom(root)];
+        while let Some(step) = steps.pop() {
+            let idx = match step {
+                Step::Text(text) => {
+                    self.output.push_str(text);
+                    continue;
+                }
+                Step::Atom(idx) => idx,
+            };
+            self.write_atom(idx);
+            let children = self
+                .traversal
+                .children
+                .get(&idx)
+                .map_or(&[][..], Vec::as_slice);
+            if let Some(((last, last_order), branches)) = children.split_last() {
+                steps.push(Step::Atom(*last));
+                steps.push(Step::Text(bond_symbol(*last_order)));
+                for (child, order) in branches.iter().rev() {
+                    steps.push(Step::Text(")"));
+                    steps.push(Step::Atom(*child));
+                    steps.push(Step::Text(bond_symbol(*order)));
+                    steps.push(Step::Text("("));
+                }
+            }
+        }
+    }
+}
+
+/// Writes the present atoms of the molecule as a canonical SMILES string in Kekulé form.
+/// Hydrogens bonded to a heavy atom are written implicitly and fragments are separated by `.`.
+/// Atoms are ranked by `Graph::canonical_ranks` and the traversal starts from the lowest rank
+/// and visits neighbors by rank, so renumbering the atoms doesn't change the output.
+pub fn to_smiles(molecule: &Molecule) -> String {
+    let graph = Graph::new(molecule);
+    let mut traversal = Traversal::default();
+    let mut roots = vec![];
+    let mut ranked = graph.elements.keys().copied().collect::<Vec<_>>();
+    ranked.sort_by_key(|idx| graph.ranks[idx]);
+    for idx in &ranked {
+        if !traversal.visited.contains(idx) {
+            roots.push(*idx);
+            traversal.visit(&graph, *idx);
+        }
+    }
+    let mut writer = Writer {
+        graph: &graph,
+        traversal: &traversal,
+        digits: BTreeSet::new(),
+        open_rings: HashMap::new(),
+        output: String::new(),
+    };
+    for (i, root) in roots.into_iter().enumerate() {
+        if i > 0 {
+            writer.output.push('.');
+        }
+        writer.write(root);
+    }
+    writer.output
+}
+
+mod test {
+    #[cfg(test)]
+    fn molecule(elements: &[usize], bonds: &[(usize, usize, f64)]) -> crate::entity::Molecule {
+        use crate::entity::{Atom, BondKind, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use pair::Pair;
+
+        Molecule::new(
+            elements
+                .iter()
+                .enumerate()
+                .map(|(idx, element)| (idx, Some(Atom::new(*element, Point3::origin()))))
+                .collect(),
+            bonds
+                .iter()
+                .map(|(a, b, order)| (Pair::new_ordered(*a, *b), BondKind::from(*order)))
+                .collect(),
+            NtoN::new(),
+        )
+    }
+
+    #[test]
+    fn ethane() {
+        use crate::smiles::to_smiles;
+
+        assert_eq!(to_smiles(&molecule(&[6, 6], &[(0, 1, 1.)])), "CC");
+        let explicit = molecule(
+            &[6, 6, 1, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 1.),
+                (0, 2, 1.),
+                (0, 3, 1.),
+                (0, 4, 1.),
+                (1, 5, 1.),
+                (1, 6, 1.),
+                (1, 7, 1.),
+            ],
+        );
+        assert_eq!(to_smiles(&explicit), "CC");
+    }
+
+    #[test]
+    fn benzene() {
+        use crate::smiles::to_smiles;
+
+        let benzene = molecule(
+            &[6; 6],
+            &[
+                (0, 1, 2.),
+                (1, 2, 1.),
+                (2, 3, 2.),
+                (3, 4, 1.),
+                (4, 5, 2.),
+                (5, 0, 1.),
+            ],
+        );
+        assert_eq!(to_smiles(&benzene), "C=1C=CC=CC1");
+    }
+
+    #[test]
+    fn branched_alcohol() {
+        use crate::smiles::to_smiles;
+
+        let isopropanol = molecule(&[6, 6, 6, 8], &[(0, 1, 1.), (1, 2, 1.), (1, 3, 1.)]);
+        assert_eq!(to_smiles(&isopropanol), "CC(C)O");
+        let salt = molecule(&[11, 17], &[]);
+        assert_eq!(to_smiles(&salt), "[Na].Cl");
+    }
+
+    #[test]
+    fn canonical_across_numberings() {
+        use crate::smiles::to_smiles;
+
+        // Isobutanol, 2-methylpropan-1-ol, numbered from either end.
+        let isobutanol = molecule(
+            &[6, 6, 6, 6, 8],
+            &[(0, 1, 1.), (1, 2, 1.), (1, 3, 1.), (3, 4, 1.)],
+        );
+        let renumbered = molecule(
+            &[8, 6, 6, 6, 6],
+            &[(0, 1, 1.), (1, 2, 1.), (2, 3, 1.), (2, 4, 1.)],
+        );
+        assert_eq!(to_smiles(&isobutanol), to_smiles(&renumbered));
+        let benzene = |offset: usize| {
+            let bonds = (0..6)
+                .map(|idx| {
+                    (
+                        (idx + offset) % 6,
+                        (idx + offset + 1) % 6,
+                        [2., 1.][idx % 2],
+                    )
+                })
+                .collect::<Vec<_>>();
+            molecule(&[6; 6], &bonds)
+        };
+        assert_eq!(to_smiles(&benzene(0)), to_smiles(&benzene(3)));
+        let salt = molecule(&[17, 11], &[]);
+        assert_eq!(to_smiles(&salt), "[Na].Cl");
+    }
+
+    #[test]
+    fn long_chain() {
+        use crate::smiles::to_smiles;
+
+        let count = 100_000;
+        let bonds = (1..count).map(|idx| (idx - 1, idx, 1.)).collect::<Vec<_>>();
+        let chain = molecule(&vec![6; count], &bonds);
+        assert_eq!(to_smiles(&chain), "C".repeat(count));
+    }
+}
@@ -0,0 +1,471 @@
+use std::f64::consts::PI;
+
+use nalgebra::{Matrix3, Rotation3, SymmetricEigen, Transform3, Translation3, Unit, Vector3};
+
+use crate::{entity::Molecule, error::LMECoreError, periodic};
+
+/// Highest rotation order searched for.
+const MAX_ORDER: usize = 6;
+const PARALLEL_TOLERANCE: f64 = 1e-3;
+pub const POINT_GROUP_MAX_ATOMS: usize = 500;
+
+/// Present atoms centered on their average position weighted by atomic number, which every
+/// symmetry operation keeps in place just like the center of mass.
+struct Frame {
+    atoms: Vec<(usize, Vector3<f64>)>,
+    tolerance: f64,
+}
+
+impl Frame {
+    fn new(molecule: &Molecule, tolerance: f64) -> Self {
+        let atoms = molecule
+            .atoms()
+            .values()
+            .flatten()
+            .map(|atom| (atom.element(), atom.position().coords))
+            .collect::<Vec<_>>();
+        let weight = atoms
+            .iter()
+            .map(|(element, _)| *element as f64)
+            .sum::<f64>();
+        let center = atoms
+            .iter()
+            .map(|(element, position)| position * *element as f64)
+            .sum::<Vector3<f64>>()
+            / weight.max(1.);
+        Self {
+            atoms: atoms
+                .into_iter()
+                .map(|(element, position)| (element, position - center))
+                .collect(),
+            tolerance,
+        }
+    }
+
+    fn invariant(&self, operation: impl Fn(&Vector3<f64>) -> Vector3<f64>) -> bool {
+        self.atoms.iter().all(|(element, position)| {
+            let image = operation(position);
+            self.atoms.iter().any(|(other, target)| {
+                other == element && (image - target).norm() <= self.tolerance
+            })
+        })
+    }
+
+    fn rotation(&self, axis: &Unit<Vector3<f64>>, order: usize) -> bool {
+        let rotation = Rotation3::from_axis_angle(axis, 2. * PI / order as f64);
+        self.invariant(|position| rotation * position)
+    }
+
+    fn reflection(&self, normal: &Unit<Vector3<f64>>) -> bool {
+        self.invariant(|position| reflect(position, normal))
+    }
+
+    fn improper_rotation(&self, axis: &Unit<Vector3<f64>>, order: usize) -> bool {
+        let rotation = Rotation3::from_axis_angle(axis, 2. * PI / order as f64);
+        self.invariant(|position| reflect(&(rotation * position), axis))
+    }
+
+    fn any_reflection<'a, I>(&self, normals: I) -> bool
+    where
+        I: IntoIterator<Item = &'a Unit<Vector3<f64>>>,
+    {
+        normals.into_iter().any(|normal| self.reflection(normal))
+    }
+
+    fn inversion(&self) -> bool {
+        self.invariant(|position| -position)
+    }
+
+    fn rotation_order(&self, axis: &Unit<Vector3<f64>>) -> usize {
+        (2..=MAX_ORDER)
+            .rev()
+            .find(|order| self.rotation(axis, *order))
+            .unwrap_or(1)
+    }
+
+    fn linear(&self) -> bool {
+        let farthest = self
+            .atoms
+            .iter()
+            .map(|(_, position)| position)
+            .max_by(|a, b| a.norm().total_cmp(&b.norm()));
+        match farthest.and_then(|position| Unit::try_new(*position, self.tolerance)) {
+            Some(axis) => self
+                .atoms
+                .iter()
+                .all(|(_, position)| position.cross(&axis).norm() <= self.tolerance),
+            None => true,
+        }
+    }
+
+    /// Directions symmetry elements may lie along: principal axes of inertia, atoms, midpoints
+    /// and differences of same-element pairs, and normals of planes through two atoms or
+    /// through three atoms of the rarest element.
+    fn candidates(&self) -> Vec<Unit<Vector3<f64>>> {
+        let mut candidates = vec![];
+        let mut push = |direction: Vector3<f64>| {
+            if let Some(direction) = Unit::try_new(direction, 1e-6) {
+                if candidates.iter().all(|other: &Unit<Vector3<f64>>| {
+                    direction.dot(other).abs() < 1. - PARALLEL_TOLERANCE
+                }) {
+                    candidates.push(direction)
+                }
+            }
+        };
+        let inertia = self
+            .atoms
+            .iter()
+            .map(|(element, position)| {
+                (Matrix3::identity() * position.norm_squared() - position * position.transpose())
+                    * *element as f64
+            })
+            .sum::<Matrix3<f64>>();
+        SymmetricEigen::new(inertia)
+            .eigenvectors
+            .column_iter()
+            .for_each(|axis| push(axis.into_owned()));
+        for (i, (element, a)) in self.atoms.iter().enumerate() {
+            push(*a);
+            for (other, b) in &self.atoms[i + 1..] {
+                push(a.cross(b));
+                if element == other {
+                    push(a + b);
+                    push(a - b);
+                }
+            }
+        }
+        let off_center = self
+            .atoms
+            .iter()
+            .filter(|(_, position)| position.norm() > self.tolerance);
+        let rarest = off_center
+            .clone()
+            .map(|(element, _)| *element)
+            .min_by_key(|element| {
+                self.atoms
+                    .iter()
+                    .filter(|(other, _)| other == element)
+                    .count()
+            });
+        let ring = off_center
+            .filter(|(element, _)| Some(*element) == rarest)
+            .map(|(_, position)| position)
+            .collect::<Vec<_>>();
+        for (i, a) in ring.iter().enumerate() {
+            for (j, b) in ring.iter().enumerate().skip(i + 1) {
+                for c in &ring[j + 1..] {
+                    push((*b - *a).cross(&(*c - *a)));
+                }
+            }
+        }
+        candidates
+    }
+}
+
+fn reflect(position: &Vector3<f64>, normal: &Unit<Vector3<f64>>) -> Vector3<f64> {
+    position - normal.as_ref() * (2. * position.dot(normal))
+}
+
+/// Masses and positions of the present atoms, at least two of them.
+fn weighted_atoms(molecule: &Molecule) -> Result<Vec<(f64, Vector3<f64>)>, LMECoreError> {
+    let atoms = molecule
+        .atoms()
+        .values()
+        .flatten()
+        .map(|atom| {
+            periodic::mass(atom.element())
+                .map(|mass| (mass, atom.position().coords))
+                .ok_or_else(|| {
+                    LMECoreError::InvalidAtom(format!("No mass for element {}", atom.element()))
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if atoms.len() < 2 {
+        return Err(LMECoreError::InvalidSelection(
+            "Principal axes need at least 2 atoms".to_string(),
+        ));
+    }
+    Ok(atoms)
+}
+
+fn mass_center(atoms: &[(f64, Vector3<f64>)]) -> Vector3<f64> {
+    let total = atoms.iter().map(|(mass, _)| mass).sum::<f64>();
+    atoms
+        .iter()
+        .map(|(mass, position)| position * *mass)
+        .sum::<Vector3<f64>>()
+        / total
+}
+
+/// Principal moments of inertia in amu·Å², ascending, and the matching principal axes as the
+/// columns of the matrix, about the center of mass of the present atoms.
+pub fn principal_axes(molecule: &Molecule) -> Result<(Vector3<f64>, Matrix3<f64>), LMECoreError> {
+    let atoms = weighted_atoms(molecule)?;
+    let center = mass_center(&atoms);
+    let inertia = atoms
+        .iter()
+        .map(|(mass, position)| {
+            let position = position - center;
+            (Matrix3::identity() * position.norm_squared() - position * position.transpose())
+                * *mass
+        })
+        .sum::<Matrix3<f64>>();
+    let eigen = SymmetricEigen::new(inertia);
+    let mut order = [0, 1, 2];
+    order.sort_by(|a, b| eigen.eigenvalues[*a].total_cmp(&eigen.eigenvalues[*b]));
+    Ok((
+        Vector3::from_fn(|i, _| eigen.eigenvalues[order[i]]),
+        Matrix3::from_fn(|row, col| eigen.eigenvectors[(row, order[col])]),
+    ))
+}
+
+/// Moves the center of mass to the origin and turns the axis of largest moment to Z and the
+/// second one to Y. The eigenvectors are orthonormal even for equal moments, so linear molecules
+/// and spherical tops get an arbitrary but valid rotation among the equivalent ones.
+pub fn principal_alignment(molecule: &Molecule) -> Result<Transform3<f64>, LMECoreError> {
+    let center = mass_center(&weighted_atoms(molecule)?);
+    let (_, axes) = principal_axes(molecule)?;
+    let mut rotation = axes.transpose();
+    if rotation.determinant() < 0. {
+        rotation.row_mut(0).neg_mut();
+    }
+    let rotation = Rotation3::from_matrix_unchecked(rotation);
+    Ok(Transform3::from_matrix_unchecked(
+        (rotation * Translation3::from(-center)).to_homogeneous(),
+    ))
+}
+
+/// Schoenflies symbol of the point group of the present atoms, positions matching when they are
+/// at most `tolerance` apart. Rotation axes are searched up to order 6 among candidate
+/// directions built from the atoms, which costs O(n⁴) in the number of atoms: molecules of more
+/// than `POINT_GROUP_MAX_ATOMS` atoms are refused.
+pub fn point_group(molecule: &Molecule, tolerance: f64) -> Result<String, LMECoreError> {
+    let frame = Frame::new(molecule, tolerance);
+    if frame.atoms.len() > POINT_GROUP_MAX_ATOMS {
+        return Err(LMECoreError::TooManyAtoms(POINT_GROUP_MAX_ATOMS));
+    }
+    Ok(schoenflies(&frame))
+}
+
+fn schoenflies(frame: &Frame) -> String {
+    if frame.atoms.len() < 2 {
+        return "Kh".to_string();
+    }
+    if frame.linear() {
+        return if frame.inversion() { "D∞h" } else { "C∞v" }.to_string();
+    }
+    let candidates = frame.candidates();
+    let orders = candidates
+        .iter()
+        .map(|axis| frame.rotation_order(axis))
+        .collect::<Vec<_>>();
+    let count = |order: usize| {
+        orders
+            .iter()
+            .filter(|item| **item > 1 && *item % order == 0)
+            .count()
+    };
+    if count(3) > 1 {
+        let group = if count(5) > 1 {
+            if frame.inversion() {
+                "Ih"
+            } else {
+                "I"
+            }
+        } else if count(4) > 0 {
+            if frame.inversion() {
+                "Oh"
+            } else {
+                "O"
+            }
+        } else if frame.inversion() {
+            "Th"
+        } else if frame.any_reflection(&candidates) {
+            "Td"
+        } else {
+            "T"
+        };
+        return group.to_string();
+    }
+    let order = orders.iter().copied().max().unwrap_or(1);
+    if order < 2 {
+        let group = if frame.any_reflection(&candidates) {
+            "Cs"
+        } else if frame.inversion() {
+            "Ci"
+        } else {
+            "C1"
+        };
+        return group.to_string();
+    }
+    // Among equivalent axes, the one carrying an improper rotation is the principal axis of
+    // groups like D2d.
+    let mut principal = candidates
+        .iter()
+        .zip(&orders)
+        .filter(|(_, item)| **item == order)
+        .map(|(axis, _)| axis);
+    let axis = principal
+        .clone()
+        .find(|axis| frame.improper_rotation(axis, 2 * order))
+        .or_else(|| principal.next())
+        .expect("Should never hint this condition");
+    let perpendicular = candidates
+        .iter()
+        .filter(|other| other.dot(axis).abs() < PARALLEL_TOLERANCE);
+    let horizontal = frame.reflection(axis);
+    if perpendicular.clone().any(|other| frame.rotation(other, 2)) {
+        if horizontal {
+            format!("D{order}h")
+        } else if frame.any_reflection(perpendicular.clone()) {
+            format!("D{order}d")
+        } else {
+            format!("D{order}")
+        }
+    } else if horizontal {
+        format!("C{order}h")
+    } else if frame.any_reflection(perpendicular.clone()) {
+        format!("C{order}v")
+    } else if frame.improper_rotation(axis, 2 * order) {
+        format!("S{}", 2 * order)
+    } else {
+        format!("C{order}")
+    }
+}
+
+mod test {
+    #[cfg(test)]
+    fn molecule(atoms: &[(usize, [f64; 3])]) -> crate::entity::Molecule {
+        use crate::entity::{Atom, Molecule};
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        Molecule::new(
+            atoms
+                .iter()
+                .enumerate()
+                .map(|(idx, (element, [x, y, z]))| {
+                    (idx, Some(Atom::new(*element, Point3::new(*x, *y, *z))))
+                })
+                .collect(),
+            HashMap::new(),
+            NtoN::new(),
+        )
+    }
+
+    #[test]
+    fn water_and_methane() {
+        use crate::symmetry::point_group;
+
+        let water = molecule(&[
+            (8, [0., 0., 0.1173]),
+            (1, [0., 0.7572, -0.4692]),
+            (1, [0., -0.7572, -0.4692]),
+        ]);
+        assert_eq!(point_group(&water, 0.05).unwrap(), "C2v");
+        let d = 0.629;
+        let methane = molecule(&[
+            (6, [0., 0., 0.]),
+            (1, [d, d, d]),
+            (1, [d, -d, -d]),
+            (1, [-d, d, -d]),
+            (1, [-d, -d, d]),
+        ]);
+        assert_eq!(point_group(&methane, 0.05).unwrap(), "Td");
+        let bent = molecule(&[(8, [0., 0., 0.]), (1, [0.96, 0., 0.]), (9, [0., 1.4, 0.3])]);
+        assert_eq!(point_group(&bent, 0.05).unwrap(), "Cs");
+    }
+
+    #[test]
+    fn benzene_and_ethane() {
+        use crate::{
+            error::LMECoreError,
+            symmetry::{point_group, POINT_GROUP_MAX_ATOMS},
+        };
+
+        let ring = |element, radius: f64, z, phase: f64| {
+            (0..6).map(move |k| {
+                let angle = phase + std::f64::consts::PI * k as f64 / 3.;
+                (element, [radius * angle.cos(), radius * angle.sin(), z])
+            })
+        };
+        let benzene = ring(6, 1.39, 0., 0.)
+            .chain(ring(1, 2.48, 0., 0.))
+            .collect::<Vec<_>>();
+        assert_eq!(point_group(&molecule(&benzene), 0.05).unwrap(), "D6h");
+        let staggered = (0..3)
+            .map(|k| {
+                let angle = 2. * std::f64::consts::PI * k as f64 / 3.;
+                (1, [angle.cos(), angle.sin(), 1.2])
+            })
+            .chain((0..3).map(|k| {
+                let angle = 2. * std::f64::consts::PI * k as f64 / 3. + std::f64::consts::PI / 3.;
+                (1, [angle.cos(), angle.sin(), -1.2])
+            }))
+            .chain([(6, [0., 0., 0.77]), (6, [0., 0., -0.77])])
+            .collect::<Vec<_>>();
+        assert_eq!(point_group(&molecule(&staggered), 0.05).unwrap(), "D3d");
+        let sf6 = [
+            [1., 0., 0.],
+            [-1., 0., 0.],
+            [0., 1., 0.],
+            [0., -1., 0.],
+            [0., 0., 1.],
+            [0., 0., -1.],
+        ]
+        .into_iter()
+        .map(|position| (9, position))
+        .chain([(16, [0., 0., 0.])])
+        .collect::<Vec<_>>();
+        assert_eq!(point_group(&molecule(&sf6), 0.05).unwrap(), "Oh");
+        let chain = (0..=POINT_GROUP_MAX_ATOMS)
+            .map(|k| (6, [1.5 * k as f64, 0., 0.]))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            point_group(&molecule(&chain), 0.05),
+            Err(LMECoreError::TooManyAtoms(POINT_GROUP_MAX_ATOMS))
+        ));
+    }
+
+    #[test]
+    fn linear_principal_axes() {
+        use crate::symmetry::principal_axes;
+
+        let co2 = molecule(&[(8, [-1.16, 0., 0.]), (6, [0., 0., 0.]), (8, [1.16, 0., 0.])]);
+        let (moments, axes) = principal_axes(&co2).unwrap();
+        assert!(moments[0].abs() < 1e-9);
+        assert!((moments[1] - moments[2]).abs() < 1e-9);
+        assert!((moments[1] - 2. * 15.999 * 1.16 * 1.16).abs() < 1e-6);
+        assert!((axes.column(0).x.abs() - 1.).abs() < 1e-9);
+        assert!(principal_axes(&molecule(&[(6, [0., 0., 0.])])).is_err());
+    }
+
+    #[test]
+    fn align_water() {
+        use crate::{
+            entity::Layer,
+            symmetry::{principal_alignment, principal_axes},
+        };
+        use nalgebra::Vector3;
+
+        let water = molecule(&[
+            (8, [1.0, 2.0, 3.0]),
+            (1, [1.5, 2.6, 3.4]),
+            (1, [0.3, 2.2, 3.6]),
+        ]);
+        let transform = principal_alignment(&water).unwrap();
+        let aligned = Layer::Transform(transform).filter(water.clone()).unwrap();
+        let (moments, axes) = principal_axes(&aligned).unwrap();
+        let (original, _) = principal_axes(&water).unwrap();
+        assert!((moments - original).norm() < 1e-9);
+        for (col, axis) in [Vector3::x(), Vector3::y(), Vector3::z()]
+            .iter()
+            .enumerate()
+        {
+            assert!((axes.column(col).dot(axis).abs() - 1.).abs() < 1e-9);
+        }
+        assert!(transform.matrix().iter().all(|x| x.is_finite()));
+        assert!((transform.matrix().fixed_view::<3, 3>(0, 0).determinant() - 1.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashMap, fmt::Write, str::Lines};
+
+use n_to_n::NtoN;
+use nalgebra::Point3;
+use rayon::prelude::*;
+
+use crate::{
+    entity::{Atom, Molecule},
+    error::LMECoreError,
+    periodic,
+};
+
+pub const DEFAULT_PRECISION: usize = 6;
+
+/// Writes the present atoms of the molecule in index order as an XYZ frame, with coordinates
+/// rounded to `precision` decimals. Elements without a symbol are written as `X`.
+pub fn to_xyz(molecule: &Molecule, comment: &str, precision: usize) -> String {
+    let mut atoms = molecule.present_atoms().collect::<Vec<_>>();
+    atoms.sort_by_key(|(idx, _)| *idx);
+    let mut output = format!("{}\n{}\n", atoms.len(), comment.replace('\n', " "));
+    for (_, atom) in atoms {
+        let symbol = periodic::symbol(atom.element()).unwrap_or("X");
+        let position = atom.position();
+        writeln!(
+            output,
+            "{symbol} {:.*} {:.*} {:.*}",
+            precision, position.x, precision, position.y, precision, position.z
+        )
+        .expect("Writing to a String never fails");
+    }
+    output
+}
+
+fn parse_frame(lines: &mut Lines, frame: usize) -> Result<Vec<Atom>, LMECoreError> {
+    let error = |message: String| LMECoreError::InvalidXyz(frame, message);
+    let count = lines
+        .next()
+        .and_then(|line| line.trim().parse::<usize>().ok())
+        .ok_or_else(|| error("Expected an atom count".to_string()))?;
+    lines
+        .next()
+        .ok_or_else(|| error("Missing comment line".to_string()))?;
+    (0..count)
+        .map(|idx| {
+            let line = lines
+                .next()
+                .ok_or_else(|| error(format!("Expected {count} atoms, found {idx}")))?;
+            let mut fields = line.split_whitespace();
+            let element = fields
+                .next()
+                .and_then(|field| field.parse().ok().or_else(|| periodic::element(field)))
+                .ok_or_else(|| error(format!("Unknown element on line: {line}")))?;
+            let mut coordinates = fields.map(str::parse::<f64>);
+            let mut coordinate = || {
+                coordinates
+                    .next()
+                    .and_then(Result::ok)
+                    .ok_or_else(|| error(format!("Invalid coordinates on line: {line}")))
+            };
+            let position = Point3::new(coordinate()?, coordinate()?, coordinate()?);
+            Atom::try_new(element, position).map_err(|_| error(format!("Invalid atom: {line}")))
+        })
+        .collect()
+}
+
+/// Parses a single XYZ frame, atoms being numbered from 0 in file order.
+pub fn parse_xyz(data: &str) -> Result<Molecule, LMECoreError> {
+    let atoms = parse_frame(&mut data.lines(), 0)?;
+    Ok(to_molecule(atoms))
+}
+
+/// Parses a multi-frame XYZ file. Every frame must list the same elements in the same order as
+/// the first one, otherwise the error carries the index of the offending frame.
+pub fn parse_xyz_trajectory(data: &str) -> Result<Vec<Molecule>, LMECoreError> {
+    let mut lines = data.lines();
+    let mut frames: Vec<Vec<Atom>> = vec![];
+    while lines.clone().any(|line| !line.trim().is_empty()) {
+        while lines
+            .clone()
+            .next()
+            .is_some_and(|line| line.trim().is_empty())
+        {
+            lines.next();
+        }
+        let frame = frames.len();
+        let atoms = parse_frame(&mut lines, frame)?;
+        if let Some(first) = frames.first() {
+            let elements = |atoms: &[Atom]| atoms.iter().map(Atom::element).collect::<Vec<_>>();
+            if elements(first) != elements(&atoms) {
+                return Err(LMECoreError::InvalidXyz(
+                    frame,
+                    "Atoms differ from the first frame".to_string(),
+                ));
+            }
+        }
+        frames.push(atoms);
+    }
+    Ok(frames.into_iter().map(to_molecule).collect())
+}
+
+fn to_molecule(atoms: Vec<Atom>) -> Molecule {
+    Molecule::new(
+        atoms
+            .into_iter()
+            .enumerate()
+            .map(|(idx, atom)| (idx, Some(atom)))
+            .collect(),
+        HashMap::new(),
+        NtoN::new(),
+    )
+}
+
+mod test {
+    #[test]
+    fn xyz_precision() {
+        use crate::{
+            entity::{Atom, Molecule},
+            xyz::to_xyz,
+        };
+        use n_to_n::NtoN;
+        use nalgebra::Point3;
+        use std::collections::HashMap;
+
+        let molecule = Molecule::new(
+            HashMap::from([
+                (2, Some(Atom::new(8, Point3::new(0.1 + 0.2, -1., 2.5)))),
+                (0, Some(Atom::new(1, Point3::origin()))),
+                (1, None),
+            ]),
+            HashMap::new(),
+            NtoN::new(),
+        );
+        assert_eq!(
+            to_xyz(&molecule, "water\nfragment", 3),
+            "2\nwater fragment\nH 0.000 0.000 0.000\nO 0.300 -1.000 2.500\n"
+        );
+        assert!(to_xyz(&molecule, "", 6).ends_with("O 0.300000 -1.000000 2.500000\n"));
+    }
+
+    #[test]
+    fn xyz_trajectory() {
+        use crate::{
+            error::LMECoreError,
+            xyz::{parse_xyz, parse_xyz_trajectory, to_xyz},
+        };
+
+        let frame = "2\nwater\nO 0 0 0\n1 0.96 0 0\n";
+        let molecule = parse_xyz(frame).unwrap();
+        assert_eq!(molecule.get_atom(1).unwrap().element(), 1);
+        assert_eq!(parse_xyz(&to_xyz(&molecule, "water", 6)).unwrap(), molecule);
+
+        let trajectory = format!("{frame}{}\n", frame.replace("0.96", "1.01"));
+        let frames = parse_xyz_trajectory(&trajectory).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].get_atom(1).unwrap().position().x, 1.01);
+
+        let swapped = format!("{frame}2\n\nH 0 0 0\nO 1 0 0\n");
+        assert!(matches!(
+            parse_xyz_trajectory(&swapped),
+            Err(LMECoreError::InvalidXyz(1, _))
+        ));
+        let truncated = format!("{frame}{frame}2\n\nO 0 0 0\n");
+        assert!(matches!(
+            parse_xyz_trajectory(&truncated),
+            Err(LMECoreError::InvalidXyz(2, _))
+        ));
+    }
+}
@@ -1,9 +1,9 @@
 use std::collections::hash_set::IntoIter;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct NtoN<L: Eq + Hash, R: Eq + Hash>(HashSet<(L, R)>);
@@ -68,6 +68,16 @@ impl<L: Sync + Send + Eq + Hash + Clone, R: Sync + Send + Eq + Hash + Clone> Nto
         self.data_mut().extend(iter)
     }
 
+    /// Rewrites every left value through `f`; pairs ending up equal merge into one.
+    pub fn map_left<F: Fn(&L) -> L>(&mut self, f: F) {
+        self.0 = self.0.drain().map(|(l, r)| (f(&l), r)).collect()
+    }
+
+    /// Rewrites every right value through `f`; pairs ending up equal merge into one.
+    pub fn map_right<F: Fn(&R) -> R>(&mut self, f: F) {
+        self.0 = self.0.drain().map(|(l, r)| (l, f(&r))).collect()
+    }
+
     pub fn overlay_to(&self, other: &Self) -> Self {
         let mut overlayed = other.clone();
         overlayed.extend(self.data().clone());
@@ -81,9 +91,9 @@ impl<L: Eq + Hash, R: Eq + Hash> From<HashSet<(L, R)>> for NtoN<L, R> {
     }
 }
 
-impl<L: Eq + Hash, R: Eq + Hash> Into<HashSet<(L, R)>> for NtoN<L, R> {
-    fn into(self) -> HashSet<(L, R)> {
-        self.0
+impl<L: Eq + Hash, R: Eq + Hash> From<NtoN<L, R>> for HashSet<(L, R)> {
+    fn from(value: NtoN<L, R>) -> Self {
+        value.0
     }
 }
 
@@ -94,3 +104,85 @@ impl<L: Eq + Hash, R: Eq + Hash> IntoIterator for NtoN<L, R> {
         self.0.into_iter()
     }
 }
+
+/// Serializes an `NtoN` grouped by its left values, e.g. `{"ring1": [1, 2, 3]}` instead of
+/// `[["ring1", 1], ["ring1", 2], ["ring1", 3]]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Grouped<L: Eq + Hash, R: Eq + Hash>(pub NtoN<L, R>);
+
+impl<L: Eq + Hash + Serialize, R: Eq + Hash + Serialize> Serialize for Grouped<L, R> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut groups: HashMap<&L, Vec<&R>> = HashMap::new();
+        for (l, r) in &self.0 .0 {
+            groups.entry(l).or_default().push(r);
+        }
+        groups.serialize(serializer)
+    }
+}
+
+impl<'de, L, R> Deserialize<'de> for Grouped<L, R>
+where
+    L: Eq + Hash + Clone + Deserialize<'de>,
+    R: Eq + Hash + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let groups = HashMap::<L, Vec<R>>::deserialize(deserializer)?;
+        let pairs = groups
+            .into_iter()
+            .flat_map(|(l, rs)| rs.into_iter().map(move |r| (l.clone(), r)))
+            .collect::<HashSet<_>>();
+        Ok(Self(NtoN(pairs)))
+    }
+}
+
+impl<L: Eq + Hash, R: Eq + Hash> From<NtoN<L, R>> for Grouped<L, R> {
+    fn from(value: NtoN<L, R>) -> Self {
+        Self(value)
+    }
+}
+
+impl<L: Eq + Hash, R: Eq + Hash> From<Grouped<L, R>> for NtoN<L, R> {
+    fn from(value: Grouped<L, R>) -> Self {
+        value.0
+    }
+}
+
+mod test {
+    #[test]
+    fn map_left_offsets_keys() {
+        use crate::NtoN;
+        use std::collections::HashSet;
+
+        let mut groups = NtoN::from(HashSet::from([
+            (1, "ring".to_string()),
+            (2, "ring".to_string()),
+            (2, "chain".to_string()),
+        ]));
+        groups.map_left(|idx| idx + 10);
+        assert_eq!(
+            groups.get_right(&"ring".to_string()),
+            HashSet::from([11, 12])
+        );
+        assert_eq!(groups.get_left(&12).len(), 2);
+        groups.map_right(|_| "all".to_string());
+        groups.map_left(|_| 0);
+        assert_eq!(groups.data(), &HashSet::from([(0, "all".to_string())]));
+    }
+
+    #[test]
+    fn grouped_round_trip() {
+        use crate::{Grouped, NtoN};
+        use std::collections::HashSet;
+
+        let groups = NtoN::from(HashSet::from([
+            ("ring1".to_string(), 1),
+            ("ring1".to_string(), 2),
+            ("chain".to_string(), 3),
+        ]));
+        let json = serde_json::to_value(Grouped(groups.clone())).unwrap();
+        assert_eq!(json["chain"], serde_json::json!([3]));
+        assert_eq!(json["ring1"].as_array().unwrap().len(), 2);
+        let restored: Grouped<String, usize> = serde_json::from_value(json).unwrap();
+        assert_eq!(NtoN::from(restored), groups);
+    }
+}
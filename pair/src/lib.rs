@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 )]
 pub struct Pair<T>(T, T);
 
-impl<T:Add<Output = T> + Copy> Pair<T> {
+impl<T: Add<Output = T> + Copy> Pair<T> {
     pub fn offset(self, offset: T) -> Self {
         let Self(a, b) = self;
         Self(a + offset, b + offset)
@@ -20,6 +20,12 @@ impl<T> Pair<T> {
     }
 }
 
+impl<T> From<Pair<T>> for (T, T) {
+    fn from(Pair(a, b): Pair<T>) -> Self {
+        (a, b)
+    }
+}
+
 impl<T: PartialOrd> Pair<T> {
     pub fn new_ordered(a: T, b: T) -> Self {
         if a >= b {
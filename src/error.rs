@@ -0,0 +1,37 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use lme_core::error::LMECoreError;
+
+pub struct ServerError(pub LMECoreError);
+
+impl From<LMECoreError> for ServerError {
+    fn from(value: LMECoreError) -> Self {
+        Self(value)
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            LMECoreError::NoSuchStack | LMECoreError::NoSuchAtom | LMECoreError::NoSuchId => {
+                StatusCode::NOT_FOUND
+            }
+            LMECoreError::PluginLayerError(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
+            LMECoreError::PluginNotFound(_) => StatusCode::FAILED_DEPENDENCY,
+            LMECoreError::InvalidTransform(_)
+            | LMECoreError::InvalidXyz(_, _)
+            | LMECoreError::InvalidSelection(_)
+            | LMECoreError::InvalidRequest(_)
+            | LMECoreError::InvalidAtom(_) => StatusCode::BAD_REQUEST,
+            LMECoreError::AtomSlotOccupied(_)
+            | LMECoreError::RootLayerError
+            | LMECoreError::StackConflict
+            | LMECoreError::NoUnitCell => StatusCode::CONFLICT,
+            LMECoreError::TooManyAtoms(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        };
+        (status, Json(self.0)).into_response()
+    }
+}
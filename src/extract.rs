@@ -0,0 +1,106 @@
+use axum::{
+    async_trait,
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    http::{request::Parts, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use lme_core::error::LMECoreError;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Rejection of the extractors below, answered with the JSON shape of `ServerError` while
+/// keeping the status chosen by axum.
+pub struct Rejection(StatusCode, String);
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        (self.0, axum::Json(LMECoreError::InvalidRequest(self.1))).into_response()
+    }
+}
+
+impl From<JsonRejection> for Rejection {
+    fn from(value: JsonRejection) -> Self {
+        Self(value.status(), value.body_text())
+    }
+}
+
+impl From<QueryRejection> for Rejection {
+    fn from(value: QueryRejection) -> Self {
+        Self(value.status(), value.body_text())
+    }
+}
+
+impl From<PathRejection> for Rejection {
+    fn from(value: PathRejection) -> Self {
+        Self(value.status(), value.body_text())
+    }
+}
+
+/// Limit given to `DefaultBodyLimit`, carried as an extension so that rejections can report it.
+#[derive(Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Json<T>
+where
+    axum::Json<T>: FromRequest<S, B, Rejection = JsonRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let limit = req.extensions().get::<BodyLimit>().copied();
+        let axum::Json(value) =
+            axum::Json::from_request(req, state)
+                .await
+                .map_err(|rejection| match limit {
+                    Some(BodyLimit(limit))
+                        if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE =>
+                    {
+                        Rejection(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            format!("Request body exceeds the limit of {limit} bytes"),
+                        )
+                    }
+                    _ => Rejection::from(rejection),
+                })?;
+        Ok(Self(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Query<T> {
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
+
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for Path<T> {
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) =
+            axum::extract::Path::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
@@ -1,35 +1,96 @@
 mod state_handler {
-    use std::sync::Arc;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
 
     use axum::{
-        extract::{Path, State},
-        http::{Request, StatusCode},
+        extract::State,
+        http::{header, HeaderMap, Request, StatusCode},
         middleware::Next,
-        response::{IntoResponse, Response},
-        Json,
+        response::{IntoResponse, Response, Result},
+        Extension,
+    };
+    use dashmap::mapref::entry::Entry;
+    use lme_core::{
+        entity::Molecule,
+        error::LMECoreError,
+        periodic::{self, ElementInfo},
+        schema, Workspace,
     };
-    use lme_core::{entity::Molecule, Workspace};
     use serde::Deserialize;
-    use tokio::sync::Mutex;
+    use tokio::{runtime::Handle, sync::Mutex, task};
+    use tracing::Instrument;
 
-    use crate::ServerState;
+    use crate::{
+        error::ServerError,
+        extract::{Json, Path, Query, Rejection},
+        AtomLimit, ServerState, WorkspaceEntry,
+    };
 
     #[derive(Deserialize)]
     pub struct WorkspaceParam {
         ws: String,
     }
 
+    #[derive(Deserialize)]
+    pub struct WorkspaceSource {
+        from: Option<String>,
+    }
+
+    /// Whether the request came with a body, which then has to be a valid molecule.
+    fn has_body(headers: &HeaderMap) -> bool {
+        let length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+        headers.contains_key(header::CONTENT_TYPE)
+            || headers.contains_key(header::TRANSFER_ENCODING)
+            || length.is_some_and(|length| length > 0)
+    }
+
+    /// Creates a workspace from the posted base molecule, or branches an existing workspace when
+    /// `from` is given. Branches share the immutable layers but own their stacks and names. A
+    /// body that isn't a valid molecule is refused, as is a body sent along with `from`.
     pub async fn create_workspace(
         State(state): State<ServerState>,
         Path(WorkspaceParam { ws }): Path<WorkspaceParam>,
-        Json(base): Json<Molecule>,
-    ) -> StatusCode {
-        let mut state = state.write().await;
-        if state.contains_key(&ws) {
-            StatusCode::CONFLICT
+        Query(WorkspaceSource { from }): Query<WorkspaceSource>,
+        Extension(AtomLimit(max_atoms)): Extension<AtomLimit>,
+        headers: HeaderMap,
+        base: std::result::Result<Json<Molecule>, Rejection>,
+    ) -> Result<StatusCode> {
+        let base = match base {
+            Ok(Json(base)) => Some(base),
+            Err(_) if !has_body(&headers) => None,
+            Err(rejection) => return Err(rejection.into()),
+        };
+        let workspace = if let Some(from) = from {
+            if base.is_some() {
+                return Err(ServerError(LMECoreError::InvalidRequest(
+                    "A branched workspace can't take a base molecule".to_string(),
+                ))
+                .into());
+            }
+            // Cloned out of the map so that no shard stays locked while waiting for the source.
+            let source = state.get(&from).map(|source| source.workspace.clone());
+            if let Some(source) = source {
+                source.lock().await.clone()
+            } else {
+                return Ok(StatusCode::NOT_FOUND);
+            }
         } else {
-            state.insert(ws, Arc::new(Mutex::new(Workspace::new(base))));
-            StatusCode::OK
+            let mut workspace = Workspace::new(base.unwrap_or_default());
+            workspace.set_max_atoms(Some(max_atoms));
+            workspace
+        };
+        if let Entry::Vacant(entry) = state.entry(ws) {
+            entry.insert(WorkspaceEntry {
+                workspace: Arc::new(Mutex::new(workspace)),
+                last_accessed: Instant::now(),
+            });
+            Ok(StatusCode::OK)
+        } else {
+            Ok(StatusCode::CONFLICT)
         }
     }
 
@@ -37,7 +98,6 @@ mod state_handler {
         State(state): State<ServerState>,
         Path(WorkspaceParam { ws }): Path<WorkspaceParam>,
     ) -> StatusCode {
-        let mut state = state.write().await;
         if state.remove(&ws).is_some() {
             StatusCode::OK
         } else {
@@ -45,16 +105,77 @@ mod state_handler {
         }
     }
 
+    #[derive(Deserialize)]
+    pub struct EvictParam {
+        idle_secs: u64,
+    }
+
+    /// Removes the workspaces without any request for more than `idle_secs` seconds and returns
+    /// their names, sorted. Workspaces with a request in flight, which holds a clone of the
+    /// accessor, are kept. Nothing is persisted, evicted workspaces are gone.
+    pub async fn evict_idle_workspaces(
+        State(state): State<ServerState>,
+        Query(EvictParam { idle_secs }): Query<EvictParam>,
+    ) -> Json<Vec<String>> {
+        let idle = Duration::from_secs(idle_secs);
+        let mut evicted = vec![];
+        state.retain(|ws, entry| {
+            let keep =
+                entry.last_accessed.elapsed() <= idle || Arc::strong_count(&entry.workspace) > 1;
+            if !keep {
+                evicted.push(ws.clone());
+            }
+            keep
+        });
+        evicted.sort();
+        tracing::info!(?evicted, "Evicted idle workspaces");
+        Json(evicted)
+    }
+
+    pub async fn molecule_schema() -> Json<serde_json::Value> {
+        Json(schema::molecule())
+    }
+
+    /// `element` is either an atomic number or a symbol.
+    pub async fn element_info(
+        Path(element): Path<String>,
+    ) -> Result<Json<ElementInfo>, ServerError> {
+        element
+            .parse()
+            .ok()
+            .or_else(|| periodic::element(&element))
+            .and_then(periodic::info)
+            .map(Json)
+            .ok_or_else(|| {
+                ServerError(LMECoreError::InvalidRequest(format!(
+                    "Unknown element {element}"
+                )))
+            })
+    }
+
+    pub async fn periodic_table() -> Json<Vec<ElementInfo>> {
+        Json(periodic::table())
+    }
+
+    /// Stack reads apply their layers synchronously, and plugin layers may wait for a plugin
+    /// permit on top of running their process. Workspace requests are therefore driven from a
+    /// thread the runtime gives up, so these waits don't park a worker other requests need.
     pub async fn workspace_middleware<B>(
         State(state): State<ServerState>,
         Path(WorkspaceParam { ws }): Path<WorkspaceParam>,
         mut req: Request<B>,
         next: Next<B>,
     ) -> Response {
-        let workspace = state.read().await.get(&ws).cloned();
+        let workspace = state.get_mut(&ws).map(|mut entry| {
+            entry.last_accessed = Instant::now();
+            entry.workspace.clone()
+        });
         if let Some(workspace) = workspace {
             req.extensions_mut().insert(workspace);
-            next.run(req).await
+            let response = next
+                .run(req)
+                .instrument(tracing::info_span!("workspace", %ws));
+            task::block_in_place(|| Handle::current().block_on(response))
         } else {
             (StatusCode::NOT_FOUND, "No such workspace").into_response()
         }
@@ -63,36 +184,117 @@ mod state_handler {
 
 mod workspace_handler {
     use axum::{
-        http::StatusCode,
-        response::{ErrorResponse, Result},
+        http::{header, HeaderMap, StatusCode},
+        response::{ErrorResponse, IntoResponse, Response, Result},
+    };
+    use std::{
+        collections::{hash_map::DefaultHasher, HashMap, HashSet},
+        hash::{Hash, Hasher},
+        ops::{Deref, Range},
+        sync::Arc,
     };
-    use std::{ops::Deref, sync::Arc};
 
-    use axum::{extract::Query, Extension, Json};
+    use axum::Extension;
     use lme_core::{
-        entity::{Layer, Molecule, Stack},
-        WorkspaceExport,
+        entity::{molecule_digest, Layer, Molecule, PluginOptions, Stack, UnitCell},
+        error::LMECoreError,
+        periodic, LayerFailure, StackSummary, WorkspaceExport, WorkspaceStats,
     };
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
 
-    use crate::WorkspaceAccessor;
+    use crate::{
+        error::ServerError,
+        extract::{Json, Path, Query},
+        StackIndex, WorkspaceAccessor,
+    };
 
+    /// An empty `range` is refused instead of selecting nothing, as it usually means the client
+    /// failed to fill its selection.
     #[derive(Deserialize)]
+    #[serde(try_from = "RawStacksSelect")]
     pub struct StacksSelect {
         pub start: usize,
         pub range: usize,
     }
 
+    #[derive(Deserialize)]
+    struct RawStacksSelect {
+        start: usize,
+        range: usize,
+    }
+
+    impl TryFrom<RawStacksSelect> for StacksSelect {
+        type Error = String;
+
+        fn try_from(RawStacksSelect { start, range }: RawStacksSelect) -> Result<Self, String> {
+            if range == 0 {
+                Err(format!("Empty selection of stacks starting at {start}"))
+            } else {
+                Ok(Self { start, range })
+            }
+        }
+    }
+
+    /// Answers with an `ETag` digesting the molecules, and with `304 Not Modified` when it
+    /// matches `If-None-Match`. Stacks are computed on read, so this saves bandwidth, not work.
     pub async fn read_stacks(
         Extension(workspace): Extension<WorkspaceAccessor>,
         Query(StacksSelect { start, range }): Query<StacksSelect>,
+        headers: HeaderMap,
+    ) -> Result<Response> {
+        let molecules = {
+            let end = start
+                .checked_add(range)
+                .ok_or(ServerError(LMECoreError::NoSuchStack))?;
+            let workspace = workspace.lock().await;
+            (start..end)
+                .map(|index| workspace.read(index))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(ServerError)?
+        };
+        let mut hasher = DefaultHasher::new();
+        for molecule in &molecules {
+            molecule_digest(molecule).hash(&mut hasher);
+        }
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        let matched = headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+        if matched {
+            Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response())
+        } else {
+            Ok(([(header::ETAG, etag)], Json(molecules)).into_response())
+        }
+    }
+
+    /// Reads the stacks at `indexes`, in the given order, under a single lock.
+    pub async fn read_many(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(indexes): Json<Vec<usize>>,
     ) -> Result<Json<Vec<Molecule>>> {
-        let workspace = workspace.lock().await;
-        (start..start + range)
-            .map(|index| workspace.read(index))
-            .collect::<Option<Vec<_>>>()
-            .map(|result| Json(result))
-            .ok_or(ErrorResponse::from(StatusCode::NOT_FOUND))
+        let molecules = workspace
+            .lock()
+            .await
+            .read_many(&indexes)
+            .map_err(ServerError)?;
+        Ok(Json(molecules))
+    }
+
+    pub async fn stack_summaries(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Json<Vec<StackSummary>> {
+        Json(workspace.lock().await.stack_summaries())
+    }
+
+    pub async fn workspace_stats(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Json<WorkspaceStats> {
+        Json(workspace.lock().await.stats())
     }
 
     #[derive(Deserialize)]
@@ -109,7 +311,7 @@ mod workspace_handler {
     pub async fn create_stack(
         Extension(workspace): Extension<WorkspaceAccessor>,
         Query(StackCreationParam { copies }): Query<StackCreationParam>,
-    ) -> Json<usize> {
+    ) -> Json<Range<usize>> {
         let mut workspace = workspace.lock().await;
         Json(workspace.create_stack(Arc::new(Stack::new(vec![])), copies))
     }
@@ -117,27 +319,187 @@ mod workspace_handler {
     pub async fn write_to_stack(
         Extension(workspace): Extension<WorkspaceAccessor>,
         Query(StacksSelect { start, range }): Query<StacksSelect>,
-        Json(data): Json<Molecule>
-    ) -> Json<bool> {
-        Json(
+        Json(data): Json<Molecule>,
+    ) -> Result<Json<bool>> {
+        let written = workspace
+            .lock()
+            .await
+            .write_to_stack(start, range, data)
+            .map_err(ServerError)?;
+        Ok(Json(written))
+    }
+
+    pub async fn add_layer_to_stack(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StacksSelect { start, range }): Query<StacksSelect>,
+        Json(layer): Json<Layer>,
+    ) -> Result<Json<bool>> {
+        layer.validate().map_err(ServerError)?;
+        let mut workspace = workspace.lock().await;
+        let layer = workspace.bind_cell(layer).map_err(ServerError)?;
+        Ok(Json(workspace.add_layer_to_stack(
+            start,
+            range,
+            Arc::new(layer),
+        )))
+    }
+
+    #[derive(Deserialize)]
+    pub struct HideHydrogensParam {
+        #[serde(default = "nonpolar")]
+        elements: Vec<usize>,
+        #[serde(default)]
+        valences: HashMap<usize, usize>,
+    }
+
+    fn nonpolar() -> Vec<usize> {
+        vec![6]
+    }
+
+    /// Hides the hydrogens of `elements`, carbon unless given, using the default valences of
+    /// `periodic::valence` overridden by `valences`.
+    pub async fn overlay_hide_hydrogens(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StacksSelect { start, range }): Query<StacksSelect>,
+        Json(HideHydrogensParam { elements, valences }): Json<HideHydrogensParam>,
+    ) -> Result<Json<bool>> {
+        let mut table = HashMap::new();
+        for element in elements {
+            let valence = valences
+                .get(&element)
+                .copied()
+                .or_else(|| periodic::valence(element))
+                .ok_or_else(|| {
+                    ServerError(LMECoreError::InvalidRequest(format!(
+                        "No default valence for element {element}"
+                    )))
+                })?;
+            table.insert(element, valence);
+        }
+        table.extend(valences);
+        let layer = Arc::new(Layer::HideHydrogens(table));
+        Ok(Json(
             workspace
                 .lock()
                 .await
-                .write_to_stack(start, range, data),
-        )
+                .add_layer_to_stack(start, range, layer),
+        ))
     }
 
-    pub async fn add_layer_to_stack(
+    /// Overlays a `ReplaceElement` layer from a pair of element symbols, e.g. `["Cl", "Br"]`.
+    pub async fn replace_element_by_symbol(
         Extension(workspace): Extension<WorkspaceAccessor>,
         Query(StacksSelect { start, range }): Query<StacksSelect>,
-        Json(layer): Json<Layer>
-    ) -> Json<bool> {
-        Json(
+        Json((from, to)): Json<(String, String)>,
+    ) -> Result<Json<bool>> {
+        let resolve = |symbol: &str| {
+            periodic::element(symbol).ok_or_else(|| {
+                ServerError(LMECoreError::InvalidRequest(format!(
+                    "Unknown element symbol {symbol}"
+                )))
+            })
+        };
+        let layer = Arc::new(Layer::ReplaceElement(resolve(&from)?, resolve(&to)?));
+        Ok(Json(
             workspace
                 .lock()
                 .await
-                .add_layer_to_stack(start, range, Arc::new(layer)),
-        )
+                .add_layer_to_stack(start, range, layer),
+        ))
+    }
+
+    /// Works on a snapshot of the workspace, so the lock is only held while cloning it and
+    /// concurrent edits are not blocked by the preview computation.
+    pub async fn preview_layer(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StacksSelect { start, range }): Query<StacksSelect>,
+        Json(layer): Json<Layer>,
+    ) -> Result<Json<Vec<Molecule>>> {
+        layer.validate().map_err(ServerError)?;
+        let snapshot = workspace.lock().await.clone();
+        let layer = snapshot.bind_cell(layer).map_err(ServerError)?;
+        let molecules = snapshot
+            .preview_layer(start, range, &layer)
+            .map_err(ServerError)?;
+        Ok(Json(molecules))
+    }
+
+    #[derive(Deserialize)]
+    pub struct PluginCall {
+        plugin: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        options: PluginOptions,
+    }
+
+    pub async fn create_stacks_from_plugin(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json(PluginCall {
+            plugin,
+            args,
+            options,
+        }): Json<PluginCall>,
+    ) -> Result<Json<Range<usize>>> {
+        let range = workspace
+            .lock()
+            .await
+            .create_stacks_from_plugin(index, &plugin, &args, &options)
+            .map_err(ServerError)?;
+        Ok(Json(range))
+    }
+
+    pub async fn remove_stacks(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StacksSelect { start, range }): Query<StacksSelect>,
+    ) -> Result<Json<HashMap<usize, usize>>> {
+        workspace
+            .lock()
+            .await
+            .remove_stacks(start, range)
+            .map(Json)
+            .ok_or(ErrorResponse::from(StatusCode::NOT_FOUND))
+    }
+
+    pub async fn stack_id(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<Uuid>> {
+        Ok(Json(
+            workspace
+                .lock()
+                .await
+                .stack_id(index)
+                .map_err(ServerError)?,
+        ))
+    }
+
+    pub async fn get_stack_by_uuid(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(id): Path<Uuid>,
+    ) -> Result<Json<usize>> {
+        Ok(Json(
+            workspace
+                .lock()
+                .await
+                .find_stack(&id)
+                .map_err(ServerError)?,
+        ))
+    }
+
+    /// Resolves the id under the same lock as the removal, so the id always names the removed
+    /// stack whatever was removed before.
+    pub async fn remove_stack_by_uuid(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(id): Path<Uuid>,
+    ) -> Result<Json<HashMap<usize, usize>>> {
+        let mut workspace = workspace.lock().await;
+        let index = workspace.find_stack(&id).map_err(ServerError)?;
+        workspace
+            .remove_stacks(index, 1)
+            .map(Json)
+            .ok_or(ErrorResponse::from(StatusCode::NOT_FOUND))
     }
 
     #[derive(Deserialize)]
@@ -149,43 +511,939 @@ mod workspace_handler {
     pub async fn clone_stack(
         Extension(workspace): Extension<WorkspaceAccessor>,
         Json(CloneStack { stack_idx, copies }): Json<CloneStack>,
-    ) -> Result<Json<usize>> {
+    ) -> Result<Json<Range<usize>>> {
         workspace
             .lock()
             .await
             .clone_stack(stack_idx, copies)
-            .map(|start| Json(start))
+            .map(Json)
             .ok_or(ErrorResponse::from(StatusCode::NOT_FOUND))
     }
 
     pub async fn clone_base(
         Extension(workspace): Extension<WorkspaceAccessor>,
         Json(CloneStack { stack_idx, copies }): Json<CloneStack>,
-    ) -> Result<Json<usize>> {
+    ) -> Result<Json<Range<usize>>> {
         workspace
             .lock()
             .await
             .clone_base(stack_idx, copies)
-            .map(|start| Json(start))
+            .map(Json)
             .ok_or(ErrorResponse::from(StatusCode::NOT_FOUND))
     }
 
+    #[derive(Deserialize)]
+    pub struct CloneAncestor {
+        stack_idx: usize,
+        depth: usize,
+        copies: usize,
+    }
+
+    pub async fn clone_ancestor(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(CloneAncestor {
+            stack_idx,
+            depth,
+            copies,
+        }): Json<CloneAncestor>,
+    ) -> Result<Json<Range<usize>>> {
+        let range = workspace
+            .lock()
+            .await
+            .clone_ancestor(stack_idx, depth, copies)
+            .map_err(ServerError)?;
+        Ok(Json(range))
+    }
+
+    pub async fn set_atom_names(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(names): Json<HashMap<usize, String>>,
+    ) -> Json<HashMap<usize, String>> {
+        Json(workspace.lock().await.set_atom_names(names))
+    }
+
+    #[derive(Deserialize)]
+    pub struct AtomNameParam {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct StackFilter {
+        stack: Option<usize>,
+    }
+
+    /// `present` tells whether the atom exists in `stack`, when one is given.
+    #[derive(Serialize)]
+    pub struct AtomLocation {
+        index: usize,
+        present: Option<bool>,
+    }
+
+    /// Atom names and workspace groups of the given atoms, atoms without any appearing with no
+    /// name and no groups.
+    pub async fn annotations_for(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(atoms): Json<Vec<usize>>,
+    ) -> Json<HashMap<usize, (Option<String>, HashSet<String>)>> {
+        Json(workspace.lock().await.annotations_for(&atoms))
+    }
+
+    pub async fn find_atom_name(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(AtomNameParam { name }): Path<AtomNameParam>,
+        Query(StackFilter { stack }): Query<StackFilter>,
+    ) -> Result<Json<AtomLocation>> {
+        let workspace = workspace.lock().await;
+        let index = workspace.find_atom_name(&name).map_err(ServerError)?;
+        let present = stack
+            .map(|stack| workspace.read(stack))
+            .transpose()
+            .map_err(ServerError)?
+            .map(|molecule| molecule.get_atom(index).is_some());
+        Ok(Json(AtomLocation { index, present }))
+    }
+
+    #[derive(Deserialize)]
+    pub struct GroupSelect {
+        group: String,
+    }
+
+    pub async fn group_members(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(GroupSelect { group }): Query<GroupSelect>,
+    ) -> Json<HashSet<usize>> {
+        Json(workspace.lock().await.groups.get_left(&group))
+    }
+
+    pub async fn add_to_groups(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(members): Json<Vec<(String, usize)>>,
+    ) {
+        workspace.lock().await.groups.extend(members)
+    }
+
+    pub async fn remove_from_groups(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(members): Json<Vec<(String, usize)>>,
+    ) {
+        let mut workspace = workspace.lock().await;
+        for (group, idx) in members {
+            workspace.groups.remove(&group, &idx);
+        }
+    }
+
+    pub async fn check_stacks(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Json<Vec<LayerFailure>> {
+        let snapshot = workspace.lock().await.clone();
+        Json(snapshot.check_stacks())
+    }
+
+    /// Index of the layer that last changed each atom slot of the stack.
+    pub async fn stack_provenance(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<HashMap<usize, usize>>> {
+        let provenance = workspace
+            .lock()
+            .await
+            .provenance(index)
+            .map_err(ServerError)?;
+        Ok(Json(provenance))
+    }
+
+    pub async fn stack_layers(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<Vec<(usize, Layer)>>> {
+        let layers = workspace
+            .lock()
+            .await
+            .stack_layers(index)
+            .map_err(ServerError)?;
+        Ok(Json(layers))
+    }
+
+    #[derive(Deserialize)]
+    pub struct LayerRange {
+        index: usize,
+        from: usize,
+        to: usize,
+    }
+
+    pub async fn stack_layers_between(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(LayerRange { index, from, to }): Query<LayerRange>,
+    ) -> Result<Json<Vec<(usize, Layer)>>> {
+        let layers = workspace
+            .lock()
+            .await
+            .layers_between(index, from, to)
+            .map_err(ServerError)?;
+        Ok(Json(layers))
+    }
+
+    pub async fn layer_tree(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Json<Vec<(Vec<usize>, Vec<Layer>)>> {
+        Json(workspace.lock().await.layer_tree())
+    }
+
     pub async fn workspace_export(
         Extension(workspace): Extension<WorkspaceAccessor>,
     ) -> Json<WorkspaceExport> {
         Json(WorkspaceExport::from(workspace.lock().await.deref()))
     }
+
+    #[derive(Deserialize)]
+    pub struct CheckpointParam {
+        name: String,
+    }
+
+    pub async fn list_checkpoints(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Json<Vec<String>> {
+        let workspace = workspace.lock().await;
+        Json(
+            workspace
+                .checkpoints()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    pub async fn create_checkpoint(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(CheckpointParam { name }): Path<CheckpointParam>,
+    ) {
+        workspace.lock().await.checkpoint(name)
+    }
+
+    pub async fn restore_checkpoint(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(CheckpointParam { name }): Path<CheckpointParam>,
+    ) -> Result<()> {
+        workspace
+            .lock()
+            .await
+            .restore_checkpoint(&name)
+            .map_err(ServerError)?;
+        Ok(())
+    }
+
+    pub async fn get_cell(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Json<Option<UnitCell>> {
+        Json(workspace.lock().await.cell().copied())
+    }
+
+    /// `null` removes the cell. Layers already bound to the previous cell keep it.
+    pub async fn set_cell(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Json(cell): Json<Option<UnitCell>>,
+    ) -> Result<()> {
+        workspace.lock().await.set_cell(cell).map_err(ServerError)?;
+        Ok(())
+    }
+
+    /// a, b, c in Å and α, β, γ in degrees.
+    pub async fn cell_parameters(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+    ) -> Result<Json<(f64, f64, f64, f64, f64, f64)>> {
+        let workspace = workspace.lock().await;
+        let cell = workspace
+            .cell()
+            .ok_or(ServerError(LMECoreError::NoUnitCell))?;
+        Ok(Json(cell.parameters()))
+    }
 }
 
 mod chemistry_handler {
-    use std::collections::HashMap;
+    use std::{
+        collections::{HashMap, HashSet},
+        ops::Range,
+        sync::Arc,
+    };
+
+    use axum::{
+        body::StreamBody,
+        http::{header, StatusCode},
+        response::{ErrorResponse, IntoResponse, Response, Result},
+        Extension,
+    };
+    use futures::stream;
+    use lme_core::{
+        entity::{
+            bond_map, molecule_fingerprint, BondKind, CompactedMolecule, CompositionReport, Layer,
+            Molecule,
+        },
+        error::LMECoreError,
+        select::{self, Predicate},
+        smiles, symmetry, xyz,
+    };
+    use n_to_n::NtoN;
+    use nalgebra::{Matrix3, Point3, Transform3, Translation3, Vector3};
+    use pair::Pair;
+    use rayon::prelude::*;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        error::ServerError,
+        extract::{Json, Path, Query},
+        StacksSelect, WorkspaceAccessor,
+    };
+
+    #[derive(Deserialize)]
+    pub struct StackIndex {
+        pub index: usize,
+    }
+
+    const COMMIT_ATTEMPTS: usize = 3;
+
+    /// Reads a stack and computes a patch for it without holding the workspace lock, then
+    /// commits the patch only if the stack was not replaced meanwhile. A conflicting edit makes
+    /// the patch be recomputed on the new state, up to `COMMIT_ATTEMPTS` times before the
+    /// conflict is reported to the client.
+    async fn patch_stack<R>(
+        workspace: &WorkspaceAccessor,
+        index: usize,
+        compute: impl Fn(&Molecule) -> Result<(Molecule, R), LMECoreError>,
+    ) -> Result<R, ServerError> {
+        for _ in 0..COMMIT_ATTEMPTS {
+            let snapshot = workspace.lock().await.snapshot(index)?;
+            let current = snapshot.read()?;
+            let (patch, result) = compute(&current)?;
+            match workspace.lock().await.commit(&snapshot, &current, patch) {
+                Err(LMECoreError::StackConflict) => continue,
+                committed => return Ok(committed.map(|_| result)?),
+            }
+        }
+        Err(ServerError(LMECoreError::StackConflict))
+    }
+
+    pub async fn nearest_atoms(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json((point, k)): Json<(Point3<f64>, usize)>,
+    ) -> Result<Json<Vec<(usize, f64)>>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(molecule.nearest_atoms(&point, k)))
+    }
+
+    #[derive(Deserialize)]
+    pub struct AtomSelect {
+        index: usize,
+        atom: usize,
+    }
+
+    pub async fn atom_neighbors(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(AtomSelect { index, atom }): Query<AtomSelect>,
+    ) -> Result<Json<Vec<(usize, BondKind)>>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        molecule.get_atom(atom).ok_or(LMECoreError::NoSuchAtom)?;
+        let mut neighbors = molecule
+            .neighbors(atom)
+            .map(|(idx, kind)| (idx, *kind))
+            .collect::<Vec<_>>();
+        neighbors.sort_by_key(|(idx, _)| *idx);
+        Ok(Json(neighbors))
+    }
+
+    /// Atoms of the connected fragment holding `atom`, to select a whole molecule from a click.
+    pub async fn fragment_of(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(atom): Path<usize>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<Vec<usize>>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(molecule.fragment_of(atom)?))
+    }
+
+    #[derive(Deserialize)]
+    pub struct BondParam {
+        a: usize,
+        b: usize,
+    }
+
+    pub async fn get_bond(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Path(BondParam { a, b }): Path<BondParam>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<Option<BondKind>>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        if molecule.get_atom(a).is_none() || molecule.get_atom(b).is_none() {
+            return Err(ServerError(LMECoreError::NoSuchAtom));
+        }
+        Ok(Json(molecule.get_bond(a, b).copied()))
+    }
 
-    use axum::{extract::Query, Extension, Json};
+    /// `values[i]` is NaN, serialized as `null`, when `errors[i]` is set.
+    #[derive(Serialize)]
+    pub struct MeasureReport {
+        values: Vec<f64>,
+        errors: Vec<Option<LMECoreError>>,
+    }
 
-    use crate::{StacksSelect, WorkspaceAccessor};
+    #[derive(Deserialize)]
+    pub struct MeasureParam {
+        index: usize,
+        #[serde(default)]
+        periodic: bool,
+    }
+
+    /// With `periodic`, atoms are measured across the boundaries of the workspace cell.
+    pub async fn measure_batch(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(MeasureParam { index, periodic }): Query<MeasureParam>,
+        Json(selections): Json<Vec<Vec<usize>>>,
+    ) -> Result<Json<MeasureReport>, ServerError> {
+        let (molecule, cell) = {
+            let workspace = workspace.lock().await;
+            let cell = if periodic {
+                Some(*workspace.cell().ok_or(LMECoreError::NoUnitCell)?)
+            } else {
+                None
+            };
+            (workspace.read(index)?, cell)
+        };
+        let (values, errors) = selections
+            .iter()
+            .map(|atoms| match cell {
+                Some(cell) => molecule.measure_periodic(atoms, &cell),
+                None => molecule.measure(atoms),
+            })
+            .map(|measured| match measured {
+                Ok(value) => (value, None),
+                Err(err) => (f64::NAN, Some(err)),
+            })
+            .unzip();
+        Ok(Json(MeasureReport { values, errors }))
+    }
+
+    pub async fn select_atoms(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json(predicate): Json<Predicate>,
+    ) -> Result<Json<Vec<usize>>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(select::select(&molecule, &predicate)))
+    }
+
+    pub async fn read_stack_subset(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json(indexes): Json<Vec<usize>>,
+    ) -> Result<Json<Molecule>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(molecule.subset(&indexes)))
+    }
 
-    pub fn modify_bonds(Extension(workspace): Extension<WorkspaceAccessor>, Query(StacksSelect {start, range}): Query<StacksSelect>, Json(bonds): Json<HashMap<Pair<usize>, f64>>) -> Json<bool> {}
+    pub async fn composition(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<CompositionReport>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(molecule.composition()))
+    }
+
+    pub async fn replace_base(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json(base): Json<Molecule>,
+    ) -> Result<Json<bool>, ServerError> {
+        workspace.lock().await.replace_base(index, base)?;
+        Ok(Json(true))
+    }
+
+    pub async fn read_base(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<Molecule>, ServerError> {
+        Ok(Json(workspace.lock().await.read_base(index)?))
+    }
+
+    #[derive(Deserialize)]
+    pub struct PointGroupParam {
+        index: usize,
+        tolerance: Option<f64>,
+    }
+
+    pub async fn detect_point_group(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(PointGroupParam { index, tolerance }): Query<PointGroupParam>,
+    ) -> Result<Json<String>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        let tolerance = tolerance.unwrap_or(0.1);
+        tokio::task::spawn_blocking(move || symmetry::point_group(&molecule, tolerance))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+            .map(Json)
+            .map_err(ServerError)
+    }
+
+    pub async fn principal_axes(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<(Vector3<f64>, Matrix3<f64>)>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(symmetry::principal_axes(&molecule)?))
+    }
+
+    /// Overlays the transform of `symmetry::principal_alignment` on the stack and returns it so
+    /// that clients can undo it.
+    pub async fn align_principal_axes(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<Transform3<f64>>, ServerError> {
+        let mut workspace = workspace.lock().await;
+        let transform = symmetry::principal_alignment(&workspace.read(index)?)?;
+        workspace.add_layer_to_stack(index, 1, Arc::new(Layer::Transform(transform)));
+        Ok(Json(transform))
+    }
+
+    /// Turns items into a newline-delimited JSON body, serializing each item only when the client
+    /// is ready for it.
+    fn ndjson<T, I>(items: I) -> impl IntoResponse
+    where
+        T: Serialize,
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+    {
+        let lines = items.into_iter().map(|item| {
+            let mut line = serde_json::to_vec(&item)?;
+            line.push(b'\n');
+            Ok::<_, serde_json::Error>(line)
+        });
+        (
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            StreamBody::new(stream::iter(lines)),
+        )
+    }
+
+    #[derive(Serialize)]
+    struct AtomLine {
+        idx: usize,
+        element: usize,
+        position: Point3<f64>,
+    }
+
+    /// Present atoms of the stack in index order, one JSON object per line.
+    pub async fn stream_atoms(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<impl IntoResponse, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        let mut atoms = molecule
+            .present_atoms()
+            .map(|(idx, atom)| (idx, *atom))
+            .collect::<Vec<_>>();
+        atoms.sort_unstable_by_key(|(idx, _)| *idx);
+        Ok(ndjson(atoms.into_iter().map(|(idx, atom)| AtomLine {
+            idx,
+            element: atom.element(),
+            position: *atom.position(),
+        })))
+    }
+
+    #[derive(Serialize)]
+    struct BondLine {
+        a: usize,
+        b: usize,
+        kind: BondKind,
+    }
+
+    /// Bonds of the stack ordered by atom pair, one JSON object per line.
+    pub async fn stream_bonds(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<impl IntoResponse, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        let mut bonds = molecule
+            .bonds()
+            .iter()
+            .map(|(pair, kind)| (*pair, *kind))
+            .collect::<Vec<_>>();
+        bonds.sort_unstable_by_key(|(pair, _)| *pair);
+        Ok(ndjson(bonds.into_iter().map(|(pair, kind)| {
+            let (a, b) = pair.into();
+            BondLine { a, b, kind }
+        })))
+    }
+
+    pub async fn export_smiles(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<String, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(smiles::to_smiles(&molecule))
+    }
+
+    #[derive(Deserialize)]
+    pub struct ExportParam {
+        index: usize,
+        precision: Option<usize>,
+    }
+
+    pub async fn export_xyz(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(ExportParam { index, precision }): Query<ExportParam>,
+    ) -> Result<String, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        let precision = precision.unwrap_or(xyz::DEFAULT_PRECISION);
+        Ok(xyz::to_xyz(&molecule, &format!("stack {index}"), precision))
+    }
+
+    /// Formats offered by `export_stack`, anything else is refused with 400.
+    #[derive(Deserialize, Clone, Copy)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ExportFormat {
+        Xyz,
+        Smiles,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FormatParam {
+        index: usize,
+        format: ExportFormat,
+        precision: Option<usize>,
+    }
+
+    /// Single download URL for every format, answered as an attachment.
+    pub async fn export_stack(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(FormatParam {
+            index,
+            format,
+            precision,
+        }): Query<FormatParam>,
+    ) -> Result<Response, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        let (body, content_type, extension) = match format {
+            ExportFormat::Xyz => {
+                let precision = precision.unwrap_or(xyz::DEFAULT_PRECISION);
+                let body = xyz::to_xyz(&molecule, &format!("stack {index}"), precision);
+                (body, "chemical/x-xyz", "xyz")
+            }
+            ExportFormat::Smiles => (
+                smiles::to_smiles(&molecule),
+                "chemical/x-daylight-smiles",
+                "smi",
+            ),
+        };
+        let disposition = format!("attachment; filename=\"stack-{index}.{extension}\"");
+        Ok((
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            body,
+        )
+            .into_response())
+    }
+
+    pub async fn import_xyz_trajectory(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        data: String,
+    ) -> Result<Json<Range<usize>>, ServerError> {
+        let frames = xyz::parse_xyz_trajectory(&data)?;
+        Ok(Json(
+            workspace.lock().await.create_stacks_from_frames(frames),
+        ))
+    }
+
+    #[derive(Deserialize)]
+    pub struct FingerprintParam {
+        index: usize,
+        #[serde(default = "default_fingerprint_decimals")]
+        decimals: i32,
+    }
+
+    fn default_fingerprint_decimals() -> i32 {
+        3
+    }
+
+    /// Answers the fingerprint as hex, as JSON numbers can't hold every `u64` exactly.
+    pub async fn fingerprint(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(FingerprintParam { index, decimals }): Query<FingerprintParam>,
+    ) -> Result<String, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(format!(
+            "{:016x}",
+            molecule_fingerprint(&molecule, decimals)
+        ))
+    }
+
+    pub async fn rotate_dihedral(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json((a, b, atoms, angle)): Json<(usize, usize, Vec<usize>, f64)>,
+    ) -> Result<Json<bool>, ServerError> {
+        patch_stack(&workspace, index, |molecule| {
+            Ok((molecule.rotate_about_bond((a, b), &atoms, angle)?, true))
+        })
+        .await
+        .map(Json)
+    }
+
+    #[derive(Deserialize)]
+    pub struct TranslateAtomTo {
+        anchor: usize,
+        target: Point3<f64>,
+        atoms: Option<Vec<usize>>,
+    }
+
+    /// Moves atom `anchor` to `target`, carrying `atoms` along, or the whole molecule through
+    /// a translation layer when no atoms are given.
+    pub async fn translate_atom_to(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json(TranslateAtomTo {
+            anchor,
+            target,
+            atoms,
+        }): Json<TranslateAtomTo>,
+    ) -> Result<Json<Vector3<f64>>, ServerError> {
+        if let Some(atoms) = atoms {
+            return patch_stack(&workspace, index, |molecule| {
+                let offset = molecule.offset_to(anchor, &target)?;
+                Ok((molecule.translate_atoms(&atoms, &offset)?, offset))
+            })
+            .await
+            .map(Json);
+        }
+        let mut workspace = workspace.lock().await;
+        let offset = workspace.read(index)?.offset_to(anchor, &target)?;
+        let translation =
+            Transform3::from_matrix_unchecked(Translation3::from(offset).to_homogeneous());
+        workspace.add_layer_to_stack(index, 1, Arc::new(Layer::Transform(translation)));
+        Ok(Json(offset))
+    }
+
+    pub async fn set_bond_order(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json((pair, kind)): Json<(Pair<usize>, BondKind)>,
+    ) -> Result<Json<bool>, ServerError> {
+        patch_stack(&workspace, index, |molecule| {
+            let (a, b) = pair.into();
+            if molecule.get_atom(a).is_none() || molecule.get_atom(b).is_none() {
+                return Err(LMECoreError::NoSuchAtom);
+            }
+            let patch = Molecule::new(HashMap::new(), HashMap::from([(pair, kind)]), NtoN::new());
+            Ok((patch, true))
+        })
+        .await
+        .map(Json)
+    }
+
+    pub async fn compact_stack(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<Json<HashMap<usize, usize>>, ServerError> {
+        Ok(Json(workspace.lock().await.compact_stack(index)?))
+    }
+
+    pub async fn discard_top_layer(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+    ) -> Result<(), ServerError> {
+        Ok(workspace.lock().await.discard_top_layer(index)?)
+    }
+
+    pub async fn build_repeat(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json((unit, step, count)): Json<(Vec<usize>, Transform3<f64>, usize)>,
+    ) -> Result<Json<Vec<Vec<usize>>>, ServerError> {
+        let limit = workspace.lock().await.max_atoms();
+        patch_stack(&workspace, index, |molecule| {
+            molecule.repeat(&unit, &step, count, limit)
+        })
+        .await
+        .map(Json)
+    }
+
+    #[derive(Deserialize)]
+    pub struct StackGroupSelect {
+        index: usize,
+        group: String,
+    }
+
+    pub async fn stack_group_members(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackGroupSelect { index, group }): Query<StackGroupSelect>,
+    ) -> Result<Json<HashSet<usize>>, ServerError> {
+        let molecule = workspace.lock().await.read(index)?;
+        Ok(Json(molecule.group_members(&group)))
+    }
+
+    /// Stack molecules are patched by merging, so members can be added to a group but not
+    /// removed from it this way.
+    pub async fn add_to_stack_group(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StacksSelect { start, range }): Query<StacksSelect>,
+        Json((group, atoms)): Json<(String, Vec<usize>)>,
+    ) -> Result<Json<bool>, ServerError> {
+        let mut patch = Molecule::default();
+        for idx in atoms {
+            patch.add_to_group(idx, &group);
+        }
+        Ok(Json(
+            workspace.lock().await.write_to_stack(start, range, patch)?,
+        ))
+    }
+
+    /// Without a `cutoff`, every pair of the two selections is bonded, which must be requested
+    /// explicitly with `all_pairs`.
+    #[derive(Deserialize)]
+    pub struct BondSelections {
+        a: Vec<usize>,
+        b: Vec<usize>,
+        kind: BondKind,
+        cutoff: Option<f64>,
+        #[serde(default)]
+        all_pairs: bool,
+    }
+
+    pub async fn bond_selections(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StackIndex { index }): Query<StackIndex>,
+        Json(BondSelections {
+            a,
+            b,
+            kind,
+            cutoff,
+            all_pairs,
+        }): Json<BondSelections>,
+    ) -> Result<Json<usize>> {
+        if cutoff.is_none() && !all_pairs {
+            return Err(ErrorResponse::from((
+                StatusCode::BAD_REQUEST,
+                "Either a cutoff or all_pairs is required",
+            )));
+        }
+        let count = patch_stack(&workspace, index, |molecule| {
+            let patch = molecule.bond_selections(&a, &b, kind, cutoff)?;
+            let count = patch.bonds().len();
+            Ok((patch, count))
+        })
+        .await?;
+        Ok(Json(count))
+    }
+
+    #[derive(Deserialize)]
+    pub struct ImportParam {
+        index: usize,
+        offset: Option<usize>,
+    }
+
+    pub async fn import_structure(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(ImportParam { index, offset }): Query<ImportParam>,
+        Json(structure): Json<CompactedMolecule>,
+    ) -> Result<Json<Range<usize>>, ServerError> {
+        patch_stack(&workspace, index, |molecule| {
+            molecule.import_structure(structure.clone(), offset)
+        })
+        .await
+        .map(Json)
+    }
+
+    #[derive(Deserialize)]
+    #[serde(transparent)]
+    pub struct BondPatch(#[serde(with = "bond_map")] HashMap<Pair<usize>, BondKind>);
+
+    pub async fn modify_bonds(
+        Extension(workspace): Extension<WorkspaceAccessor>,
+        Query(StacksSelect { start, range }): Query<StacksSelect>,
+        Json(BondPatch(bonds)): Json<BondPatch>,
+    ) -> Result<Json<bool>, ServerError> {
+        let patch = Molecule::new(HashMap::new(), bonds, NtoN::new());
+        Ok(Json(
+            workspace.lock().await.write_to_stack(start, range, patch)?,
+        ))
+    }
 }
 
+pub use chemistry_handler::*;
 pub use state_handler::*;
 pub use workspace_handler::*;
+
+mod test {
+    #[cfg(test)]
+    async fn create(uri: &str, body: Option<&str>) -> axum::http::StatusCode {
+        use axum::{body::Body, http::Request, routing::post, Extension, Router};
+        use dashmap::DashMap;
+        use std::sync::Arc;
+        use tower::ServiceExt;
+
+        use crate::{handler::create_workspace, AtomLimit, ServerState};
+
+        let state: ServerState = Arc::new(DashMap::new());
+        let router = Router::new()
+            .route("/ws/:ws", post(create_workspace))
+            .with_state(state)
+            .layer(Extension(AtomLimit(100)));
+        let request = Request::post("/ws/a").uri(uri);
+        let request = match body {
+            Some(body) => request
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+        router.oneshot(request.unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn create_workspace_rejects_bad_bodies() {
+        use axum::http::StatusCode;
+
+        let carbon =
+            r#"{"atoms": {"0": {"element": 6, "position": [0, 0, 0]}}, "bonds": {}, "groups": []}"#;
+        assert_eq!(create("/ws/a", None).await, StatusCode::OK);
+        assert_eq!(create("/ws/a", Some(carbon)).await, StatusCode::OK);
+        assert_eq!(create("/ws/a", Some("{")).await, StatusCode::BAD_REQUEST);
+        let invalid = carbon.replace("\"element\": 6", "\"element\": 0");
+        assert_eq!(
+            create("/ws/a", Some(&invalid)).await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            create("/ws/a?from=b", Some(carbon)).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(create("/ws/a?from=b", None).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn read_stacks_refuses_overflowing_ranges() {
+        use axum::{body::Body, http::Request, http::StatusCode, routing::get, Extension, Router};
+        use lme_core::{entity::Stack, Workspace};
+        use std::sync::Arc;
+        use tokio::sync::Mutex;
+        use tower::ServiceExt;
+
+        use crate::handler::read_stacks;
+
+        let mut workspace = Workspace::default();
+        workspace.create_stack(Arc::new(Stack::default()), 2);
+        let router = Router::new()
+            .route("/", get(read_stacks))
+            .layer(Extension(Arc::new(Mutex::new(workspace))));
+        let status = |uri: String| {
+            let router = router.clone();
+            async move {
+                let request = Request::get(uri).body(Body::empty()).unwrap();
+                router.oneshot(request).await.unwrap().status()
+            }
+        };
+        assert_eq!(status("/?start=0&range=2".into()).await, StatusCode::OK);
+        assert_eq!(
+            status(format!("/?start={}&range=1", usize::MAX)).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
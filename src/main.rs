@@ -1,39 +1,141 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
 use axum::{
+    extract::DefaultBodyLimit,
     middleware,
-    routing::{delete, post, put, get},
-    Router,
+    routing::{delete, get, post, put},
+    Extension, Router,
 };
 use clap::Parser;
+use dashmap::DashMap;
+use extract::BodyLimit;
 use handler::*;
-use lme_core::Workspace;
-use tokio::sync::{Mutex, RwLock};
+use lme_core::{entity, Workspace};
+use tokio::{signal, sync::Mutex};
+use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
+use tracing::Level;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 mod error;
+mod extract;
 mod handler;
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long)]
     listen: SocketAddr,
+    /// Maximum number of plugin processes running at once, defaults to the number of CPUs
+    #[arg(long)]
+    plugin_concurrency: Option<usize>,
+    /// Maximum size in bytes of a request body
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_body_size: usize,
+    /// Maximum number of atoms in a stack of a new workspace
+    #[arg(long, default_value_t = 5_000_000)]
+    max_atoms: usize,
 }
 
 pub type WorkspaceAccessor = Arc<Mutex<Workspace>>;
-pub type ServerState = Arc<RwLock<HashMap<String, WorkspaceAccessor>>>;
+/// Workspace with the time of the last request routed to it, see `evict_idle_workspaces`.
+pub struct WorkspaceEntry {
+    pub workspace: WorkspaceAccessor,
+    pub last_accessed: Instant,
+}
+
+/// Workspaces by name, sharded so that lookups of different workspaces don't contend.
+pub type ServerState = Arc<DashMap<String, WorkspaceEntry>>;
+
+/// Atom limit given to the workspaces created by the server.
+#[derive(Clone, Copy)]
+pub struct AtomLimit(pub usize);
 
 #[tokio::main]
 async fn main() {
-    let Args { listen } = Args::parse();
+    let Args {
+        listen,
+        plugin_concurrency,
+        max_body_size,
+        max_atoms,
+    } = Args::parse();
+    // Closing spans report their duration; `RUST_LOG` overrides the default `info` level.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+    if let Some(limit) = plugin_concurrency {
+        entity::set_plugin_concurrency(limit);
+    }
 
-    let state: ServerState = Arc::new(RwLock::new(HashMap::new()));
+    let state: ServerState = Arc::new(DashMap::new());
 
     let ws_router = Router::new()
         .route("/stack/clone_stack", post(clone_stack))
         .route("/stack/clone_base", post(clone_base))
+        .route("/stack/clone_ancestor", post(clone_ancestor))
         .route("/stack/layer", put(add_layer_to_stack))
+        .route("/stack/layers", get(stack_layers))
+        .route("/stack/layers_between", get(stack_layers_between))
+        .route("/stack/provenance", get(stack_provenance))
+        .route("/stack/hide_hydrogens", put(overlay_hide_hydrogens))
+        .route("/stack/replace_element", put(replace_element_by_symbol))
+        .route("/stack/preview", post(preview_layer))
+        .route("/stack/plugin_multi", post(create_stacks_from_plugin))
+        .route("/stack/read_many", post(read_many))
+        .route("/stack/summaries", get(stack_summaries))
+        .route("/stack/check", post(check_stacks))
         .route("/stack/write", put(write_to_stack))
-        .route("/stack", post(create_stack))
+        .route("/stack/bonds", get(stream_bonds).put(modify_bonds))
+        .route("/stack/bond_order", put(set_bond_order))
+        .route("/stack/bond/:a/:b", get(get_bond))
+        .route("/stack/bond_selections", post(bond_selections))
+        .route("/stack/nearest_atoms", post(nearest_atoms))
+        .route("/stack/measure", post(measure_batch))
+        .route("/stack/neighbors", get(atom_neighbors))
+        .route("/stack/fragment/:atom", get(fragment_of))
+        .route(
+            "/stack/group",
+            get(stack_group_members).put(add_to_stack_group),
+        )
+        .route("/stack/base", get(read_base).put(replace_base))
+        .route("/stack/point_group", get(detect_point_group))
+        .route("/stack/principal_axes", get(principal_axes))
+        .route("/stack/atoms", get(stream_atoms))
+        .route("/stack/align_principal_axes", put(align_principal_axes))
+        .route("/stack/smiles", get(export_smiles))
+        .route("/stack/xyz", get(export_xyz).post(import_xyz_trajectory))
+        .route("/stack/export", get(export_stack))
+        .route("/stack/composition", get(composition))
+        .route("/stack/fingerprint", get(fingerprint))
+        .route("/stack/subset", post(read_stack_subset))
+        .route("/stack/select", post(select_atoms))
+        .route("/stack/dihedral", put(rotate_dihedral))
+        .route("/stack/translate_atom_to", put(translate_atom_to))
+        .route("/stack/compact", post(compact_stack))
+        .route("/stack/discard", post(discard_top_layer))
+        .route("/stack/repeat", post(build_repeat))
+        .route("/stack/import", post(import_structure))
+        .route("/stack", post(create_stack).delete(remove_stacks))
+        .route("/stack/id", get(stack_id))
+        .route(
+            "/stacks/:id",
+            get(get_stack_by_uuid).delete(remove_stack_by_uuid),
+        )
+        .route("/atom_names", put(set_atom_names))
+        .route("/atom_names/:name", get(find_atom_name))
+        .route("/annotations", post(annotations_for))
+        .route(
+            "/groups",
+            get(group_members)
+                .put(add_to_groups)
+                .delete(remove_from_groups),
+        )
+        .route("/stats", get(workspace_stats))
+        .route("/layers", get(layer_tree))
         .route("/export", post(workspace_export))
+        .route("/checkpoints", get(list_checkpoints))
+        .route("/checkpoints/:name", put(create_checkpoint))
+        .route("/checkpoints/:name/restore", post(restore_checkpoint))
+        .route("/cell", get(get_cell).put(set_cell))
+        .route("/cell/parameters", get(cell_parameters))
         .route("/", get(read_stacks))
         .layer(middleware::from_fn_with_state(
             state.clone(),
@@ -44,10 +146,43 @@ async fn main() {
         .nest("/ws/:ws", ws_router)
         .route("/ws/:ws", delete(remove_workspace))
         .route("/ws/:ws", post(create_workspace))
-        .with_state(state);
+        .route("/schema/molecule", get(molecule_schema))
+        .route("/periodic", get(periodic_table))
+        .route("/admin/evict", post(evict_idle_workspaces))
+        .route("/periodic/:element", get(element_info))
+        .with_state(state)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .layer(Extension(BodyLimit(max_body_size)))
+        .layer(Extension(AtomLimit(max_atoms)));
 
     axum::Server::bind(&listen)
         .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap()
 }
+
+/// Resolves on SIGINT, or SIGTERM on unix, letting in-flight requests finish.
+async fn shutdown_signal() {
+    let interrupt = async {
+        signal::ctrl_c().await.expect("Failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}